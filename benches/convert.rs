@@ -0,0 +1,96 @@
+//! Colour conversion on synthetic frames. Run with `cargo bench --bench convert`.
+
+// The binary's modules are pulled in by path, and most of what they have isn't used here
+#![allow(dead_code)]
+
+#[path = "../src/frame.rs"]
+mod frame;
+
+#[path = "../src/convert/mod.rs"]
+mod convert;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use std::time::Instant;
+
+use convert::simd::{self, Kernel};
+use convert::Conversion;
+use frame::{ColorRange, Frame, Matrix, PixelFormat, Plane};
+
+const SIZES: [(&str, usize, usize); 2] = [("1080p", 1920, 1080), ("4k", 3840, 2160)];
+
+/// Gradients with noise on top, in BGRA byte order. `pad` extra bytes end each row.
+fn synthetic(width: usize, height: usize, pad: usize) -> Plane {
+    let stride = width * 4 + pad;
+    let mut data = vec![0; stride * height];
+    let mut seed = 0x2545_f491_u32;
+
+    for (y, row) in data.chunks_exact_mut(stride).enumerate() {
+        for (x, px) in row[..width * 4].chunks_exact_mut(4).enumerate() {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let noise = (seed & 63) as usize;
+
+            px[0] = ((x * 255 / width + noise) % 256) as u8;
+            px[1] = ((y * 255 / height + noise) % 256) as u8;
+            px[2] = ((x + y) * 255 / (width + height)) as u8;
+            px[3] = 255;
+        }
+    }
+
+    Plane { data, stride }
+}
+
+fn frame(width: usize, height: usize, format: PixelFormat, plane: Plane) -> Frame {
+    Frame::new(width, height, format, vec![plane], Instant::now())
+}
+
+fn as_rgba(plane: &Plane) -> Plane {
+    let mut data = plane.data.clone();
+    for px in data.chunks_exact_mut(4) {
+        px.swap(0, 2);
+    }
+
+    Plane { data, stride: plane.stride }
+}
+
+// Kernels are checked against each other by the tests in convert::simd
+fn bgra_to_420(c: &mut Criterion) {
+    for (name, width, height) in SIZES {
+        let plane = synthetic(width, height, 0);
+        let rgba = as_rgba(&plane);
+        let bgra = frame(width, height, PixelFormat::Bgra, plane);
+
+        let mut group = c.benchmark_group(format!("convert/{}", name));
+        group.throughput(Throughput::Elements((width * height) as u64));
+        group.sample_size(20);
+
+        for format in [PixelFormat::Nv12, PixelFormat::I420] {
+            let to = Conversion { format, matrix: Matrix::Bt709, range: ColorRange::Limited };
+
+            for kernel in Kernel::available() {
+                let id = BenchmarkId::new(format!("bgra_to_{:?}", format), format!("{:?}", kernel));
+                group.bench_function(id, |b| b.iter(|| simd::bgra_to_420(black_box(&bgra), to, kernel)));
+            }
+
+            let id = BenchmarkId::new(format!("rgba_to_{:?}", format), "float");
+            group.bench_function(id, |b| {
+                b.iter_batched(
+                    || frame(width, height, PixelFormat::Rgba, Plane { data: rgba.data.clone(), stride: rgba.stride }),
+                    |f| convert::convert(f, to).unwrap(),
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+
+        // What the Media Foundation encoder does to every frame
+        let mut flipped = Vec::new();
+        group.bench_function("flip_vertical", |b| b.iter(|| convert::flip_vertical(black_box(&bgra), &mut flipped).unwrap()));
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bgra_to_420);
+criterion_main!(benches);
@@ -0,0 +1,233 @@
+use anyhow::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+mod sine;
+mod wav;
+
+pub use sine::SineSource;
+pub use wav::WavSource;
+
+/// How many channels PCM has. Samples of all channels are interleaved, left first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+}
+
+impl ChannelLayout {
+    pub fn channels(self) -> usize {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+        }
+    }
+
+    pub fn from_channels(channels: usize) -> Option<Self> {
+        match channels {
+            1 => Some(ChannelLayout::Mono),
+            2 => Some(ChannelLayout::Stereo),
+            _ => None,
+        }
+    }
+}
+
+/// A run of PCM, always interleaved 32-bit float from -1.0 to 1.0 whatever the
+/// source had, so encoders only deal with one sample format.
+#[derive(Clone, Debug)]
+pub struct AudioBuffer {
+    pub sample_rate: u32,
+    pub layout: ChannelLayout,
+    pub samples: Vec<f32>,
+    /// When the first sample was captured
+    pub timestamp: Instant,
+}
+
+impl AudioBuffer {
+    /// Samples per channel.
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.layout.channels()
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.frames() as f64 / self.sample_rate as f64)
+    }
+}
+
+/// A pull-based source of PCM, the audio side of `capture::FrameSource`.
+pub trait AudioSource: Send {
+    fn sample_rate(&self) -> u32;
+
+    fn layout(&self) -> ChannelLayout;
+
+    /// Returns `None` once the source is exhausted.
+    fn next_buffer(&mut self) -> Result<Option<AudioBuffer>, Error>;
+}
+
+/// Where the audio track comes from, as picked on the command line.
+#[derive(Clone, Debug)]
+pub enum AudioInput {
+    /// Generated tone at `hz`, stamped by a clock `skew_ppm` parts per million
+    /// off to check drift correction with
+    Sine { hz: f64, skew_ppm: f64 },
+    Wav(PathBuf),
+}
+
+/// `sine`, `sine:<Hz>`, `sine:<Hz>:<skew>ppm` or the path to a `.wav` file
+impl FromStr for AudioInput {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.split_once(':') {
+            _ if s.eq_ignore_ascii_case("sine") => Ok(AudioInput::Sine { hz: 440.0, skew_ppm: 0.0 }),
+            Some((kind, rest)) if kind.eq_ignore_ascii_case("sine") => {
+                let (hz, skew) = rest.split_once(':').unwrap_or((rest, "0ppm"));
+
+                let hz = match hz.parse::<f64>() {
+                    Ok(hz) if hz > 0.0 && hz.is_finite() => hz,
+                    _ => return Err(Error::msg(format!("Invalid sine frequency: {}", hz))),
+                };
+                let skew_ppm = match skew.strip_suffix("ppm").unwrap_or(skew).parse::<f64>() {
+                    Ok(ppm) if ppm.abs() < 1_000_000.0 => ppm,
+                    _ => return Err(Error::msg(format!("Invalid clock skew {:?}, expected something like +500ppm", skew))),
+                };
+
+                Ok(AudioInput::Sine { hz, skew_ppm })
+            }
+            _ if s.to_ascii_lowercase().ends_with(".wav") => Ok(AudioInput::Wav(PathBuf::from(s))),
+            _ => Err(Error::msg(format!("Unknown audio input {:?}, expected sine, sine:<Hz> or a .wav file", s))),
+        }
+    }
+}
+
+impl fmt::Display for AudioInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioInput::Sine { hz, skew_ppm } if *skew_ppm != 0.0 => write!(f, "{} Hz sine, clock {:+} ppm", hz, skew_ppm),
+            AudioInput::Sine { hz, .. } => write!(f, "{} Hz sine", hz),
+            AudioInput::Wav(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl AudioInput {
+    /// Opens the source. Its clock starts with the first buffer read from it.
+    pub fn open(&self) -> Result<Box<dyn AudioSource>, Error> {
+        match self {
+            AudioInput::Sine { hz, skew_ppm } => {
+                Ok(Box::new(SineSource::new(*hz, 48_000, ChannelLayout::Stereo)?.with_clock_skew(*skew_ppm)))
+            }
+            AudioInput::Wav(path) => Ok(Box::new(WavSource::open(path)?)),
+        }
+    }
+}
+
+/// Lays PCM onto a track that starts at `origin`, so it lines up with video
+/// timestamped against the same instant. Gaps between buffers become silence and
+/// anything overlapping what's already on the track is dropped, for encoders that
+/// only get to say how many samples there are, not when they were taken.
+pub struct Timeline {
+    origin: Instant,
+    sample_rate: u32,
+    channels: usize,
+    // Samples per channel on the track so far
+    written: u64,
+}
+
+impl Timeline {
+    pub fn new(origin: Instant, sample_rate: u32, layout: ChannelLayout) -> Self {
+        Self { origin, sample_rate, channels: layout.channels(), written: 0 }
+    }
+
+    /// What goes onto the track for `buffer`: silence up to where it starts, then
+    /// whatever part of it isn't already covered.
+    pub fn place(&mut self, buffer: &AudioBuffer) -> Vec<f32> {
+        let rate = self.sample_rate as f64;
+        let start = match buffer.timestamp.checked_duration_since(self.origin) {
+            Some(after) => (after.as_secs_f64() * rate).round() as i64,
+            None => -((self.origin - buffer.timestamp).as_secs_f64() * rate).round() as i64,
+        };
+
+        let gap = (start - self.written as i64).max(0) as usize;
+        let skip = (self.written as i64 - start).max(0) as usize;
+        let frames = buffer.frames().saturating_sub(skip);
+
+        let mut out = Vec::with_capacity((gap + frames) * self.channels);
+        out.resize(gap * self.channels, 0.0);
+        out.extend_from_slice(&buffer.samples[skip.min(buffer.frames()) * self.channels..]);
+
+        self.written += (gap + frames) as u64;
+        out
+    }
+
+    /// Silence that brings the track up to `at`, empty if it's already there.
+    pub fn pad_to(&mut self, at: Instant) -> Vec<f32> {
+        let due = (at.saturating_duration_since(self.origin).as_secs_f64() * self.sample_rate as f64).round() as u64;
+        let gap = due.saturating_sub(self.written) as usize;
+
+        self.written += gap as u64;
+        vec![0.0; gap * self.channels]
+    }
+}
+
+/// Plays `source` back in real time on its own thread, the way a microphone would
+/// deliver it. `sink` gets every buffer as it comes due.
+///
+/// Buffers come due by how many samples have gone out, not by their timestamps:
+/// a sound card delivers at the rate its samples are taken, whatever its clock
+/// says about them.
+pub fn start<F>(mut source: Box<dyn AudioSource>, mut sink: F) -> RunningAudio
+where
+    F: FnMut(AudioBuffer) + Send + 'static,
+{
+    let running = Arc::new(AtomicBool::new(true));
+
+    let thread = std::thread::spawn({
+        let running = running.clone();
+
+        move || {
+            let started = Instant::now();
+            let mut played = Duration::ZERO;
+
+            while running.load(Ordering::SeqCst) {
+                let buffer = match source.next_buffer() {
+                    Ok(Some(buffer)) => buffer,
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::error!("Error reading audio: {}", e);
+                        break;
+                    }
+                };
+
+                // A buffer is due once its last sample would have been recorded
+                played += buffer.duration();
+                let due = started + played;
+                let wait = due.saturating_duration_since(Instant::now());
+                if wait > Duration::ZERO {
+                    std::thread::sleep(wait);
+                }
+
+                sink(buffer);
+            }
+        }
+    });
+
+    RunningAudio { running, thread }
+}
+
+pub struct RunningAudio {
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl RunningAudio {
+    pub fn stop(self) -> Result<(), Error> {
+        self.running.store(false, Ordering::SeqCst);
+        self.thread.join().map_err(|_| Error::msg("Audio thread panicked"))
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::Error;
+use std::f64::consts::TAU;
+use std::time::{Duration, Instant};
+
+use super::{AudioBuffer, AudioSource, ChannelLayout};
+
+// 20ms per buffer, about what capture APIs hand out
+const BUFFERS_PER_SECOND: u32 = 50;
+
+// -12 dBFS, loud enough to hear without clipping anything it's mixed with
+const AMPLITUDE: f64 = 0.25;
+
+/// Endless tone on every channel. Sample `n` is stamped at `n / sample_rate`
+/// after the first buffer was read, like `TestPattern` does for frames.
+pub struct SineSource {
+    start: Option<Instant>,
+    frequency: f64,
+    // Seconds the source's clock counts per second of samples
+    rate_skew: f64,
+    sample_rate: u32,
+    layout: ChannelLayout,
+    // Samples per channel generated so far
+    position: u64,
+}
+
+impl SineSource {
+    pub fn new(frequency: f64, sample_rate: u32, layout: ChannelLayout) -> Result<Self, Error> {
+        if sample_rate == 0 {
+            return Err(Error::msg("Sine source needs a non-zero sample rate"));
+        }
+
+        if frequency <= 0.0 || frequency >= sample_rate as f64 / 2.0 {
+            return Err(Error::msg(format!("{} Hz can't be represented at {} Hz", frequency, sample_rate)));
+        }
+
+        Ok(Self {
+            start: None,
+            frequency,
+            rate_skew: 1.0,
+            sample_rate,
+            layout,
+            position: 0,
+        })
+    }
+
+    /// Stamps buffers from a clock running `ppm` parts per million fast (slow if
+    /// negative), like a sound card whose crystal is a little off. The samples
+    /// themselves don't change.
+    pub fn with_clock_skew(mut self, ppm: f64) -> Self {
+        self.rate_skew = 1.0 + ppm / 1_000_000.0;
+        self
+    }
+}
+
+impl AudioSource for SineSource {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn layout(&self) -> ChannelLayout {
+        self.layout
+    }
+
+    fn next_buffer(&mut self) -> Result<Option<AudioBuffer>, Error> {
+        let frames = (self.sample_rate / BUFFERS_PER_SECOND).max(1) as u64;
+        let channels = self.layout.channels();
+        let rate = self.sample_rate as f64;
+
+        let mut samples = Vec::with_capacity(frames as usize * channels);
+        for n in self.position..self.position + frames {
+            // Phase from the absolute position, so it doesn't drift over long recordings
+            let phase = (n as f64 * self.frequency / rate).fract();
+            let sample = ((phase * TAU).sin() * AMPLITUDE) as f32;
+            samples.extend(std::iter::repeat_n(sample, channels));
+        }
+
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let timestamp = start + Duration::from_secs_f64(self.position as f64 / rate * self.rate_skew);
+        self.position += frames;
+
+        Ok(Some(AudioBuffer { sample_rate: self.sample_rate, layout: self.layout, samples, timestamp }))
+    }
+}
@@ -0,0 +1,312 @@
+use anyhow::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::{AudioBuffer, AudioSource, ChannelLayout};
+
+// 20ms per buffer, same as the sine source
+const BUFFERS_PER_SECOND: u32 = 50;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+// WAVE_FORMAT_EXTENSIBLE, the real format is in the first two bytes of the sub-format GUID
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    /// Unsigned 8-bit
+    U8,
+    I16,
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl Encoding {
+    fn bytes(self) -> usize {
+        match self {
+            Encoding::U8 => 1,
+            Encoding::I16 => 2,
+            Encoding::I24 => 3,
+            Encoding::I32 | Encoding::F32 => 4,
+            Encoding::F64 => 8,
+        }
+    }
+
+    fn decode(self, b: &[u8]) -> f32 {
+        match self {
+            Encoding::U8 => (b[0] as f32 - 128.0) / 128.0,
+            Encoding::I16 => i16::from_le_bytes([b[0], b[1]]) as f32 / 32_768.0,
+            // Shifted up into the top of an i32 so the sign comes along
+            Encoding::I24 => i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2_147_483_648.0,
+            Encoding::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0,
+            Encoding::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            Encoding::F64 => f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32,
+        }
+    }
+}
+
+/// Streams the PCM in a mono or stereo WAV file, stamped as if it had started
+/// playing when the first buffer was read. Ends with the file, it doesn't loop.
+pub struct WavSource {
+    reader: BufReader<File>,
+    start: Option<Instant>,
+    sample_rate: u32,
+    layout: ChannelLayout,
+    encoding: Encoding,
+    // Bytes of the data chunk not read yet
+    remaining: u64,
+    // Samples per channel handed out so far
+    position: u64,
+}
+
+impl WavSource {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path).map_err(|e| Error::msg(format!("Can't open {}: {}", path.display(), e)))?;
+        let mut reader = BufReader::new(file);
+        let invalid = |reason: &str| Error::msg(format!("{} isn't a usable WAV file: {}", path.display(), reason));
+
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header).map_err(|_| invalid("too short"))?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(invalid("no RIFF/WAVE header"));
+        }
+
+        let mut format = None;
+        loop {
+            let mut chunk = [0u8; 8];
+            reader.read_exact(&mut chunk).map_err(|_| invalid("no data chunk"))?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+
+            match &chunk[0..4] {
+                b"fmt " => {
+                    if size < 16 {
+                        return Err(invalid("fmt chunk too short"));
+                    }
+
+                    let mut fmt = vec![0u8; size as usize];
+                    reader.read_exact(&mut fmt).map_err(|_| invalid("fmt chunk cut off"))?;
+                    format = Some(fmt);
+
+                    // Chunks are padded to an even size
+                    if size % 2 == 1 {
+                        reader.seek(SeekFrom::Current(1))?;
+                    }
+                }
+                b"data" => {
+                    let fmt = format.ok_or_else(|| invalid("data before fmt"))?;
+                    let (sample_rate, layout, encoding) = parse_format(&fmt).map_err(invalid)?;
+
+                    return Ok(Self {
+                        reader,
+                        start: None,
+                        sample_rate,
+                        layout,
+                        encoding,
+                        remaining: size,
+                        position: 0,
+                    });
+                }
+                _ => {
+                    reader.seek(SeekFrom::Current((size + size % 2) as i64))?;
+                }
+            }
+        }
+    }
+}
+
+fn parse_format(fmt: &[u8]) -> Result<(u32, ChannelLayout, Encoding), &'static str> {
+    let u16_at = |at: usize| u16::from_le_bytes([fmt[at], fmt[at + 1]]);
+
+    let mut tag = u16_at(0);
+    let channels = u16_at(2);
+    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+    let bits = u16_at(14);
+
+    if tag == FORMAT_EXTENSIBLE {
+        if fmt.len() < 26 {
+            return Err("extensible fmt chunk too short");
+        }
+        tag = u16_at(24);
+    }
+
+    let encoding = match (tag, bits) {
+        (FORMAT_PCM, 8) => Encoding::U8,
+        (FORMAT_PCM, 16) => Encoding::I16,
+        (FORMAT_PCM, 24) => Encoding::I24,
+        (FORMAT_PCM, 32) => Encoding::I32,
+        (FORMAT_FLOAT, 32) => Encoding::F32,
+        (FORMAT_FLOAT, 64) => Encoding::F64,
+        _ => return Err("only 8/16/24/32-bit integer and 32/64-bit float PCM is supported"),
+    };
+
+    let layout = ChannelLayout::from_channels(channels as usize).ok_or("only mono and stereo are supported")?;
+    if sample_rate == 0 {
+        return Err("sample rate is 0");
+    }
+
+    Ok((sample_rate, layout, encoding))
+}
+
+impl AudioSource for WavSource {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn layout(&self) -> ChannelLayout {
+        self.layout
+    }
+
+    fn next_buffer(&mut self) -> Result<Option<AudioBuffer>, Error> {
+        let frame_bytes = self.encoding.bytes() * self.layout.channels();
+        let frames = (self.sample_rate / BUFFERS_PER_SECOND).max(1) as u64;
+        let wanted = (frames * frame_bytes as u64).min(self.remaining);
+
+        // Streamed WAVs can claim a bigger data chunk than they have, so the file
+        // ending early is just the end
+        let mut bytes = Vec::with_capacity(wanted as usize);
+        (&mut self.reader).take(wanted).read_to_end(&mut bytes)?;
+        self.remaining = match bytes.len() as u64 {
+            read if read < wanted => 0,
+            read => self.remaining - read,
+        };
+
+        let len = bytes.len() / frame_bytes * frame_bytes;
+        if len == 0 {
+            return Ok(None);
+        }
+        bytes.truncate(len);
+
+        let samples = bytes.chunks_exact(self.encoding.bytes()).map(|b| self.encoding.decode(b)).collect();
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let timestamp = start + Duration::from_secs_f64(self.position as f64 / self.sample_rate as f64);
+        self.position += (len / frame_bytes) as u64;
+
+        Ok(Some(AudioBuffer { sample_rate: self.sample_rate, layout: self.layout, samples, timestamp }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(tag: u16, channels: u16, sample_rate: u32, bits: u16) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&tag.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&sample_rate.to_le_bytes());
+        fmt.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        fmt.extend_from_slice(&block_align.to_le_bytes());
+        fmt.extend_from_slice(&bits.to_le_bytes());
+        fmt
+    }
+
+    // `size` is what the header claims, which doesn't have to be what's there
+    fn chunk(id: &[u8; 4], size: u32, body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&size.to_le_bytes());
+        chunk.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn open(name: &str, chunks: &[Vec<u8>]) -> Result<WavSource, Error> {
+        let body = chunks.concat();
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        file.extend_from_slice(b"WAVE");
+        file.extend_from_slice(&body);
+
+        let path = std::env::temp_dir().join(format!("wav-test-{}-{}.wav", std::process::id(), name));
+        std::fs::write(&path, file)?;
+        let source = WavSource::open(&path);
+        std::fs::remove_file(&path)?;
+        source
+    }
+
+    // Every sample in the file, from buffers that all match its header
+    fn decode(mut source: WavSource) -> Vec<f32> {
+        let mut samples = Vec::new();
+        while let Some(buffer) = source.next_buffer().unwrap() {
+            assert_eq!((buffer.sample_rate, buffer.layout), (source.sample_rate, source.layout));
+            samples.extend(buffer.samples);
+        }
+        samples
+    }
+
+    #[test]
+    fn decodes_each_encoding() {
+        let cases: [(u16, u16, Vec<u8>, Vec<f32>); 4] = [
+            (FORMAT_PCM, 8, vec![0, 128, 192], vec![-1.0, 0.0, 0.5]),
+            (FORMAT_PCM, 16, [i16::MIN, 0, 16_384].iter().flat_map(|s| s.to_le_bytes()).collect(), vec![-1.0, 0.0, 0.5]),
+            (FORMAT_PCM, 24, vec![0x00, 0x00, 0x80, 0, 0, 0, 0x00, 0x00, 0x40], vec![-1.0, 0.0, 0.5]),
+            (FORMAT_FLOAT, 32, [0.25f32, -0.75].iter().flat_map(|s| s.to_le_bytes()).collect(), vec![0.25, -0.75]),
+        ];
+
+        for (tag, bits, data, expected) in cases {
+            let source = open(&format!("encoding-{}", bits), &[
+                chunk(b"fmt ", 16, &fmt(tag, 1, 8000, bits)),
+                chunk(b"data", data.len() as u32, &data),
+            ])
+            .unwrap();
+
+            assert_eq!((source.sample_rate(), source.layout()), (8000, ChannelLayout::Mono));
+            assert_eq!(decode(source), expected, "{}-bit", bits);
+        }
+    }
+
+    #[test]
+    fn reads_the_format_out_of_extensible_headers() {
+        let mut extensible = fmt(FORMAT_EXTENSIBLE, 2, 48_000, 16);
+        extensible.extend_from_slice(&22u16.to_le_bytes());
+        extensible.extend_from_slice(&16u16.to_le_bytes()); // valid bits
+        extensible.extend_from_slice(&3u32.to_le_bytes()); // front left and right
+        extensible.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+        extensible.extend_from_slice(&[0; 14]); // rest of the GUID
+
+        let data: Vec<u8> = [i16::MIN, 16_384].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let source = open("extensible", &[chunk(b"fmt ", 40, &extensible), chunk(b"data", 4, &data)]).unwrap();
+
+        assert_eq!((source.sample_rate(), source.layout()), (48_000, ChannelLayout::Stereo));
+        assert_eq!(decode(source), [-1.0, 0.5]);
+    }
+
+    #[test]
+    fn skips_odd_sized_chunks() {
+        // An odd fmt chunk with a stray byte, and unknown chunks padded to an even size around it
+        let mut odd_fmt = fmt(FORMAT_PCM, 1, 8000, 8);
+        odd_fmt.push(0xff);
+
+        let source = open("odd", &[
+            chunk(b"LIST", 3, b"abc"),
+            chunk(b"fmt ", 17, &odd_fmt),
+            chunk(b"junk", 1, b"x"),
+            chunk(b"data", 2, &[64, 255]),
+        ])
+        .unwrap();
+
+        assert_eq!(decode(source), [-0.5, 127.0 / 128.0]);
+    }
+
+    #[test]
+    fn data_cut_short_just_ends() {
+        let data: Vec<u8> = [1i16, 2, 3, 4].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let source = open("short", &[chunk(b"fmt ", 16, &fmt(FORMAT_PCM, 1, 8000, 16)), chunk(b"data", 1000, &data)]).unwrap();
+
+        let expected: Vec<f32> = [1.0, 2.0, 3.0, 4.0].iter().map(|s| s / 32_768.0).collect();
+        assert_eq!(decode(source), expected);
+    }
+
+    #[test]
+    fn rejects_a_sample_rate_of_0() {
+        let result = open("rate-0", &[chunk(b"fmt ", 16, &fmt(FORMAT_PCM, 1, 0, 16)), chunk(b"data", 2, &[0, 0])]);
+        assert!(result.err().unwrap().to_string().contains("sample rate is 0"));
+        assert_eq!(parse_format(&fmt(FORMAT_FLOAT, 2, 0, 32)).err(), Some("sample rate is 0"));
+    }
+}
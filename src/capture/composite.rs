@@ -0,0 +1,211 @@
+use anyhow::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::{PreparedCapture, RunningCapture};
+use crate::convert::{self, Conversion};
+use crate::frame::{Frame, PixelFormat, Plane};
+
+/// How several displays recorded at once end up in the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MultiDisplay {
+    /// One canvas with every display where it sits in the arrangement
+    Composite,
+    /// One output file per display
+    Separate,
+}
+
+/// `composite` or `separate`
+impl FromStr for MultiDisplay {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "composite" | "canvas" => Ok(MultiDisplay::Composite),
+            "separate" | "tracks" => Ok(MultiDisplay::Separate),
+            _ => Err(Error::msg(format!("Unknown multi-display mode: {}", s))),
+        }
+    }
+}
+
+/// Where each source goes on the canvas, in pixels.
+pub struct Layout {
+    pub width: usize,
+    pub height: usize,
+    pub origins: Vec<(usize, usize)>,
+}
+
+impl Layout {
+    /// Lays out sources at `bounds` (x, y, width, height in points, as `list` shows
+    /// displays) at `scale` pixels per point. The canvas is the smallest rectangle
+    /// around all of them, rounded up to even dimensions.
+    pub fn new(bounds: &[(f64, f64, f64, f64)], scale: f64) -> Self {
+        let left = bounds.iter().map(|b| b.0).fold(f64::INFINITY, f64::min);
+        let top = bounds.iter().map(|b| b.1).fold(f64::INFINITY, f64::min);
+        let right = bounds.iter().map(|b| b.0 + b.2).fold(f64::NEG_INFINITY, f64::max);
+        let bottom = bounds.iter().map(|b| b.1 + b.3).fold(f64::NEG_INFINITY, f64::max);
+
+        let even = |v: f64| (v.round() as usize).div_ceil(2) * 2;
+        let origins = bounds
+            .iter()
+            .map(|b| (((b.0 - left) * scale).round() as usize, ((b.1 - top) * scale).round() as usize))
+            .collect();
+
+        Self {
+            width: even((right - left) * scale).max(2),
+            height: even((bottom - top) * scale).max(2),
+            origins,
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{} canvas with sources at", self.width, self.height)?;
+        for (x, y) in &self.origins {
+            write!(f, " {},{}", x, y)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The newest frame from each source already in BGRA, and whether it has ended
+type Latest = Arc<Mutex<Vec<(Option<Frame>, bool)>>>;
+
+pub(super) struct Compositor {
+    sources: Vec<RunningCapture>,
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Compositor {
+    /// Starts every source and draws the newest frame of each onto the canvas `fps`
+    /// times a second. Every canvas is stamped with the time it was drawn, so all
+    /// sources share the compositor's clock.
+    pub(super) fn start<F>(sources: Vec<PreparedCapture>, layout: Layout, fps: u32, mut sink: F) -> Result<Self, Error>
+    where
+        F: FnMut(Option<Frame>) + Send + 'static,
+    {
+        let count = sources.len();
+        let latest: Latest = Arc::new(Mutex::new((0..count).map(|_| (None, false)).collect()));
+
+        let mut running_sources = Vec::with_capacity(count);
+        for (index, source) in sources.into_iter().enumerate() {
+            let running = source.start(fps, source_sink(latest.clone(), index));
+
+            match running {
+                Ok(running) => running_sources.push(running),
+                Err(e) => {
+                    // Don't leave the ones that did start running
+                    for running in running_sources {
+                        running.stop().ok();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let frame_interval = Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+
+        let thread = std::thread::spawn({
+            let running = running.clone();
+
+            move || {
+                let mut canvas = vec![0u8; layout.width * layout.height * 4];
+                let mut next_tick = Instant::now();
+
+                while running.load(Ordering::SeqCst) {
+                    let timestamp = Instant::now();
+                    let (drawn, ended) = {
+                        let latest = latest.lock().unwrap();
+                        let mut drawn = false;
+
+                        for ((frame, _), &origin) in latest.iter().zip(&layout.origins) {
+                            if let Some(frame) = frame {
+                                draw(&mut canvas, &layout, origin, frame);
+                                drawn = true;
+                            }
+                        }
+
+                        (drawn, latest.iter().all(|(_, ended)| *ended))
+                    };
+
+                    if ended {
+                        break;
+                    }
+
+                    // Nothing to show until the first source delivers
+                    if drawn {
+                        let plane = Plane { data: canvas.clone(), stride: layout.width * 4 };
+                        sink(Some(Frame::new(layout.width, layout.height, PixelFormat::Bgra, vec![plane], timestamp)));
+                    }
+
+                    next_tick += frame_interval;
+                    let now = Instant::now();
+                    if next_tick > now {
+                        std::thread::sleep(next_tick - now);
+                    } else {
+                        next_tick = now;
+                    }
+                }
+
+                sink(None);
+            }
+        });
+
+        Ok(Self { sources: running_sources, running, thread })
+    }
+
+    pub(super) fn stop(self) -> Result<(), Error> {
+        let mut result = Ok(());
+        for source in self.sources {
+            if let Err(e) = source.stop() {
+                result = Err(e);
+            }
+        }
+
+        self.running.store(false, Ordering::SeqCst);
+        self.thread.join().map_err(|_| Error::msg("Compositor thread panicked"))?;
+
+        result
+    }
+}
+
+// Not generic over the compositor's own sink, or starting a source from inside
+// `PreparedCapture::start` would instantiate it forever
+fn source_sink(latest: Latest, index: usize) -> impl FnMut(Option<Frame>) + Send + 'static {
+    move |frame| {
+        let Some(frame) = frame else {
+            latest.lock().unwrap()[index].1 = true;
+            return;
+        };
+
+        // Converting here keeps the work on each source's own capture thread
+        let to = Conversion::to(PixelFormat::Bgra, &frame);
+        match convert::convert(frame, to) {
+            Ok(frame) => latest.lock().unwrap()[index].0 = Some(frame),
+            Err(e) => tracing::warn!(source = index, "Dropping frame that couldn't be composited: {}", e),
+        }
+    }
+}
+
+// Copies a BGRA frame onto the canvas with its top left corner at `origin`,
+// clipping whatever doesn't fit
+fn draw(canvas: &mut [u8], layout: &Layout, origin: (usize, usize), frame: &Frame) {
+    let plane = &frame.planes[0];
+    let width = frame.width.min(layout.width.saturating_sub(origin.0));
+    let height = frame.height.min(layout.height.saturating_sub(origin.1));
+    let canvas_stride = layout.width * 4;
+
+    for y in 0..height {
+        let src = &plane.data[y * plane.stride..y * plane.stride + width * 4];
+        let start = (origin.1 + y) * canvas_stride + origin.0 * 4;
+        canvas[start..start + width * 4].copy_from_slice(src);
+    }
+}
@@ -0,0 +1,313 @@
+use anyhow::Error;
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::convert;
+use crate::frame::{Frame, PixelFormat, Rect};
+
+mod composite;
+mod pattern;
+mod region;
+
+pub use composite::{Layout, MultiDisplay};
+pub use pattern::TestPattern;
+pub use region::Region;
+
+use composite::Compositor;
+
+// Each platform module exposes the same `list`, `prepare` and `start` functions
+// plus `ResolvedTarget` and `Stream` types
+
+#[cfg(not(target_os = "linux"))]
+mod native;
+
+#[cfg(not(target_os = "linux"))]
+use native as platform;
+
+#[cfg(target_os = "linux")]
+mod x11;
+
+#[cfg(target_os = "linux")]
+use x11 as platform;
+
+// Size of the test pattern at scale 1.0
+const PATTERN_SIZE: (f64, f64) = (1920.0, 1080.0);
+
+/// A pull-based source of frames, for sources that can produce frames on demand
+/// (generators, files) rather than pushing them from a capture callback.
+pub trait FrameSource {
+    /// Returns `None` once the source is exhausted.
+    fn next_frame(&mut self) -> Result<Option<Frame>, Error>;
+}
+
+/// What to record, as picked on the command line.
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// Index into the displays from `list`, `None` for the main display
+    Display(Option<usize>),
+    Window(WindowSelector),
+    /// Generated frames in this pixel format
+    TestPattern(PixelFormat),
+}
+
+/// How a window to record is picked out of the ones `list` shows.
+#[derive(Clone, Debug)]
+pub enum WindowSelector {
+    /// First window whose title contains this text
+    Title(String),
+    /// First window of the application with this name, ignoring case
+    App(String),
+    /// The ID `list` shows
+    Id(u64),
+}
+
+impl WindowSelector {
+    pub fn matches(&self, window: &SourceInfo) -> bool {
+        match self {
+            WindowSelector::Title(title) => window.name.contains(title.as_str()),
+            WindowSelector::App(app) => window.app.as_ref().is_some_and(|name| name.eq_ignore_ascii_case(app)),
+            WindowSelector::Id(id) => window.id == *id,
+        }
+    }
+}
+
+impl fmt::Display for WindowSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowSelector::Title(title) => write!(f, "with a title containing {:?}", title),
+            WindowSelector::App(app) => write!(f, "belonging to {:?}", app),
+            WindowSelector::Id(id) => write!(f, "with ID {}", id),
+        }
+    }
+}
+
+/// A display or window that can be recorded, with its bounds in points.
+pub struct SourceInfo {
+    pub kind: &'static str,
+    /// Index for displays, for windows whatever ID the platform has for them
+    pub id: u64,
+    /// Title for windows
+    pub name: String,
+    /// Application a window belongs to
+    pub app: Option<String>,
+    pub pid: Option<u32>,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+pub fn list() -> Result<Vec<SourceInfo>, Error> {
+    platform::list()
+}
+
+/// A target that has been looked up and sized, but isn't capturing yet.
+pub struct PreparedCapture {
+    pub description: String,
+    pub width: usize,
+    pub height: usize,
+    // Size of what's captured before any cropping, and pixels per display point
+    source: (usize, usize),
+    scale: f64,
+    // Part of each frame that's kept, in pixels
+    crop: Option<Rect>,
+    resolved: Resolved,
+}
+
+enum Resolved {
+    Platform(platform::ResolvedTarget),
+    Pattern(PixelFormat),
+    Composite(Vec<PreparedCapture>, Layout),
+}
+
+pub fn prepare(target: &Target, scale: f64) -> Result<PreparedCapture, Error> {
+    if let Target::TestPattern(format) = target {
+        // NV12-friendly even dimensions
+        let width = ((PATTERN_SIZE.0 * scale) as usize / 2 * 2).max(2);
+        let height = ((PATTERN_SIZE.1 * scale) as usize / 2 * 2).max(2);
+
+        return Ok(PreparedCapture {
+            description: "test pattern".to_string(),
+            width,
+            height,
+            source: (width, height),
+            scale,
+            crop: None,
+            resolved: Resolved::Pattern(*format),
+        });
+    }
+
+    let (description, width, height, resolved) = platform::prepare(target, scale)?;
+
+    Ok(PreparedCapture {
+        description,
+        width,
+        height,
+        source: (width, height),
+        scale,
+        crop: None,
+        resolved: Resolved::Platform(resolved),
+    })
+}
+
+/// Prepares `displays` to be recorded onto one canvas, arranged the way they are
+/// on the desktop.
+pub fn prepare_composite(displays: &[usize], scale: f64) -> Result<PreparedCapture, Error> {
+    let sources = list()?;
+    let mut bounds = Vec::with_capacity(displays.len());
+    let mut captures = Vec::with_capacity(displays.len());
+
+    for &index in displays {
+        let display = sources
+            .iter()
+            .find(|source| source.kind == "display" && source.id == index as u64)
+            .ok_or(Error::msg(format!("No display {}", index)))?;
+
+        bounds.push((display.x, display.y, display.width, display.height));
+        captures.push(prepare(&Target::Display(Some(index)), scale)?);
+    }
+
+    Ok(composite(captures, &bounds, scale))
+}
+
+/// Puts already prepared captures onto one canvas, each at its bounds in points.
+pub fn composite(captures: Vec<PreparedCapture>, bounds: &[(f64, f64, f64, f64)], scale: f64) -> PreparedCapture {
+    let layout = Layout::new(bounds, scale);
+    let names: Vec<_> = captures.iter().map(|capture| capture.description.as_str()).collect();
+
+    PreparedCapture {
+        description: format!("{} composited", names.join(" + ")),
+        width: layout.width,
+        height: layout.height,
+        source: (layout.width, layout.height),
+        scale,
+        crop: None,
+        resolved: Resolved::Composite(captures, layout),
+    }
+}
+
+impl PreparedCapture {
+    /// How the sources of a composite capture are arranged, `None` for anything else.
+    pub fn layout(&self) -> Option<&Layout> {
+        match &self.resolved {
+            Resolved::Composite(_, layout) => Some(layout),
+            _ => None,
+        }
+    }
+
+    /// Records only `region` of the display. Frames are cropped as they arrive, so
+    /// `width` and `height` become the size of the region in pixels.
+    pub fn with_region(mut self, region: Region) -> Result<Self, Error> {
+        let rect = region.to_pixels(self.source, self.scale)?;
+
+        self.description = format!("{}, region {}", self.description, region);
+        self.width = rect.width;
+        self.height = rect.height;
+        self.crop = Some(rect);
+
+        Ok(self)
+    }
+
+    /// Starts capturing. `sink` gets every frame, then `None` once the stream has ended.
+    /// Frames whose planes don't fit their size and format are dropped here, so
+    /// nothing downstream has to second-guess row pitch or chroma size, and frames
+    /// are cropped to the region here too.
+    pub fn start<F>(self, fps: u32, mut sink: F) -> Result<RunningCapture, Error>
+    where
+        F: FnMut(Option<Frame>) + Send + 'static,
+    {
+        let crop = self.crop;
+        let sink = move |frame: Option<Frame>| {
+            let Some(frame) = frame else {
+                return sink(None);
+            };
+
+            if let Err(e) = convert::validate(&frame) {
+                tracing::warn!("Dropping malformed frame: {}", e);
+                return;
+            }
+
+            match crop.map(|rect| convert::crop(&frame, rect)) {
+                None => sink(Some(frame)),
+                Some(Ok(cropped)) => sink(Some(cropped)),
+                // Most likely the display changed resolution under us
+                Some(Err(e)) => tracing::warn!("Dropping frame that couldn't be cropped: {}", e),
+            }
+        };
+
+        let running = match self.resolved {
+            Resolved::Platform(resolved) => Running::Platform(platform::start(resolved, fps, sink)?),
+            Resolved::Pattern(format) => start_pattern(self.source.0, self.source.1, fps, format, sink)?,
+            Resolved::Composite(sources, layout) => Running::Composite(Compositor::start(sources, layout, fps, sink)?),
+        };
+
+        Ok(RunningCapture { running })
+    }
+}
+
+pub struct RunningCapture {
+    running: Running,
+}
+
+enum Running {
+    Platform(platform::Stream),
+    Pattern {
+        running: Arc<AtomicBool>,
+        thread: JoinHandle<()>,
+    },
+    Composite(Compositor),
+}
+
+impl RunningCapture {
+    pub fn stop(self) -> Result<(), Error> {
+        match self.running {
+            Running::Platform(mut stream) => stream.stop(),
+            Running::Pattern { running, thread } => {
+                running.store(false, Ordering::SeqCst);
+                thread.join().map_err(|_| Error::msg("Test pattern thread panicked"))
+            }
+            Running::Composite(compositor) => compositor.stop(),
+        }
+    }
+}
+
+// Plays the pattern back in real time, like a display that's always changing
+fn start_pattern<F>(width: usize, height: usize, fps: u32, format: PixelFormat, mut sink: F) -> Result<Running, Error>
+where
+    F: FnMut(Option<Frame>) + Send + 'static,
+{
+    let mut pattern = TestPattern::new(width, height, fps, format)?;
+    let running = Arc::new(AtomicBool::new(true));
+
+    let thread = std::thread::spawn({
+        let running = running.clone();
+
+        move || {
+            while running.load(Ordering::SeqCst) {
+                let frame = match pattern.next_frame() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::error!("Error generating test pattern: {}", e);
+                        break;
+                    }
+                };
+
+                let wait = frame.timestamp.saturating_duration_since(Instant::now());
+                if wait > Duration::ZERO {
+                    std::thread::sleep(wait);
+                }
+
+                sink(Some(frame));
+            }
+
+            sink(None);
+        }
+    });
+
+    Ok(Running::Pattern { running, thread })
+}
@@ -0,0 +1,119 @@
+use anyhow::Error;
+use pollster::FutureExt;
+
+use crabgrab::capturable_content::{CapturableContent, CapturableContentFilter, CapturableWindow};
+use crabgrab::capture_stream::{CaptureConfig, CaptureStream, StreamEvent, CapturePixelFormat};
+
+use super::{SourceInfo, Target};
+use crate::frame::Frame;
+
+const STREAM_PX_FMT: CapturePixelFormat = CapturePixelFormat::Bgra8888;
+
+pub type Stream = CaptureStream;
+
+pub struct ResolvedTarget {
+    config: CaptureConfig,
+}
+
+pub fn list() -> Result<Vec<SourceInfo>, Error> {
+    let content = CapturableContent::new(CapturableContentFilter::EVERYTHING_NORMAL).block_on()?;
+
+    let displays = content.displays().enumerate().map(|(index, display)| {
+        let rect = display.rect();
+        SourceInfo {
+            kind: "display",
+            id: index as u64,
+            name: format!("Display {}", index),
+            app: None,
+            pid: None,
+            x: rect.origin.x,
+            y: rect.origin.y,
+            width: rect.size.width,
+            height: rect.size.height,
+        }
+    });
+
+    let windows = content.windows().enumerate().map(|(index, window)| window_info(index, &window));
+
+    Ok(displays.chain(windows).collect())
+}
+
+// crabgrab has no window IDs of its own, so the position in its list stands in
+fn window_info(index: usize, window: &CapturableWindow) -> SourceInfo {
+    let rect = window.rect();
+    let application = window.application();
+
+    SourceInfo {
+        kind: "window",
+        id: index as u64,
+        name: window.title(),
+        app: Some(application.name()),
+        pid: u32::try_from(application.pid()).ok(),
+        x: rect.origin.x,
+        y: rect.origin.y,
+        width: rect.size.width,
+        height: rect.size.height,
+    }
+}
+
+pub fn prepare(target: &Target, scale: f64) -> Result<(String, usize, usize, ResolvedTarget), Error> {
+    let (description, size, config) = match target {
+        Target::Display(index) => {
+            let content = CapturableContent::new(CapturableContentFilter::DISPLAYS).block_on()?;
+            let index = index.unwrap_or(0);
+            let display = content.displays().nth(index).ok_or(Error::msg("No such display"))?;
+
+            let size = display.rect().scaled(scale).size;
+            let config = CaptureConfig::with_display(display, STREAM_PX_FMT, None);
+
+            (format!("display {}", index), size, config)
+        }
+        Target::Window(selector) => {
+            let content = CapturableContent::new(CapturableContentFilter::NORMAL_WINDOWS).block_on()?;
+            let window = content
+                .windows()
+                .enumerate()
+                .find(|(index, window)| selector.matches(&window_info(*index, window)))
+                .map(|(_, window)| window)
+                .ok_or(Error::msg(format!("No window {}", selector)))?;
+
+            // The stream is asked for this output size below, so a resized window is
+            // normally scaled by the OS. Any frame that still differs goes through the
+            // resize policy like an X11 one.
+            let description = format!("window {:?} ({})", window.title(), window.application().name());
+            let size = window.rect().scaled(scale).size;
+            let config = CaptureConfig::with_window(window, STREAM_PX_FMT)?;
+
+            (description, size, config)
+        }
+        Target::TestPattern(_) => unreachable!("test pattern is handled by capture::prepare"),
+    };
+
+    let config = config
+        .with_color_space_name("kCGColorSpaceSRGB".to_string())
+        .with_output_size(size);
+
+    Ok((description, size.width as usize, size.height as usize, ResolvedTarget { config }))
+}
+
+pub fn start<F>(target: ResolvedTarget, _fps: u32, mut sink: F) -> Result<Stream, Error>
+where
+    F: FnMut(Option<Frame>) + Send + 'static,
+{
+    let stream_token =
+    CaptureStream::test_access(false).ok_or(Error::msg("Failed to get access token"))?;
+
+    let stream = CaptureStream::new(stream_token, target.config, move |result| match result {
+        Ok(event) => match event {
+            StreamEvent::Video(frame) => match Frame::try_from(frame) {
+                Ok(frame) => sink(Some(frame)),
+                Err(e) => tracing::warn!("Error converting frame: {}", e),
+            },
+            StreamEvent::End => sink(None),
+            _ => {}
+        },
+        Err(e) => tracing::error!("Capture stream error: {}", e),
+    })?;
+
+    Ok(stream)
+}
@@ -0,0 +1,142 @@
+use anyhow::Error;
+use std::time::{Duration, Instant};
+
+use super::FrameSource;
+use crate::convert::{self, Conversion};
+use crate::frame::{Frame, PixelFormat, Plane};
+
+// 75% colour bars, left to right, as BGRA
+const BARS: [[u8; 4]; 7] = [
+    [191, 191, 191, 255], // white
+    [0, 191, 191, 255],   // yellow
+    [191, 191, 0, 255],   // cyan
+    [0, 191, 0, 255],     // green
+    [191, 0, 191, 255],   // magenta
+    [0, 0, 191, 255],     // red
+    [191, 0, 0, 255],     // blue
+];
+
+// 3x5 digit glyphs, one bit per pixel, row-major from the top-left
+const DIGITS: [u16; 10] = [
+    0b111_101_101_101_111,
+    0b010_110_010_010_111,
+    0b111_001_111_100_111,
+    0b111_001_111_001_111,
+    0b101_101_111_001_001,
+    0b111_100_111_001_111,
+    0b111_100_111_101_111,
+    0b111_001_010_010_010,
+    0b111_101_111_101_111,
+    0b111_101_111_001_111,
+];
+
+/// Deterministic frame generator: colour bars, a box bouncing across the frame and the
+/// frame number burned into the top-left corner. Frame `n` is always identical and is
+/// stamped at `n / fps` after the pattern was created, so it can drive an encoder
+/// without a display or capture permission.
+pub struct TestPattern {
+    start: Instant,
+    width: usize,
+    height: usize,
+    fps: u32,
+    format: PixelFormat,
+    frame_index: u64,
+}
+
+impl TestPattern {
+    pub fn new(width: usize, height: usize, fps: u32, format: PixelFormat) -> Result<Self, Error> {
+        if width == 0 || height == 0 || fps == 0 {
+            return Err(Error::msg("Test pattern needs a non-zero size and fps"));
+        }
+
+        Ok(Self {
+            start: Instant::now(),
+            width,
+            height,
+            fps,
+            format,
+            frame_index: 0,
+        })
+    }
+
+    pub fn render(&self, index: u64) -> Result<Frame, Error> {
+        let plane = Plane { data: self.render_bgra(index), stride: self.width * 4 };
+        let timestamp = self.start + Duration::from_secs_f64(index as f64 / self.fps as f64);
+        let frame = Frame::new(self.width, self.height, PixelFormat::Bgra, vec![plane], timestamp);
+
+        let to = Conversion::to(self.format, &frame);
+        Ok(convert::convert(frame, to)?)
+    }
+
+    fn render_bgra(&self, index: u64) -> Vec<u8> {
+        let (width, height) = (self.width, self.height);
+        let mut data = vec![0u8; width * height * 4];
+
+        for (y, row) in data.chunks_exact_mut(width * 4).enumerate() {
+            // Bottom quarter is a black-to-white ramp, the rest is colour bars
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                if y >= height * 3 / 4 {
+                    let v = (x * 255 / (width - 1).max(1)) as u8;
+                    px.copy_from_slice(&[v, v, v, 255]);
+                } else {
+                    px.copy_from_slice(&BARS[x * BARS.len() / width]);
+                }
+            }
+        }
+
+        // Box bounces horizontally across the middle, one box-width per second
+        let size = (height / 8).max(2);
+        let travel = width.saturating_sub(size).max(1) as u64;
+        let step = (index * size as u64 / self.fps as u64) % (travel * 2);
+        let box_x = if step < travel { step } else { travel * 2 - step } as usize;
+        let box_y = (height.saturating_sub(size)) / 2;
+        fill_rect(&mut data, width, height, box_x, box_y, size, size, [255, 255, 255, 255]);
+
+        // Frame counter in black on a white plate
+        let digits = index.to_string();
+        let scale = (height / 60).max(2);
+        let glyph_w = 4 * scale;
+        fill_rect(
+            &mut data, width, height,
+            0, 0,
+            digits.len() * glyph_w + scale, 7 * scale,
+            [255, 255, 255, 255],
+        );
+
+        for (i, c) in digits.bytes().enumerate() {
+            let glyph = DIGITS[(c - b'0') as usize];
+
+            for bit in 0..15 {
+                if glyph & (1 << (14 - bit)) != 0 {
+                    let gx = scale + i * glyph_w + (bit % 3) * scale;
+                    let gy = scale + (bit / 3) * scale;
+                    fill_rect(&mut data, width, height, gx, gy, scale, scale, [0, 0, 0, 255]);
+                }
+            }
+        }
+
+        data
+    }
+}
+
+impl FrameSource for TestPattern {
+    fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let frame = self.render(self.frame_index)?;
+        self.frame_index += 1;
+
+        Ok(Some(frame))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fill_rect(data: &mut [u8], width: usize, height: usize, x: usize, y: usize, w: usize, h: usize, color: [u8; 4]) {
+    let x = x.min(width);
+    let x_end = (x + w).min(width);
+    let y_end = (y + h).min(height);
+
+    for row in y..y_end {
+        for px in data[(row * width + x) * 4..(row * width + x_end) * 4].chunks_exact_mut(4) {
+            px.copy_from_slice(&color);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::frame::Rect;
+
+/// Part of a display to record, in points from its top left corner.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Region {
+    /// Works out the pixels the region covers in frames of `size` pixels, taken from a
+    /// display at `scale` pixels per point. The result starts on an even pixel and has
+    /// even dimensions, so it can be cropped out of 4:2:0 frames and encoded as 4:2:0.
+    pub fn to_pixels(self, size: (usize, usize), scale: f64) -> Result<Rect, Error> {
+        let bounds = (size.0 as f64 / scale, size.1 as f64 / scale);
+
+        // Half a pixel of slack, the display size in points was rounded to get `size`
+        let slack = 0.5 / scale;
+        if self.x + self.width > bounds.0 + slack || self.y + self.height > bounds.1 + slack {
+            return Err(Error::msg(format!(
+                "Region {} doesn't fit on a {:.0}x{:.0} point display",
+                self, bounds.0, bounds.1
+            )));
+        }
+
+        // The near edge rounds down and the far one up, so every pixel the region touches
+        // is kept. A far edge that lands past the frame is pulled back in, which on an odd
+        // sized frame loses its last column or row since crops can't end on an odd pixel.
+        // The tolerance stops float error like 1280.0000000002 adding a column.
+        let even = |v: usize| v / 2 * 2;
+        let x = even((self.x * scale).floor() as usize);
+        let y = even((self.y * scale).floor() as usize);
+        let right = ((self.x + self.width) * scale - 1e-6).ceil() as usize;
+        let bottom = ((self.y + self.height) * scale - 1e-6).ceil() as usize;
+
+        let rect = Rect {
+            x,
+            y,
+            width: right.saturating_sub(x).next_multiple_of(2).min(even(size.0 - x)),
+            height: bottom.saturating_sub(y).next_multiple_of(2).min(even(size.1 - y)),
+        };
+
+        if rect.width < 2 || rect.height < 2 {
+            return Err(Error::msg(format!("Region {} is less than 2x2 pixels at scale {}", self, scale)));
+        }
+
+        Ok(rect)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}x{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// `X,Y,WxH`, e.g. `100,200,1280x720`
+impl FromStr for Region {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::msg(format!("Invalid region {:?}, expected X,Y,WxH", s));
+
+        let mut parts = s.splitn(3, ',');
+        let (x, y, size) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(x), Some(y), Some(size)) => (x, y, size),
+            _ => return Err(invalid()),
+        };
+        let (width, height) = size.split_once(['x', 'X']).ok_or_else(invalid)?;
+
+        let number = |v: &str| v.trim().parse::<f64>().ok().filter(|v| v.is_finite());
+        let region = match (number(x), number(y), number(width), number(height)) {
+            (Some(x), Some(y), Some(width), Some(height)) => Region { x, y, width, height },
+            _ => return Err(invalid()),
+        };
+
+        if region.x < 0.0 || region.y < 0.0 {
+            return Err(Error::msg(format!("Region {} starts off the display", region)));
+        }
+
+        if region.width <= 0.0 || region.height <= 0.0 {
+            return Err(Error::msg(format!("Region {} is empty", region)));
+        }
+
+        Ok(region)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covers_the_whole_region() {
+        let rect = |region: &str, size, scale| region.parse::<Region>().unwrap().to_pixels(size, scale).unwrap();
+
+        // Odd edges on both sides grow outwards to even pixels
+        assert_eq!(rect("1,1,10x10", (100, 100), 1.0), Rect { x: 0, y: 0, width: 12, height: 12 });
+        assert_eq!(rect("3,5,4x3", (100, 100), 1.0), Rect { x: 2, y: 4, width: 6, height: 4 });
+        assert_eq!(rect("10.4,0,1.2x2", (100, 100), 1.5), Rect { x: 14, y: 0, width: 4, height: 4 });
+        // Exact fits stay as they are
+        assert_eq!(rect("0,0,1280x720", (2560, 1440), 2.0), Rect { x: 0, y: 0, width: 2560, height: 1440 });
+        assert_eq!(rect("0.1,0.1,0.2x0.2", (100, 100), 10.0), Rect { x: 0, y: 0, width: 4, height: 4 });
+        // Up against the edge of an odd sized frame
+        assert_eq!(rect("96,96,5x5", (101, 101), 1.0), Rect { x: 96, y: 96, width: 4, height: 4 });
+        assert_eq!(rect("95,95,4x4", (100, 100), 1.0), Rect { x: 94, y: 94, width: 6, height: 6 });
+    }
+}
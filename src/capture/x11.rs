@@ -0,0 +1,367 @@
+use anyhow::Error;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt, ImageFormat, MapState, Window};
+use x11rb::rust_connection::RustConnection;
+
+use super::{SourceInfo, Target};
+use crate::frame::{Frame, PixelFormat, Plane};
+
+pub type Stream = X11CaptureStream;
+
+pub struct ResolvedTarget {
+    screen: usize,
+    // Set when recording one window instead of the whole screen
+    window: Option<Window>,
+}
+
+// Screens and windows of the X server in $DISPLAY, X11 has no scale factor so points are pixels
+pub fn list() -> Result<Vec<SourceInfo>, Error> {
+    let (screens, _) = X11CaptureStream::screens(None)?;
+
+    // Screens aren't arranged relative to each other, so they're listed side by side
+    let mut x = 0.0;
+    let displays = screens.into_iter().enumerate().map(|(index, (width, height))| {
+        let info = SourceInfo {
+            kind: "display",
+            id: index as u64,
+            name: format!("X11 screen {}", index),
+            app: None,
+            pid: None,
+            x,
+            y: 0.0,
+            width: width as f64,
+            height: height as f64,
+        };
+        x += width as f64;
+        info
+    });
+
+    let windows = X11CaptureStream::windows(None)?.into_iter().map(|window| window.info());
+
+    Ok(displays.chain(windows).collect())
+}
+
+pub fn prepare(target: &Target, scale: f64) -> Result<(String, usize, usize, ResolvedTarget), Error> {
+    if scale != 1.0 {
+        return Err(Error::msg("X11 capture can't scale, use --scale 1"));
+    }
+
+    match target {
+        Target::Display(index) => {
+            let (screens, default_screen) = X11CaptureStream::screens(None)?;
+            let screen = index.unwrap_or(default_screen);
+            let (width, height) = *screens.get(screen).ok_or(Error::msg("No such X11 screen"))?;
+
+            Ok((format!("X11 screen {}", screen), width, height, ResolvedTarget { screen, window: None }))
+        }
+        Target::Window(selector) => {
+            let window = X11CaptureStream::windows(None)?
+                .into_iter()
+                .find(|window| selector.matches(&window.info()))
+                .ok_or(Error::msg(format!("No window {}", selector)))?;
+
+            let description = format!("X11 window {:?} ({:#x})", window.title, window.id);
+            let resolved = ResolvedTarget { screen: window.screen, window: Some(window.id) };
+
+            Ok((description, window.width as usize, window.height as usize, resolved))
+        }
+        Target::TestPattern(_) => unreachable!("test pattern is handled by capture::prepare"),
+    }
+}
+
+pub fn start<F>(target: ResolvedTarget, fps: u32, mut sink: F) -> Result<Stream, Error>
+where
+    F: FnMut(Option<Frame>) + Send + 'static,
+{
+    X11CaptureStream::new(None, Some(target.screen), target.window, fps, move |result| match result {
+        Ok(X11StreamEvent::Video(frame)) => sink(Some(frame)),
+        Ok(X11StreamEvent::End) => sink(None),
+        Err(e) => tracing::error!("Capture stream error: {}", e),
+    })
+}
+
+pub enum X11StreamEvent {
+    Video(Frame),
+    End,
+}
+
+/// A top level window, as the window manager (or failing that, the root window) lists it.
+pub struct X11Window {
+    pub id: Window,
+    pub screen: usize,
+    pub title: String,
+    /// The class half of `WM_CLASS`
+    pub app: Option<String>,
+    pub pid: Option<u32>,
+    /// Position relative to the root window
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl X11Window {
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            kind: "window",
+            id: self.id as u64,
+            name: self.title.clone(),
+            app: self.app.clone(),
+            pid: self.pid,
+            x: self.x as f64,
+            y: self.y as f64,
+            width: self.width as f64,
+            height: self.height as f64,
+        }
+    }
+}
+
+struct Atoms {
+    net_client_list: Atom,
+    net_wm_name: Atom,
+    net_wm_pid: Atom,
+    utf8_string: Atom,
+}
+
+impl Atoms {
+    fn intern(conn: &RustConnection) -> Result<Self, Error> {
+        let atom = |name: &[u8]| -> Result<Atom, Error> { Ok(conn.intern_atom(false, name)?.reply()?.atom) };
+
+        Ok(Self {
+            net_client_list: atom(b"_NET_CLIENT_LIST")?,
+            net_wm_name: atom(b"_NET_WM_NAME")?,
+            net_wm_pid: atom(b"_NET_WM_PID")?,
+            utf8_string: atom(b"UTF8_STRING")?,
+        })
+    }
+}
+
+/// Polls the root window (or a single window) with `GetImage` at a fixed rate and hands
+/// frames to a callback, mirroring the shape of crabgrab's `CaptureStream`. Works
+/// against Xvfb, so no real display or compositor is required.
+pub struct X11CaptureStream {
+    running: Arc<AtomicBool>,
+    capture_thread: Option<JoinHandle<()>>,
+}
+
+impl X11CaptureStream {
+    /// Sizes in pixels of every screen on `display` (falls back to `$DISPLAY`),
+    /// along with the index of the default one.
+    pub fn screens(display: Option<&str>) -> Result<(Vec<(usize, usize)>, usize), Error> {
+        let (conn, default_screen) = x11rb::connect(display)?;
+        let sizes = conn
+            .setup()
+            .roots
+            .iter()
+            .map(|screen| (screen.width_in_pixels as usize, screen.height_in_pixels as usize))
+            .collect();
+
+        Ok((sizes, default_screen))
+    }
+
+    /// Top level windows on every screen of `display` (falls back to `$DISPLAY`)
+    /// that are mapped and have a title.
+    pub fn windows(display: Option<&str>) -> Result<Vec<X11Window>, Error> {
+        let (conn, _) = x11rb::connect(display)?;
+        let atoms = Atoms::intern(&conn)?;
+        let mut windows = Vec::new();
+
+        for (screen, root) in conn.setup().roots.iter().enumerate() {
+            // EWMH window managers keep a list of client windows, without one the
+            // root's children are the closest thing
+            let clients = conn
+                .get_property(false, root.root, atoms.net_client_list, AtomEnum::WINDOW, 0, u32::MAX)?
+                .reply()?;
+            let mut ids: Vec<Window> = clients.value32().map(|ids| ids.collect()).unwrap_or_default();
+            if ids.is_empty() {
+                ids = conn.query_tree(root.root)?.reply()?.children;
+            }
+
+            for id in ids {
+                // Windows can disappear between listing and asking about them
+                match window_info(&conn, &atoms, screen, root.root, id) {
+                    Ok(Some(window)) => windows.push(window),
+                    Ok(None) => {}
+                    Err(e) => tracing::debug!("Skipping X11 window {:#x}: {}", id, e),
+                }
+            }
+        }
+
+        Ok(windows)
+    }
+
+    /// Records `window`, or when `None` all of `screen` (the default screen of
+    /// `display` when that's `None` too). A window is grabbed at whatever size it
+    /// has each frame, so frames change size when it's resized.
+    pub fn new<F>(display: Option<&str>, screen: Option<usize>, window: Option<Window>, fps: u32, mut callback: F) -> Result<Self, Error>
+    where
+        F: FnMut(Result<X11StreamEvent, Error>) + Send + 'static,
+    {
+        let (conn, default_screen) = x11rb::connect(display)?;
+        let screen = conn
+            .setup()
+            .roots
+            .get(screen.unwrap_or(default_screen))
+            .ok_or(Error::msg("No such X11 screen"))?;
+        let drawable = window.unwrap_or(screen.root);
+        let (mut width, mut height) = (screen.width_in_pixels, screen.height_in_pixels);
+        let mut depth = screen.root_depth;
+
+        if let Some(window) = window {
+            let geometry = conn.get_geometry(window)?.reply()?;
+            (width, height, depth) = (geometry.width, geometry.height, geometry.depth);
+        }
+
+        // ZPixmap data is only plain BGRX when the depth is stored in 32 bits per pixel
+        let bpp = conn
+            .setup()
+            .pixmap_formats
+            .iter()
+            .find(|f| f.depth == depth)
+            .map(|f| f.bits_per_pixel)
+            .ok_or(Error::msg(format!("No pixmap format for depth {}", depth)))?;
+
+        if bpp != 32 {
+            return Err(Error::msg(format!("Unsupported X11 pixmap format: {} bpp", bpp)));
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let frame_interval = Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+
+        let capture_thread = std::thread::spawn({
+            let running = running.clone();
+
+            move || {
+                let mut next_tick = Instant::now();
+
+                while running.load(Ordering::SeqCst) {
+                    if let Some(window) = window {
+                        match conn.get_geometry(window).map(|cookie| cookie.reply()) {
+                            Ok(Ok(geometry)) => (width, height) = (geometry.width, geometry.height),
+                            // Closed, there's nothing more to record
+                            _ => break,
+                        }
+                    }
+
+                    match grab_frame(&conn, drawable, width, height) {
+                        Ok(frame) => callback(Ok(X11StreamEvent::Video(frame))),
+                        Err(e) => callback(Err(e)),
+                    }
+
+                    next_tick += frame_interval;
+                    let now = Instant::now();
+                    if next_tick > now {
+                        std::thread::sleep(next_tick - now);
+                    } else {
+                        // We fell behind, don't try to catch up with a burst of frames
+                        next_tick = now;
+                    }
+                }
+
+                callback(Ok(X11StreamEvent::End));
+            }
+        });
+
+        Ok(Self {
+            running,
+            capture_thread: Some(capture_thread),
+        })
+    }
+
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.running.store(false, Ordering::SeqCst);
+
+        if let Some(capture_thread) = self.capture_thread.take() {
+            capture_thread
+                .join()
+                .map_err(|_| Error::msg("X11 capture thread panicked"))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for X11CaptureStream {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+// None for windows that aren't worth listing: unmapped or untitled
+fn window_info(conn: &RustConnection, atoms: &Atoms, screen: usize, root: Window, id: Window) -> Result<Option<X11Window>, Error> {
+    if conn.get_window_attributes(id)?.reply()?.map_state != MapState::VIEWABLE {
+        return Ok(None);
+    }
+
+    let property = |property: Atom, type_: Atom| -> Result<Vec<u8>, Error> {
+        Ok(conn.get_property(false, id, property, type_, 0, 1024)?.reply()?.value)
+    };
+
+    let mut title = property(atoms.net_wm_name, atoms.utf8_string)?;
+    if title.is_empty() {
+        title = property(AtomEnum::WM_NAME.into(), AtomEnum::STRING.into())?;
+    }
+    if title.is_empty() {
+        return Ok(None);
+    }
+
+    // WM_CLASS is the instance and class names, each null terminated
+    let class = property(AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into())?;
+    let app = class
+        .split(|&b| b == 0)
+        .nth(1)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned());
+
+    let pid = conn
+        .get_property(false, id, atoms.net_wm_pid, AtomEnum::CARDINAL, 0, 1)?
+        .reply()?
+        .value32()
+        .and_then(|mut values| values.next());
+
+    let geometry = conn.get_geometry(id)?.reply()?;
+    let position = conn.translate_coordinates(id, root, 0, 0)?.reply()?;
+
+    Ok(Some(X11Window {
+        id,
+        screen,
+        title: String::from_utf8_lossy(&title).into_owned(),
+        app,
+        pid,
+        x: position.dst_x,
+        y: position.dst_y,
+        width: geometry.width,
+        height: geometry.height,
+    }))
+}
+
+fn grab_frame(conn: &RustConnection, drawable: u32, width: u16, height: u16) -> Result<Frame, Error> {
+    let capture_time = Instant::now();
+    let image = conn
+        .get_image(ImageFormat::Z_PIXMAP, drawable, 0, 0, width, height, !0)?
+        .reply()?;
+
+    let mut data = image.data;
+
+    // X leaves the padding byte undefined, make it opaque
+    for px in data.chunks_exact_mut(4) {
+        px[3] = 0xff;
+    }
+
+    // Work the pitch out from what came back instead of trusting it to be width * 4,
+    // in case the server pads scanlines
+    let stride = data.len() / (height as usize).max(1);
+    if stride < width as usize * 4 {
+        return Err(Error::msg(format!("X11 image is too small: {} bytes for {}x{}", data.len(), width, height)));
+    }
+
+    let plane = Plane { data, stride };
+
+    Ok(Frame::new(width as usize, height as usize, PixelFormat::Bgra, vec![plane], capture_time))
+}
@@ -0,0 +1,248 @@
+use anyhow::Error;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::audio::AudioInput;
+use crate::capture::{MultiDisplay, Region};
+use crate::encoder::{AudioCodec, Backend, Codec, FrameTiming, Profile, RateControl};
+#[cfg(feature = "ffmpeg")]
+use crate::encoder::FfmpegEncoder;
+use crate::frame::PixelFormat;
+use crate::queue::DropPolicy;
+use crate::scale::{AspectMode, Filter, Resolution};
+use crate::segment::ResizePolicy;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Record a display or window to a video file")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// off, error, warn, info, debug or trace. RUST_LOG overrides it per module
+    #[arg(long, global = true, default_value = "info")]
+    pub log_level: LevelFilter,
+
+    /// Also write logs (at least debug level) as JSON lines to this file, for bug reports
+    #[arg(long, global = true)]
+    pub log_json: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List the displays and windows that can be recorded
+    List,
+    /// Record until --duration runs out or Ctrl-C
+    Record(Box<RecordArgs>),
+}
+
+#[derive(Args, Debug)]
+pub struct RecordArgs {
+    /// Index of the display to record, as shown by `list`. Several (e.g. 0,1)
+    /// record at once, see --multi-display
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["window_target", "test_pattern"])]
+    pub display: Vec<usize>,
+
+    /// Record every display at once, see --multi-display
+    #[arg(long, conflicts_with_all = ["display", "window_target", "test_pattern"])]
+    pub all_displays: bool,
+
+    /// How several displays are recorded: composite (one canvas following the display
+    /// arrangement) or separate (one file per display, video-display0.mp4 and so on)
+    #[arg(long, default_value = "composite")]
+    pub multi_display: MultiDisplay,
+
+    /// Record the first window whose title contains this text
+    #[arg(long, group = "window_target")]
+    pub window: Option<String>,
+
+    /// Record the first window of this application (name as shown by `list`)
+    #[arg(long, group = "window_target")]
+    pub window_app: Option<String>,
+
+    /// Record the window with this ID, as shown by `list`
+    #[arg(long, group = "window_target")]
+    pub window_id: Option<u64>,
+
+    /// What to do when the window changes size: scale (letterbox into the first
+    /// size) or restart (finish the file and carry on in video-2.mp4 and so on)
+    #[arg(long, default_value = "scale")]
+    pub on_resize: ResizePolicy,
+
+    /// Record only this part of the display, as X,Y,WxH in points (e.g. 100,200,1280x720).
+    /// Rounded to even pixels for 4:2:0. With composited displays it's a part of the canvas
+    #[arg(long, conflicts_with = "window_target")]
+    pub region: Option<Region>,
+
+    /// Record the built-in test pattern (1920x1080 before scaling) instead of the screen
+    #[arg(long, conflicts_with = "window_target")]
+    pub test_pattern: bool,
+
+    /// Pixel format the test pattern is generated in: bgra, rgba, nv12, i420, i444 or p010
+    #[arg(long, default_value = "bgra", requires = "test_pattern")]
+    pub pattern_format: PixelFormat,
+
+    /// Seconds to record for, runs until Ctrl-C when omitted
+    #[arg(long, value_parser = parse_seconds)]
+    pub duration: Option<f64>,
+
+    /// Output file, the extension picks the container (and backend, unless --backend is given)
+    #[arg(short, long, default_value = "video.mp4")]
+    pub output: PathBuf,
+
+    /// Replace output files that already exist, including other displays' and later segments'
+    #[arg(long)]
+    pub overwrite: bool,
+
+    /// Frames per second to capture at, and the grid --timing cfr puts them on
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u32).range(1..))]
+    pub fps: u32,
+
+    /// cfr re-times frames onto an exact --fps grid, repeating or dropping them as
+    /// needed; vfr keeps the time each frame was captured at
+    #[arg(long, default_value = "cfr")]
+    pub timing: FrameTiming,
+
+    /// Output size relative to the display's size in points (e.g. 2.0 on a Retina display)
+    #[arg(long, default_value_t = 1.0, value_parser = parse_scale)]
+    pub scale: f64,
+
+    /// Scale frames to 1080p, 720p, 4k, WxH, "max 1920 wide" or "max 1080 tall"
+    /// before encoding, on top of --scale
+    #[arg(long)]
+    pub resolution: Option<Resolution>,
+
+    /// How --resolution deals with a different aspect ratio: fit, fill (crops) or letterbox (pads)
+    #[arg(long, default_value = "fit")]
+    pub fit: AspectMode,
+
+    /// Filter for --resolution: bilinear, bicubic or lanczos
+    #[arg(long, default_value = "bicubic")]
+    pub filter: Filter,
+
+    /// h264, hevc, av1, vp9 or ffv1
+    #[arg(long, default_value = "h264")]
+    pub codec: Codec,
+
+    /// Target bitrate in bits per second, accepts k/M suffixes (e.g. 8M)
+    #[arg(long, default_value = "10M", value_parser = parse_bitrate)]
+    pub bitrate: u32,
+
+    /// cbr, vbr, lossless or crf:<0-51>
+    #[arg(long, default_value = "vbr")]
+    pub rate_control: RateControl,
+
+    /// Most frames between keyframes, left to the encoder when omitted
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub keyframe_interval: Option<u32>,
+
+    /// baseline, main or high
+    #[arg(long)]
+    pub profile: Option<Profile>,
+
+    /// Audio track to record alongside: sine, sine:<Hz> or a .wav file. With separate
+    /// displays it goes into the first one's file. sine:<Hz>:<skew>ppm stamps the tone
+    /// with a clock that's off by that much, to check drift correction
+    #[arg(long)]
+    pub audio: Option<AudioInput>,
+
+    /// aac or opus
+    #[arg(long, default_value = "aac", requires = "audio")]
+    pub audio_codec: AudioCodec,
+
+    /// Audio bitrate in bits per second, accepts k/M suffixes
+    #[arg(long, default_value = "128k", value_parser = parse_bitrate, requires = "audio")]
+    pub audio_bitrate: u32,
+
+    /// native, lossless, av1 (or ffmpeg when built with it), defaults to a guess from --output
+    #[arg(long)]
+    pub backend: Option<Backend>,
+
+    /// rav1e speed preset for the av1 backend, 0 (slowest, smallest) to 10 (fastest)
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u8).range(0..=10))]
+    pub av1_speed: u8,
+
+    /// Tiles the av1 backend splits frames into so more threads can work on them, 0 lets rav1e decide
+    #[arg(long, default_value_t = 0)]
+    pub av1_tiles: usize,
+
+    /// libavcodec encoder for the ffmpeg backend, as NAME or NAME:PIXEL_FORMAT (e.g. libx264:yuv444p),
+    /// instead of the one --codec picks. --codec still decides how --rate-control and --profile
+    /// are spelled for it, and the output extension picks the muxer
+    #[cfg(feature = "ffmpeg")]
+    #[arg(long)]
+    pub ffmpeg_encoder: Option<FfmpegEncoder>,
+
+    /// Write a fragmented MP4 with fragments of this many seconds, so a crash
+    /// only loses the last fragment
+    #[arg(long, value_parser = parse_seconds)]
+    pub fragment: Option<f64>,
+
+    /// Frames that can wait for the encoder before --drop-policy kicks in, at least 1
+    #[arg(long, default_value_t = 8, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub queue_size: usize,
+
+    /// drop-oldest, drop-newest or block (holds up capture until the encoder catches up)
+    #[arg(long, default_value = "drop-oldest")]
+    pub drop_policy: DropPolicy,
+
+    /// Pause and resume with Enter. Every pause starts a chapter in outputs that can hold them.
+    #[arg(long)]
+    pub pausable: bool,
+
+    /// Seconds between progress lines, 0 turns them off
+    #[arg(long, default_value_t = 5.0, value_parser = parse_interval)]
+    pub stats_interval: f64,
+
+    /// Print the resolved configuration and exit without recording
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+fn parse_bitrate(s: &str) -> Result<u32, Error> {
+    let (digits, multiplier) = match s.as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 1_000.0),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 1_000_000.0),
+        _ => (s, 1.0),
+    };
+
+    let value: f64 = digits
+        .parse()
+        .map_err(|_| Error::msg(format!("Invalid bitrate: {}", s)))?;
+
+    // NaN fails both comparisons
+    let bits = (value * multiplier).round();
+    if !(bits >= 1.0 && bits <= u32::MAX as f64) {
+        return Err(Error::msg(format!("Bitrate must be between 1 and {} bits per second: {}", u32::MAX, s)));
+    }
+
+    Ok(bits as u32)
+}
+
+// Anything that ends up in a `Duration`, which panics on what isn't finite and positive
+fn parse_seconds(s: &str) -> Result<f64, Error> {
+    let value: f64 = s.parse().map_err(|_| Error::msg(format!("Invalid number of seconds: {}", s)))?;
+
+    match Duration::try_from_secs_f64(value) {
+        Ok(duration) if !duration.is_zero() => Ok(value),
+        _ => Err(Error::msg(format!("Seconds must be above 0 and below 2^64: {}", s))),
+    }
+}
+
+// Like `parse_seconds`, but 0 turns something off
+fn parse_interval(s: &str) -> Result<f64, Error> {
+    match s.parse::<f64>() {
+        Ok(value) if value == 0.0 => Ok(value),
+        _ => parse_seconds(s).map_err(|_| Error::msg(format!("Seconds must be 0 or above, and below 2^64: {}", s))),
+    }
+}
+
+fn parse_scale(s: &str) -> Result<f64, Error> {
+    let value: f64 = s.parse().map_err(|_| Error::msg(format!("Invalid scale: {}", s)))?;
+
+    match value.is_finite() && value > 0.0 {
+        true => Ok(value),
+        false => Err(Error::msg(format!("Scale must be above 0 and finite: {}", s))),
+    }
+}
@@ -0,0 +1,443 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::encoder::Chapter;
+
+// Only the smallest gap between timestamp and arrival in each window goes into the
+// fit, the rest is a source running late rather than its clock being off
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Windows measured before drift is estimated at all
+const SETTLE: f64 = 3.0;
+
+// Real clocks are off by tens of ppm, a few thousand at worst. A source that seems
+// to be further off than this is stalling, and correcting for it would make it worse.
+const MAX_DRIFT: f64 = 0.005;
+
+/// The one timeline every source in a session is stamped against: the host's
+/// monotonic clock, counted from when the session started.
+///
+/// Sources that keep time their own way (an audio device counting samples, the
+/// test pattern counting frames) register and hand every timestamp through their
+/// `SourceClock`, which compares it with when it actually arrived. A clock that
+/// runs fast or slow shows up as that gap growing or shrinking, and gets pulled
+/// back onto the session's. Cheap to clone, every clone is the same clock.
+///
+/// Pausing stops the timeline: nothing gets stamped until it's resumed, and from
+/// then on stamps are moved back by however long the pause was, so the outputs
+/// play straight through it.
+#[derive(Clone)]
+pub struct SessionClock {
+    origin: Instant,
+    sources: Arc<Mutex<Vec<Drift>>>,
+    pauses: Arc<Mutex<Pauses>>,
+}
+
+#[derive(Default)]
+struct Pauses {
+    // When the current pause started, on the wall clock
+    since: Option<Instant>,
+    // Length of every pause that's over
+    total: Duration,
+    // Where each pause is on the session's timeline, and how long it was
+    done: Vec<(Instant, Duration)>,
+}
+
+impl SessionClock {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            sources: Arc::new(Mutex::new(Vec::new())),
+            pauses: Arc::new(Mutex::new(Pauses::default())),
+        }
+    }
+
+    /// Time zero for every output of the session.
+    pub fn origin(&self) -> Instant {
+        self.origin
+    }
+
+    pub fn register(&self, name: &str) -> SourceClock {
+        let mut sources = self.sources.lock().unwrap();
+        sources.push(Drift::new(name));
+
+        SourceClock { sources: self.sources.clone(), pauses: self.pauses.clone(), index: sources.len() - 1 }
+    }
+
+    /// Stops stamping until `resume`, false if the session was already paused.
+    pub fn pause(&self) -> bool {
+        let mut pauses = self.pauses.lock().unwrap();
+
+        match pauses.since {
+            Some(_) => false,
+            None => {
+                pauses.since = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Picks the session back up where it was paused, returning how long the
+    /// pause was, or `None` if it wasn't paused.
+    pub fn resume(&self) -> Option<Duration> {
+        let mut pauses = self.pauses.lock().unwrap();
+        let since = pauses.since.take()?;
+        let length = since.elapsed();
+
+        // On the session's timeline the pause is a single point, where it started
+        let at = since.checked_sub(pauses.total).unwrap_or(self.origin);
+        pauses.total += length;
+        pauses.done.push((at, length));
+
+        Some(length)
+    }
+
+    /// How long the pauses that are over lasted altogether.
+    pub fn paused(&self) -> Duration {
+        self.pauses.lock().unwrap().total
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pauses.lock().unwrap().since.is_some()
+    }
+
+    /// One chapter from the start and another from every point the session was
+    /// resumed at, or none if it never was.
+    pub fn chapters(&self) -> Vec<Chapter> {
+        let pauses = self.pauses.lock().unwrap();
+        if pauses.done.is_empty() {
+            return Vec::new();
+        }
+
+        let first = Chapter { start: self.origin, title: "Part 1".to_string() };
+        let resumed = pauses.done.iter().enumerate().map(|(i, (at, length))| Chapter {
+            start: *at,
+            title: format!("Part {}, after a {:.1}s pause", i + 2, length.as_secs_f64()),
+        });
+
+        std::iter::once(first).chain(resumed).collect()
+    }
+
+    /// Where every registered source stands against the session clock.
+    pub fn offsets(&self) -> Vec<SyncOffset> {
+        self.sources.lock().unwrap().iter().map(Drift::offset).collect()
+    }
+}
+
+impl Default for SessionClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One source's view of the session clock, see `SessionClock::register`.
+pub struct SourceClock {
+    sources: Arc<Mutex<Vec<Drift>>>,
+    pauses: Arc<Mutex<Pauses>>,
+    index: usize,
+}
+
+impl SourceClock {
+    /// Moves `timestamp`, from the source's own clock, onto the session clock.
+    /// `arrived` is when the source handed it over, which is what the source's
+    /// clock is measured against. Stamps from one source never go backwards.
+    /// `None` while the session is paused, what arrives then isn't recorded.
+    pub fn stamp(&self, timestamp: Instant, arrived: Instant) -> Option<Instant> {
+        let stamped = self.sources.lock().unwrap()[self.index].stamp(timestamp, arrived);
+        let pauses = self.pauses.lock().unwrap();
+
+        match pauses.since {
+            Some(_) => None,
+            None => Some(stamped.checked_sub(pauses.total).unwrap_or(stamped)),
+        }
+    }
+}
+
+/// How far a source's clock has wandered from the session's.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncOffset {
+    pub source: String,
+    /// Seconds the source's clock is ahead of the session's, which is what gets
+    /// taken off its timestamps. Negative when it's behind.
+    pub offset: f64,
+    /// How much faster the source's clock runs, in parts per million
+    pub drift_ppm: f64,
+}
+
+impl fmt::Display for SyncOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:+.2}ms ({:+.0} ppm)", self.source, self.offset * 1000.0, self.drift_ppm)
+    }
+}
+
+/// Drift estimate for one source. Every measurement is the gap between when a
+/// timestamp arrived and what it said, against time since the first one. The gap
+/// itself is latency, which doesn't matter as long as it's steady, so all that's
+/// kept is its slope: a least-squares fit over the smallest gap of every window
+/// so far, which delivery jitter barely moves once there are a few of them.
+struct Drift {
+    name: String,
+    first: Option<(Instant, f64)>,
+    // Smallest (t, y) of the window being measured, and which window that is
+    window: Option<(u32, f64, f64)>,
+    // Running sums for the fit, over seconds since the first arrival (t) and the
+    // gap less the first gap (y)
+    n: f64,
+    sum_t: f64,
+    sum_y: f64,
+    sum_tt: f64,
+    sum_ty: f64,
+    // Session seconds gained per second, negative for a source running fast
+    slope: f64,
+    // Correction applied to the latest stamp, in seconds
+    correction: f64,
+    last: Option<Instant>,
+}
+
+impl Drift {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            first: None,
+            window: None,
+            n: 0.0,
+            sum_t: 0.0,
+            sum_y: 0.0,
+            sum_tt: 0.0,
+            sum_ty: 0.0,
+            slope: 0.0,
+            correction: 0.0,
+            last: None,
+        }
+    }
+
+    fn stamp(&mut self, timestamp: Instant, arrived: Instant) -> Instant {
+        let gap = seconds_between(timestamp, arrived);
+        let (first_arrival, first_gap) = *self.first.get_or_insert((arrived, gap));
+
+        let t = arrived.saturating_duration_since(first_arrival).as_secs_f64();
+        let y = gap - first_gap;
+
+        let index = (t / WINDOW.as_secs_f64()) as u32;
+        match self.window {
+            Some((current, _, min)) if current == index && min <= y => {}
+            Some((current, ..)) if current == index => self.window = Some((index, t, y)),
+            _ => {
+                // A new window closes the last one, its smallest gap goes into the fit
+                if let Some((_, t, y)) = self.window {
+                    self.fit(t, y);
+                }
+                self.window = Some((index, t, y));
+            }
+        }
+
+        // Only the slope is used, so the correction grows smoothly from nothing at
+        // the first stamp instead of following every late buffer
+        self.correction = self.slope * t;
+        let stamped = shift(timestamp, self.correction);
+
+        let stamped = self.last.map_or(stamped, |last| stamped.max(last));
+        self.last = Some(stamped);
+        stamped
+    }
+
+    fn fit(&mut self, t: f64, y: f64) {
+        self.n += 1.0;
+        self.sum_t += t;
+        self.sum_y += y;
+        self.sum_tt += t * t;
+        self.sum_ty += t * y;
+
+        let spread = self.n * self.sum_tt - self.sum_t * self.sum_t;
+        if self.n >= SETTLE && spread > 0.0 {
+            let slope = (self.n * self.sum_ty - self.sum_t * self.sum_y) / spread;
+            self.slope = slope.clamp(-MAX_DRIFT, MAX_DRIFT);
+        }
+    }
+
+    fn offset(&self) -> SyncOffset {
+        SyncOffset { source: self.name.clone(), offset: -self.correction, drift_ppm: -self.slope * 1_000_000.0 }
+    }
+}
+
+// `to - from` in seconds, negative when `to` is earlier
+fn seconds_between(from: Instant, to: Instant) -> f64 {
+    match to.checked_duration_since(from) {
+        Some(after) => after.as_secs_f64(),
+        None => -(from - to).as_secs_f64(),
+    }
+}
+
+fn shift(at: Instant, seconds: f64) -> Instant {
+    let by = Duration::from_secs_f64(seconds.abs());
+
+    match seconds >= 0.0 {
+        true => at + by,
+        false => at.checked_sub(by).unwrap_or(at),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Jitter in whole microseconds, from a seeded xorshift64
+    struct Jitter(u64);
+
+    impl Jitter {
+        fn next(&mut self, most: Duration) -> Duration {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+
+            // Mostly a few milliseconds, now and then a buffer that's very late
+            let most = most.as_micros() as u64;
+            match self.0 % 50 {
+                0 => Duration::from_micros(most * 10 + self.0 % most),
+                _ => Duration::from_micros(self.0 % most),
+            }
+        }
+    }
+
+    /// Runs a source whose clock is `ppm` fast for `length`, stamping a buffer every
+    /// 10ms that arrives 5ms late plus jitter. Returns the error of every stamp
+    /// against the session's clock, in seconds, and where the source ended up.
+    fn run(ppm: f64, length: Duration, seed: u64) -> (Vec<f64>, SyncOffset) {
+        let clock = SessionClock::new();
+        let source = clock.register("source");
+        let origin = clock.origin();
+        let mut jitter = Jitter(seed);
+        let mut last: Option<Instant> = None;
+        let mut arrived = origin;
+        let mut errors = Vec::new();
+
+        for i in 0..(length.as_millis() / 10) as u32 {
+            let at = Duration::from_millis(10) * i;
+            let timestamp = origin + at.mul_f64(1.0 + ppm / 1_000_000.0);
+            // Buffers come in order, the ones behind a late one arrive in a burst with it
+            arrived = arrived.max(origin + at + Duration::from_millis(5) + jitter.next(Duration::from_millis(3)));
+
+            let stamped = source.stamp(timestamp, arrived).unwrap();
+            assert!(last.is_none_or(|last| stamped >= last), "stamp {} went backwards", i);
+            last = Some(stamped);
+
+            errors.push(seconds_between(origin + at, stamped));
+        }
+
+        (errors, clock.offsets().remove(0))
+    }
+
+    #[test]
+    fn corrects_drifting_sources() {
+        for (ppm, seed) in [(500.0, 1), (-500.0, 2), (120.0, 3), (0.0, 4)] {
+            let (errors, offset) = run(ppm, Duration::from_secs(120), seed);
+
+            // Uncorrected, 500 ppm would be 60ms out by the end
+            let worst = errors.iter().fold(0.0f64, |worst, e| worst.max(e.abs()));
+            assert!(worst < 0.003, "{} ppm: stamps were up to {:.2}ms out", ppm, worst * 1000.0);
+
+            let end = errors[errors.len() - 1000..].iter().fold(0.0f64, |worst, e| worst.max(e.abs()));
+            assert!(end < 0.0005, "{} ppm: still {:.2}ms out after 110s", ppm, end * 1000.0);
+
+            assert!((offset.drift_ppm - ppm).abs() < 2.0, "{} ppm: estimated {:.1} ppm", ppm, offset.drift_ppm);
+            assert!((offset.offset - ppm * 1e-6 * 120.0).abs() < 0.001, "{} ppm: offset {:.2}ms", ppm, offset.offset * 1000.0);
+        }
+    }
+
+    #[test]
+    fn never_corrects_past_max_drift() {
+        // A source 2% out is stalling rather than drifting, only MAX_DRIFT of it is undone
+        for ppm in [20_000.0, -20_000.0] {
+            let (errors, offset) = run(ppm, Duration::from_secs(60), 5);
+
+            assert_eq!(offset.drift_ppm, MAX_DRIFT * 1_000_000.0 * ppm.signum());
+
+            let left = errors.last().unwrap().abs();
+            let expected = (ppm.abs() / 1_000_000.0 - MAX_DRIFT) * 60.0;
+            assert!((left - expected).abs() < 0.005, "{} ppm: {:.1}ms out", ppm, left * 1000.0);
+        }
+    }
+
+    #[test]
+    fn stamps_never_go_backwards() {
+        let clock = SessionClock::new();
+        let source = clock.register("source");
+        let origin = clock.origin();
+        let mut last = origin;
+        let mut own = Duration::ZERO;
+
+        // A source that swings between 4000 ppm fast and slow, so the estimate keeps
+        // moving, and whose own timestamps step back 3ms now and then
+        for i in 0..20_000u32 {
+            let rate = if (i / 2_000) % 2 == 0 { 1.004 } else { 0.996 };
+            own += Duration::from_millis(10).mul_f64(rate);
+            let timestamp = match i % 7 {
+                0 => origin + own - Duration::from_millis(3),
+                _ => origin + own,
+            };
+
+            let stamped = source.stamp(timestamp, origin + Duration::from_millis(10) * i).unwrap();
+            assert!(stamped >= last, "stamp {} went backwards", i);
+            last = stamped;
+        }
+    }
+
+    #[test]
+    fn takes_pauses_out_of_stamps() {
+        let clock = SessionClock::new();
+        let source = clock.register("source");
+        // A source that's exactly on time, so all that moves its stamps is pausing
+        let stamp = |at: Instant| source.stamp(at, at);
+
+        let before = Instant::now();
+        assert_eq!(stamp(before), Some(before));
+        assert_eq!(clock.resume(), None);
+
+        assert!(clock.pause());
+        let paused_at = Instant::now();
+        assert!(!clock.pause());
+        assert!(clock.is_paused());
+        assert_eq!(stamp(Instant::now()), None);
+
+        std::thread::sleep(Duration::from_millis(20));
+        let first = clock.resume().unwrap();
+        assert!(first >= Duration::from_millis(20));
+        assert!(!clock.is_paused());
+        assert_eq!(clock.resume(), None);
+
+        let after = Instant::now();
+        assert_eq!(stamp(after), Some(after - first));
+
+        assert!(clock.pause());
+        let paused_again_at = Instant::now();
+        std::thread::sleep(Duration::from_millis(10));
+        let second = clock.resume().unwrap();
+
+        // Pauses add up
+        let later = Instant::now();
+        assert_eq!(clock.paused(), first + second);
+        assert_eq!(stamp(later), Some(later - first - second));
+
+        // A chapter from the start, then one where each pause is on the timeline
+        // it leaves behind, which is when it started less the pauses before it
+        let chapters = clock.chapters();
+        assert_eq!(chapters.len(), 3);
+        assert_eq!(chapters[0].start, clock.origin());
+        assert!(chapters[1].start >= before && chapters[1].start <= paused_at);
+        assert!(chapters[2].start >= after - first && chapters[2].start <= paused_again_at - first);
+        assert!(chapters[2].title.starts_with("Part 3, after a "));
+    }
+
+    #[test]
+    fn no_chapters_without_a_pause() {
+        let clock = SessionClock::new();
+        assert!(clock.chapters().is_empty());
+
+        // Not until the pause is over either
+        clock.pause();
+        assert!(clock.chapters().is_empty());
+        clock.resume();
+        assert_eq!(clock.chapters().len(), 2);
+    }
+}
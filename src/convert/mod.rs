@@ -0,0 +1,792 @@
+use thiserror::Error;
+
+use crate::frame::{ColorRange, Frame, Matrix, PixelFormat, Plane, Rect};
+
+pub mod simd;
+
+use simd::Kernel;
+
+/// What a frame should look like after `convert`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Conversion {
+    pub format: PixelFormat,
+    /// Ignored when converting to RGB
+    pub matrix: Matrix,
+    pub range: ColorRange,
+}
+
+impl Conversion {
+    /// Converts to `format` and changes as little else as possible: YUV to YUV keeps
+    /// the frame's matrix and range, RGB to YUV goes to BT.709 limited range and
+    /// anything to RGB goes to full range.
+    pub fn to(format: PixelFormat, from: &Frame) -> Self {
+        let (matrix, range) = match (from.format.is_rgb(), format.is_rgb()) {
+            (_, true) => (Matrix::Bt709, ColorRange::Full),
+            (true, false) => (Matrix::Bt709, ColorRange::Limited),
+            (false, false) => (from.matrix, from.color_range),
+        };
+
+        Self { format, matrix, range }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConvertError {
+    #[error("frame is {width}x{height}, there's nothing to convert")]
+    Empty { width: usize, height: usize },
+
+    #[error("{format:?} needs {expected} planes, got {got}")]
+    PlaneCount { format: PixelFormat, expected: usize, got: usize },
+
+    #[error("plane {plane} has a stride of {stride} bytes but rows are {row} bytes")]
+    StrideTooSmall { plane: usize, stride: usize, row: usize },
+
+    #[error("plane {plane} needs {needed} bytes, got {got}")]
+    PlaneTooSmall { plane: usize, needed: usize, got: usize },
+
+    #[error("can't crop {rect:?} out of a {width}x{height} {format:?} frame")]
+    Crop { rect: Rect, width: usize, height: usize, format: PixelFormat },
+}
+
+/// Checks that the frame's planes are big enough for its format and size, so
+/// nothing reading them has to bounds check each row.
+pub fn validate(frame: &Frame) -> Result<(), ConvertError> {
+    if frame.width == 0 || frame.height == 0 {
+        return Err(ConvertError::Empty { width: frame.width, height: frame.height });
+    }
+
+    let sizes = frame.format.plane_sizes(frame.width, frame.height);
+
+    if frame.planes.len() != sizes.len() {
+        return Err(ConvertError::PlaneCount { format: frame.format, expected: sizes.len(), got: frame.planes.len() });
+    }
+
+    for (i, (plane, (row, rows))) in frame.planes.iter().zip(sizes).enumerate() {
+        if plane.stride < row {
+            return Err(ConvertError::StrideTooSmall { plane: i, stride: plane.stride, row });
+        }
+
+        // The last row doesn't need its padding
+        let needed = plane.stride * rows.saturating_sub(1) + row;
+        if plane.data.len() < needed {
+            return Err(ConvertError::PlaneTooSmall { plane: i, needed, got: plane.data.len() });
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts `frame` to the format, matrix and range in `to`. A frame that already
+/// matches is handed back untouched, otherwise the result has tightly packed
+/// planes. Subsampled chroma is averaged over each 2x2 block on the way down and
+/// repeated on the way up, odd edges only use the pixels inside the frame.
+pub fn convert(frame: Frame, to: Conversion) -> Result<Frame, ConvertError> {
+    validate(&frame)?;
+
+    let same_color = match to.format.is_rgb() {
+        true => frame.color_range == to.range,
+        false => frame.color_range == to.range && frame.matrix == to.matrix,
+    };
+
+    if frame.format == to.format && same_color {
+        return Ok(frame);
+    }
+
+    // What every capturer hands us and most encoders want, worth its own fast path
+    let fast = frame.format == PixelFormat::Bgra
+        && frame.color_range == ColorRange::Full
+        && matches!(to.format, PixelFormat::Nv12 | PixelFormat::I420);
+
+    if fast {
+        let planes = simd::bgra_to_420(&frame, to, Kernel::detect());
+        return Ok(converted(&frame, to, planes));
+    }
+
+    let (width, height) = (frame.width, frame.height);
+    let reader = Reader::new(&frame, to);
+    let mut writer = Writer::new(width, height, to);
+
+    // Work two rows at a time so 4:2:0 output can average chroma vertically
+    let mut rows = [vec![[0.0; 4]; width], vec![[0.0; 4]; width]];
+
+    for y in (0..height).step_by(2) {
+        let pair = (height - y).min(2);
+
+        for (i, row) in rows.iter_mut().take(pair).enumerate() {
+            reader.read_row(y + i, row);
+        }
+
+        writer.write_rows(y, &rows[..pair]);
+    }
+
+    Ok(converted(&frame, to, writer.planes))
+}
+
+fn converted(frame: &Frame, to: Conversion, planes: Vec<Plane>) -> Frame {
+    Frame {
+        width: frame.width,
+        height: frame.height,
+        format: to.format,
+        color_space: frame.color_space,
+        color_range: to.range,
+        matrix: to.matrix,
+        planes,
+        timestamp: frame.timestamp,
+        #[cfg(not(target_os = "linux"))]
+        native: None,
+    }
+}
+
+/// Copies the frame upside down into `out`, as tightly packed planes one after
+/// the other. Whatever `out` held is replaced, but its allocation is kept, so the
+/// same buffer can take every frame of a recording.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub fn flip_vertical(frame: &Frame, out: &mut Vec<u8>) -> Result<(), ConvertError> {
+    validate(frame)?;
+    out.clear();
+
+    for (plane, (row, rows)) in frame.planes.iter().zip(frame.format.plane_sizes(frame.width, frame.height)) {
+        for y in (0..rows).rev() {
+            out.extend_from_slice(&plane.data[y * plane.stride..y * plane.stride + row]);
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies `rect` out of the frame into tightly packed planes. The rectangle has
+/// to start on an even pixel for subsampled formats, so chroma stays aligned.
+pub fn crop(frame: &Frame, rect: Rect) -> Result<Frame, ConvertError> {
+    validate(frame)?;
+
+    let subsampled = !matches!(frame.format, PixelFormat::Bgra | PixelFormat::Rgba | PixelFormat::I444);
+    let inside = rect.x + rect.width <= frame.width && rect.y + rect.height <= frame.height;
+    let aligned = !subsampled || (rect.x.is_multiple_of(2) && rect.y.is_multiple_of(2));
+
+    if rect.width == 0 || rect.height == 0 || !inside || !aligned {
+        return Err(ConvertError::Crop { rect, width: frame.width, height: frame.height, format: frame.format });
+    }
+
+    // With an even origin, the bytes and rows before it come straight out of plane_sizes
+    let offsets = frame.format.plane_sizes(rect.x, rect.y);
+    let sizes = frame.format.plane_sizes(rect.width, rect.height);
+
+    let planes = frame
+        .planes
+        .iter()
+        .zip(offsets.into_iter().zip(sizes))
+        .map(|(plane, ((left, top), (row, rows)))| {
+            let mut data = Vec::with_capacity(row * rows);
+            for y in top..top + rows {
+                let start = y * plane.stride + left;
+                data.extend_from_slice(&plane.data[start..start + row]);
+            }
+            Plane { data, stride: row }
+        })
+        .collect();
+
+    Ok(Frame {
+        width: rect.width,
+        height: rect.height,
+        format: frame.format,
+        color_space: frame.color_space,
+        color_range: frame.color_range,
+        matrix: frame.matrix,
+        planes,
+        timestamp: frame.timestamp,
+        #[cfg(not(target_os = "linux"))]
+        native: None,
+    })
+}
+
+/// Copies the visible bytes of plane `index` row by row into a buffer with rows
+/// `dst_stride` bytes apart, e.g. one a library allocated with its own padding.
+#[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+pub fn copy_plane(frame: &Frame, index: usize, dst: &mut [u8], dst_stride: usize) -> Result<(), ConvertError> {
+    validate(frame)?;
+
+    let plane = &frame.planes[index];
+    let (row, rows) = frame.format.plane_sizes(frame.width, frame.height)[index];
+
+    for (dst_row, src_row) in dst.chunks_mut(dst_stride).zip(plane.data.chunks(plane.stride)).take(rows) {
+        dst_row[..row].copy_from_slice(&src_row[..row]);
+    }
+
+    Ok(())
+}
+
+// Pixels between reading and writing are [f32; 4]: R, G, B, A in 0..1 for RGB
+// targets, or Y in 0..1, Cb and Cr in -0.5..0.5 plus A for YUV targets.
+
+#[derive(Clone, Copy)]
+struct Coefficients {
+    kr: f32,
+    kb: f32,
+}
+
+impl Coefficients {
+    fn of(matrix: Matrix) -> Self {
+        match matrix {
+            Matrix::Bt601 => Self { kr: 0.299, kb: 0.114 },
+            Matrix::Bt709 => Self { kr: 0.2126, kb: 0.0722 },
+        }
+    }
+
+    fn to_ycc(self, [r, g, b, a]: [f32; 4]) -> [f32; 4] {
+        let y = self.kr * r + (1.0 - self.kr - self.kb) * g + self.kb * b;
+        [y, (b - y) / (2.0 * (1.0 - self.kb)), (r - y) / (2.0 * (1.0 - self.kr)), a]
+    }
+
+    fn to_rgb(self, [y, cb, cr, a]: [f32; 4]) -> [f32; 4] {
+        let r = y + 2.0 * (1.0 - self.kr) * cr;
+        let b = y + 2.0 * (1.0 - self.kb) * cb;
+        let g = (y - self.kr * r - self.kb * b) / (1.0 - self.kr - self.kb);
+        [r, g, b, a]
+    }
+}
+
+/// Maps between code values and normalized samples for one bit depth and range.
+#[derive(Clone, Copy)]
+struct Quantizer {
+    offset: f32,
+    scale: f32,
+    chroma_offset: f32,
+    chroma_scale: f32,
+    max: f32,
+}
+
+impl Quantizer {
+    fn new(format: PixelFormat, range: ColorRange) -> Self {
+        let shift = match format {
+            PixelFormat::P010 => 2,
+            _ => 0,
+        };
+        let max = ((256 << shift) - 1) as f32;
+        let mid = (128 << shift) as f32;
+
+        match range {
+            ColorRange::Limited => Self {
+                offset: (16 << shift) as f32,
+                scale: (219 << shift) as f32,
+                chroma_offset: mid,
+                chroma_scale: (224 << shift) as f32,
+                max,
+            },
+            ColorRange::Full => Self { offset: 0.0, scale: max, chroma_offset: mid, chroma_scale: max, max },
+        }
+    }
+
+    fn luma(&self, v: u16) -> f32 {
+        (v as f32 - self.offset) / self.scale
+    }
+
+    fn chroma(&self, v: u16) -> f32 {
+        (v as f32 - self.chroma_offset) / self.chroma_scale
+    }
+
+    fn quantize_luma(&self, v: f32) -> u16 {
+        (self.offset + v * self.scale).round().clamp(0.0, self.max) as u16
+    }
+
+    fn quantize_chroma(&self, v: f32) -> u16 {
+        (self.chroma_offset + v * self.chroma_scale).round().clamp(0.0, self.max) as u16
+    }
+}
+
+fn sample(plane: &Plane, x: usize, y: usize, wide: bool) -> u16 {
+    match wide {
+        true => {
+            let i = y * plane.stride + x * 2;
+            u16::from_le_bytes([plane.data[i], plane.data[i + 1]]) >> 6
+        }
+        false => plane.data[y * plane.stride + x] as u16,
+    }
+}
+
+struct Reader<'a> {
+    frame: &'a Frame,
+    quantizer: Quantizer,
+    // Into the target's colour model, if it differs from the source's
+    to_rgb: Option<Coefficients>,
+    to_ycc: Option<Coefficients>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(frame: &'a Frame, to: Conversion) -> Self {
+        let source = Coefficients::of(frame.matrix);
+        let target = Coefficients::of(to.matrix);
+
+        let (to_rgb, to_ycc) = match (frame.format.is_rgb(), to.format.is_rgb()) {
+            (true, true) => (None, None),
+            (true, false) => (None, Some(target)),
+            (false, true) => (Some(source), None),
+            (false, false) if frame.matrix == to.matrix => (None, None),
+            (false, false) => (Some(source), Some(target)),
+        };
+
+        Self { frame, quantizer: Quantizer::new(frame.format, frame.color_range), to_rgb, to_ycc }
+    }
+
+    fn read_row(&self, y: usize, out: &mut [[f32; 4]]) {
+        let (frame, q) = (self.frame, &self.quantizer);
+        let planes = &frame.planes;
+
+        match frame.format {
+            PixelFormat::Bgra | PixelFormat::Rgba => {
+                let (r, b) = match frame.format {
+                    PixelFormat::Bgra => (2, 0),
+                    _ => (0, 2),
+                };
+                let row = &planes[0].data[y * planes[0].stride..];
+
+                for (px, src) in out.iter_mut().zip(row.chunks_exact(4)) {
+                    let v = |i: usize| q.luma(src[i] as u16);
+                    *px = [v(r), v(1), v(b), src[3] as f32 / 255.0];
+                }
+            }
+            PixelFormat::Nv12 | PixelFormat::P010 => {
+                let wide = frame.format == PixelFormat::P010;
+
+                for (x, px) in out.iter_mut().enumerate() {
+                    let luma = sample(&planes[0], x, y, wide);
+                    let cb = sample(&planes[1], x / 2 * 2, y / 2, wide);
+                    let cr = sample(&planes[1], x / 2 * 2 + 1, y / 2, wide);
+                    *px = [q.luma(luma), q.chroma(cb), q.chroma(cr), 1.0];
+                }
+            }
+            PixelFormat::I420 | PixelFormat::I444 => {
+                let (cx, cy) = match frame.format {
+                    PixelFormat::I420 => (2, 2),
+                    _ => (1, 1),
+                };
+
+                for (x, px) in out.iter_mut().enumerate() {
+                    let luma = sample(&planes[0], x, y, false);
+                    let cb = sample(&planes[1], x / cx, y / cy, false);
+                    let cr = sample(&planes[2], x / cx, y / cy, false);
+                    *px = [q.luma(luma), q.chroma(cb), q.chroma(cr), 1.0];
+                }
+            }
+        }
+
+        if self.to_rgb.is_none() && self.to_ycc.is_none() {
+            return;
+        }
+
+        for px in out.iter_mut() {
+            if let Some(c) = self.to_rgb {
+                *px = c.to_rgb(*px);
+            }
+            if let Some(c) = self.to_ycc {
+                *px = c.to_ycc(*px);
+            }
+        }
+    }
+}
+
+struct Writer {
+    width: usize,
+    format: PixelFormat,
+    quantizer: Quantizer,
+    planes: Vec<Plane>,
+}
+
+impl Writer {
+    fn new(width: usize, height: usize, to: Conversion) -> Self {
+        let planes = to
+            .format
+            .plane_sizes(width, height)
+            .into_iter()
+            .map(|(row, rows)| Plane { data: vec![0; row * rows], stride: row })
+            .collect();
+
+        Self { width, format: to.format, quantizer: Quantizer::new(to.format, to.range), planes }
+    }
+
+    /// `rows` is one or two rows starting at `y`, `y` is always even.
+    fn write_rows(&mut self, y: usize, rows: &[Vec<[f32; 4]>]) {
+        let q = self.quantizer;
+
+        match self.format {
+            PixelFormat::Bgra | PixelFormat::Rgba => {
+                let (r, b) = match self.format {
+                    PixelFormat::Bgra => (2, 0),
+                    _ => (0, 2),
+                };
+                let plane = &mut self.planes[0];
+
+                for (i, row) in rows.iter().enumerate() {
+                    let start = (y + i) * plane.stride;
+                    let out = &mut plane.data[start..start + plane.stride];
+
+                    for (dst, px) in out.chunks_exact_mut(4).zip(row) {
+                        dst[r] = q.quantize_luma(px[0]) as u8;
+                        dst[1] = q.quantize_luma(px[1]) as u8;
+                        dst[b] = q.quantize_luma(px[2]) as u8;
+                        dst[3] = (px[3] * 255.0).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            }
+            PixelFormat::I444 => {
+                for (i, row) in rows.iter().enumerate() {
+                    for (p, plane) in self.planes.iter_mut().enumerate() {
+                        let start = (y + i) * plane.stride;
+                        for (dst, px) in plane.data[start..start + plane.stride].iter_mut().zip(row) {
+                            *dst = match p {
+                                0 => q.quantize_luma(px[0]),
+                                _ => q.quantize_chroma(px[p]),
+                            } as u8;
+                        }
+                    }
+                }
+            }
+            PixelFormat::Nv12 | PixelFormat::I420 | PixelFormat::P010 => {
+                let wide = self.format == PixelFormat::P010;
+                let put = |plane: &mut Plane, x: usize, y: usize, v: u16| match wide {
+                    true => {
+                        let i = y * plane.stride + x * 2;
+                        plane.data[i..i + 2].copy_from_slice(&(v << 6).to_le_bytes());
+                    }
+                    false => plane.data[y * plane.stride + x] = v as u8,
+                };
+
+                for (i, row) in rows.iter().enumerate() {
+                    for (x, px) in row.iter().enumerate() {
+                        put(&mut self.planes[0], x, y + i, q.quantize_luma(px[0]));
+                    }
+                }
+
+                for cx in 0..self.width.div_ceil(2) {
+                    let (mut cb, mut cr, mut n) = (0.0, 0.0, 0.0);
+
+                    for row in rows {
+                        for px in &row[cx * 2..(cx * 2 + 2).min(self.width)] {
+                            cb += px[1];
+                            cr += px[2];
+                            n += 1.0;
+                        }
+                    }
+
+                    let (cb, cr) = (q.quantize_chroma(cb / n), q.quantize_chroma(cr / n));
+
+                    match self.format {
+                        PixelFormat::I420 => {
+                            put(&mut self.planes[1], cx, y / 2, cb);
+                            put(&mut self.planes[2], cx, y / 2, cr);
+                        }
+                        _ => {
+                            put(&mut self.planes[1], cx * 2, y / 2, cb);
+                            put(&mut self.planes[1], cx * 2 + 1, y / 2, cr);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn bgra(width: usize, height: usize, [r, g, b]: [u8; 3]) -> Frame {
+        let data = [b, g, r, 255].repeat(width * height);
+        Frame::new(width, height, PixelFormat::Bgra, vec![Plane { data, stride: width * 4 }], Instant::now())
+    }
+
+    // Reference code values, Y Cb Cr, for black, white, red, green and blue
+    const REFERENCE: [(Matrix, ColorRange, [[u8; 3]; 5]); 4] = [
+        (Matrix::Bt601, ColorRange::Limited, [[16, 128, 128], [235, 128, 128], [81, 90, 240], [145, 54, 34], [41, 240, 110]]),
+        (Matrix::Bt601, ColorRange::Full, [[0, 128, 128], [255, 128, 128], [76, 85, 255], [150, 44, 21], [29, 255, 107]]),
+        (Matrix::Bt709, ColorRange::Limited, [[16, 128, 128], [235, 128, 128], [63, 102, 240], [173, 42, 26], [32, 240, 118]]),
+        (Matrix::Bt709, ColorRange::Full, [[0, 128, 128], [255, 128, 128], [54, 99, 255], [182, 30, 12], [18, 255, 116]]),
+    ];
+
+    const COLORS: [[u8; 3]; 5] = [[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 255, 0], [0, 0, 255]];
+
+    #[test]
+    fn rgb_to_yuv_matches_reference_values() {
+        for (matrix, range, expected) in REFERENCE {
+            for (rgb, ycc) in COLORS.into_iter().zip(expected) {
+                // I444 goes through the floating point path, I420 through the BGRA fast path
+                for format in [PixelFormat::I444, PixelFormat::I420] {
+                    let out = convert(bgra(4, 4, rgb), Conversion { format, matrix, range }).unwrap();
+                    let got = [out.planes[0].data[0], out.planes[1].data[0], out.planes[2].data[0]];
+                    assert_eq!(got, ycc, "{:?} {:?} {:?} of {:?}", format, matrix, range, rgb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn yuv_to_rgb_matches_reference_values() {
+        for (matrix, range, values) in REFERENCE {
+            for (rgb, [y, cb, cr]) in COLORS.into_iter().zip(values) {
+                let planes = [y, cb, cr].map(|v| Plane { data: vec![v; 4], stride: 2 }).into();
+                let mut frame = Frame::new(2, 2, PixelFormat::I444, planes, Instant::now());
+                frame.matrix = matrix;
+                frame.color_range = range;
+
+                let out = convert(frame, Conversion { format: PixelFormat::Rgba, matrix, range: ColorRange::Full }).unwrap();
+                for (got, want) in out.planes[0].data[..3].iter().zip(rgb) {
+                    assert!(got.abs_diff(want) <= 1, "{:?} {:?} {:?} came back as {:?}", matrix, range, rgb, &out.planes[0].data[..3]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reads_rows_by_stride() {
+        let (width, height) = (5, 3);
+        let packed = || bgra(width, height, [200, 100, 50]);
+
+        // Same pixels, with padding that would show up if it were read as pixels
+        let stride = width * 4 + 12;
+        let mut data = vec![0xee; stride * height];
+        for (dst, src) in data.chunks_mut(stride).zip(packed().planes[0].data.chunks(width * 4)) {
+            dst[..width * 4].copy_from_slice(src);
+        }
+        let padded = || Frame::new(width, height, PixelFormat::Bgra, vec![Plane { data: data.clone(), stride }], Instant::now());
+
+        for format in [PixelFormat::Nv12, PixelFormat::I444] {
+            let to = Conversion { format, matrix: Matrix::Bt709, range: ColorRange::Limited };
+            let (a, b) = (convert(packed(), to).unwrap(), convert(padded(), to).unwrap());
+
+            for (a, b) in a.planes.iter().zip(&b.planes) {
+                assert_eq!(a.data, b.data, "{:?}", format);
+            }
+        }
+    }
+
+    const FORMATS: [PixelFormat; 6] =
+        [PixelFormat::Bgra, PixelFormat::Rgba, PixelFormat::Nv12, PixelFormat::I420, PixelFormat::I444, PixelFormat::P010];
+
+    // xorshift64, seeded so a failure can be reproduced
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        // 1 and odd sizes come up often
+        fn size(&mut self) -> usize {
+            match self.below(4) {
+                0 => 1,
+                _ => 1 + self.below(37),
+            }
+        }
+    }
+
+    // Random colours in 2x2 blocks, so 4:2:0 chroma isn't what's being measured,
+    // with extremes more likely than they'd otherwise be
+    fn random_bgra(rng: &mut Rng, width: usize, height: usize) -> Frame {
+        let channel = |rng: &mut Rng| match rng.below(3) {
+            0 => [0, 1, 254, 255][rng.below(4)],
+            _ => rng.below(256) as u8,
+        };
+        let blocks: Vec<[u8; 4]> =
+            (0..width.div_ceil(2) * height.div_ceil(2)).map(|_| [channel(rng), channel(rng), channel(rng), 255]).collect();
+
+        let mut data = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                data.extend_from_slice(&blocks[y / 2 * width.div_ceil(2) + x / 2]);
+            }
+        }
+
+        Frame::new(width, height, PixelFormat::Bgra, vec![Plane { data, stride: width * 4 }], Instant::now())
+    }
+
+    fn random_frame(rng: &mut Rng, format: PixelFormat) -> Frame {
+        let (width, height) = (rng.size(), rng.size());
+        let matrix = [Matrix::Bt601, Matrix::Bt709][rng.below(2)];
+        let range = match format.is_rgb() {
+            true => ColorRange::Full,
+            false => [ColorRange::Limited, ColorRange::Full][rng.below(2)],
+        };
+
+        convert(random_bgra(rng, width, height), Conversion { format, matrix, range }).unwrap()
+    }
+
+    // The same frame with `pad` bytes of garbage after every row of every plane
+    fn padded(frame: &Frame, rng: &mut Rng, pad: usize) -> Frame {
+        let planes = frame
+            .planes
+            .iter()
+            .zip(frame.format.plane_sizes(frame.width, frame.height))
+            .map(|(plane, (row, rows))| {
+                let stride = row + pad;
+                let mut data: Vec<u8> = (0..stride * rows).map(|_| rng.below(256) as u8).collect();
+                for y in 0..rows {
+                    data[y * stride..y * stride + row].copy_from_slice(&plane.data[y * plane.stride..y * plane.stride + row]);
+                }
+                Plane { data, stride }
+            })
+            .collect();
+
+        Frame { planes, ..*frame }
+    }
+
+    // Visible code values of every plane, 10-bit samples as they are
+    fn values(frame: &Frame) -> Vec<Vec<u16>> {
+        let wide = frame.format == PixelFormat::P010;
+
+        frame
+            .planes
+            .iter()
+            .zip(frame.format.plane_sizes(frame.width, frame.height))
+            .map(|(plane, (row, rows))| {
+                let width = if wide { row / 2 } else { row };
+                (0..rows).flat_map(|y| (0..width).map(move |x| sample(plane, x, y, wide))).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_between_every_pair_of_formats() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for from in FORMATS {
+            for through in FORMATS {
+                // 8-bit YUV can't tell apart every 8-bit RGB colour, quantizing it costs
+                // up to about 1.6 RGB code values. Every other way round is within one.
+                let tolerance = match from.is_rgb() && !through.is_rgb() && through != PixelFormat::P010 {
+                    true => 2,
+                    false => 1,
+                };
+                // Compared at the precision of the 8-bit format in between
+                let shift = match (from, through) {
+                    (PixelFormat::P010, through) if through != PixelFormat::P010 => 2,
+                    _ => 0,
+                };
+
+                for _ in 0..50 {
+                    let frame = random_frame(&mut rng, from);
+                    let (width, height) = (frame.width, frame.height);
+                    let pad = rng.below(18);
+                    let original = values(&frame);
+
+                    let there = Conversion::to(through, &frame);
+                    let back = Conversion { format: from, matrix: frame.matrix, range: frame.color_range };
+
+                    // Padded rows have to give exactly what packed ones do
+                    let via_padded = convert(padded(&frame, &mut rng, pad), there).unwrap();
+                    let via_packed = convert(padded(&frame, &mut rng, 0), there).unwrap();
+                    assert_eq!(
+                        values(&via_padded),
+                        values(&via_packed),
+                        "{:?} to {:?} at {}x{}, {} bytes of padding", from, through, width, height, pad
+                    );
+
+                    let returned = convert(padded(&via_padded, &mut rng, pad), back).unwrap();
+                    for (a, b) in original.iter().zip(values(&returned)) {
+                        let worst = a.iter().zip(&b).map(|(a, b)| (a >> shift).abs_diff(b >> shift)).max().unwrap();
+                        assert!(worst <= tolerance, "{:?} through {:?} is off by {} at {}x{}", from, through, worst, width, height);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crops_the_requested_rectangle() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        for format in FORMATS {
+            let subsampled = matches!(format, PixelFormat::Nv12 | PixelFormat::I420 | PixelFormat::P010);
+
+            for _ in 0..50 {
+                let frame = padded(&random_frame(&mut rng, format), &mut rng, 5);
+                let (width, height) = (frame.width, frame.height);
+
+                let mut origin = |size: usize| match subsampled {
+                    true => rng.below(size.div_ceil(2)) * 2,
+                    false => rng.below(size),
+                };
+                let (x, y) = (origin(width), origin(height));
+                let rect = Rect { x, y, width: 1 + rng.below(width - x), height: 1 + rng.below(height - y) };
+
+                // Cropping and then converting has to give the same pixels as the other way round
+                let cropped = crop(&frame, rect).unwrap();
+                assert_eq!((cropped.width, cropped.height), (rect.width, rect.height));
+
+                let to_rgb = Conversion::to(PixelFormat::Bgra, &frame);
+                let cropped = values(&convert(cropped, to_rgb).unwrap()).remove(0);
+                let whole = values(&convert(frame, to_rgb).unwrap()).remove(0);
+
+                for row in 0..rect.height {
+                    let start = ((rect.y + row) * width + rect.x) * 4;
+                    assert_eq!(
+                        cropped[row * rect.width * 4..(row + 1) * rect.width * 4],
+                        whole[start..start + rect.width * 4],
+                        "{:?} row {} of {:?} out of {}x{}", format, row, rect, width, height
+                    );
+                }
+            }
+        }
+
+        let i420 = Conversion { format: PixelFormat::I420, matrix: Matrix::Bt709, range: ColorRange::Limited };
+        let frame = convert(random_bgra(&mut rng, 8, 6), i420).unwrap();
+        for rect in [
+            Rect { x: 1, y: 0, width: 2, height: 2 },
+            Rect { x: 0, y: 0, width: 0, height: 2 },
+            Rect { x: 4, y: 2, width: 6, height: 2 },
+        ] {
+            assert!(matches!(crop(&frame, rect), Err(ConvertError::Crop { .. })), "{:?}", rect);
+        }
+    }
+
+    #[test]
+    fn flips_every_plane_upside_down() {
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        // Reused the way the Media Foundation encoder does, leftovers mustn't show up
+        let mut out = vec![0xaa; 1 << 16];
+
+        for format in FORMATS {
+            for _ in 0..20 {
+                let pad = rng.below(9);
+                let frame = padded(&random_frame(&mut rng, format), &mut rng, pad);
+                flip_vertical(&frame, &mut out).unwrap();
+
+                let mut expected = Vec::new();
+                for (plane, (row, rows)) in frame.planes.iter().zip(format.plane_sizes(frame.width, frame.height)) {
+                    for y in (0..rows).rev() {
+                        expected.extend_from_slice(&plane.data[y * plane.stride..y * plane.stride + row]);
+                    }
+                }
+                assert_eq!(out, expected, "{:?} {}x{}", format, frame.width, frame.height);
+            }
+        }
+    }
+
+    #[test]
+    fn copies_planes_between_strides() {
+        let mut rng = Rng(0x94d0_49bb_1331_11eb);
+
+        for format in FORMATS {
+            for _ in 0..20 {
+                let pad = rng.below(9);
+                let frame = padded(&random_frame(&mut rng, format), &mut rng, pad);
+
+                for (index, (row, rows)) in format.plane_sizes(frame.width, frame.height).into_iter().enumerate() {
+                    let dst_stride = row + rng.below(33);
+                    let mut dst = vec![0xaa; dst_stride * rows];
+                    copy_plane(&frame, index, &mut dst, dst_stride).unwrap();
+
+                    let src = &frame.planes[index];
+                    for y in 0..rows {
+                        let dst_row = &dst[y * dst_stride..(y + 1) * dst_stride];
+                        let src_row = &src.data[y * src.stride..y * src.stride + row];
+                        assert_eq!(dst_row[..row], *src_row, "{:?} plane {} row {}", format, index, y);
+                        assert!(dst_row[row..].iter().all(|&b| b == 0xaa), "{:?} plane {} wrote past its row", format, index);
+                    }
+                }
+            }
+        }
+    }
+}
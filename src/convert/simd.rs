@@ -0,0 +1,456 @@
+use std::sync::OnceLock;
+
+use super::{Coefficients, Conversion};
+use crate::frame::{ColorRange, Frame, PixelFormat, Plane};
+
+// Luma weights are scaled by 2^14. Chroma is computed from the sum of a 2x2
+// block, which is 4x the average, so it's shifted by two more.
+const Y_SHIFT: i32 = 14;
+const C_SHIFT: i32 = 16;
+
+/// Which inner loop BGRA to 4:2:0 conversion runs. All of them give the same
+/// output, the scalar one is the reference.
+// Only the current architecture's kernels ever get constructed
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    Sse2,
+    Avx2,
+    Neon,
+}
+
+impl Kernel {
+    /// The fastest kernel this CPU supports, only checked the first time.
+    pub fn detect() -> Kernel {
+        static KERNEL: OnceLock<Kernel> = OnceLock::new();
+
+        *KERNEL.get_or_init(|| {
+            let kernel = Kernel::available().last().copied().unwrap_or(Kernel::Scalar);
+            tracing::debug!(?kernel, "picked BGRA conversion kernel");
+            kernel
+        })
+    }
+
+    /// Every kernel this CPU can run, slowest first.
+    pub fn available() -> Vec<Kernel> {
+        #[allow(unused_mut)]
+        let mut kernels = vec![Kernel::Scalar];
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if std::arch::is_x86_feature_detected!("sse2") {
+                kernels.push(Kernel::Sse2);
+            }
+            if std::arch::is_x86_feature_detected!("avx2") {
+                kernels.push(Kernel::Avx2);
+            }
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            kernels.push(Kernel::Neon);
+        }
+
+        kernels
+    }
+}
+
+/// `convert`'s matrix and range as fixed point weights, in the pixels' B, G, R order.
+#[derive(Clone, Copy)]
+struct Fixed {
+    y: [i16; 3],
+    cb: [i16; 3],
+    cr: [i16; 3],
+    // Black level plus rounding, already shifted
+    y_offset: i32,
+    c_offset: i32,
+}
+
+impl Fixed {
+    fn new(to: Conversion) -> Self {
+        let Coefficients { kr, kb } = Coefficients::of(to.matrix);
+        let kg = 1.0 - kr - kb;
+
+        let (y_scale, c_scale, black) = match to.range {
+            ColorRange::Limited => (219.0 / 255.0, 224.0 / 255.0, 16),
+            ColorRange::Full => (1.0, 1.0, 0),
+        };
+
+        let fix = |v: f32| (v * (1 << Y_SHIFT) as f32).round() as i16;
+        let cb = 2.0 * (1.0 - kb);
+        let cr = 2.0 * (1.0 - kr);
+
+        Self {
+            y: [fix(kb * y_scale), fix(kg * y_scale), fix(kr * y_scale)],
+            cb: [fix(0.5 * c_scale), fix(-kg / cb * c_scale), fix(-kr / cb * c_scale)],
+            cr: [fix(-kb / cr * c_scale), fix(-kg / cr * c_scale), fix(0.5 * c_scale)],
+            y_offset: (black << Y_SHIFT) + (1 << (Y_SHIFT - 1)),
+            c_offset: (128 << C_SHIFT) + (1 << (C_SHIFT - 1)),
+        }
+    }
+}
+
+/// Two source rows and where their luma and shared chroma go.
+struct Rows<'a> {
+    bgra: [&'a [u8]; 2],
+    y: [&'a mut [u8]; 2],
+    cb: &'a mut [u8],
+    cr: &'a mut [u8],
+}
+
+/// Full range BGRA to NV12 or I420 with `to`'s matrix and range, in fixed point.
+/// Within one code value of the floating point path, and identical for every kernel.
+pub fn bgra_to_420(frame: &Frame, to: Conversion, kernel: Kernel) -> Vec<Plane> {
+    let (width, height) = (frame.width, frame.height);
+    let (cw, ch) = (width.div_ceil(2), height.div_ceil(2));
+    let fixed = Fixed::new(to);
+    let src = &frame.planes[0];
+    let row = |y: usize| &src.data[y * src.stride..y * src.stride + width * 4];
+
+    let mut y_plane = vec![0; width * height];
+    let mut cb_plane = vec![0; cw * ch];
+    let mut cr_plane = vec![0; cw * ch];
+    // Luma of the row past the bottom of an odd height, thrown away
+    let mut spare = vec![0; width];
+
+    let chroma_rows = cb_plane.chunks_mut(cw).zip(cr_plane.chunks_mut(cw));
+
+    for (cy, (luma, (cb, cr))) in y_plane.chunks_mut(width * 2).zip(chroma_rows).enumerate() {
+        let top = cy * 2;
+        let (y0, y1) = match luma.len() > width {
+            true => luma.split_at_mut(width),
+            false => (luma, &mut spare[..]),
+        };
+
+        let mut rows = Rows {
+            bgra: [row(top), row((top + 1).min(height - 1))],
+            y: [y0, y1],
+            cb,
+            cr,
+        };
+
+        let done = match kernel {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Sse2 => unsafe { x86::sse2(&fixed, &mut rows, width) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Kernel::Avx2 => unsafe { x86::avx2(&fixed, &mut rows, width) },
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => unsafe { arm::neon(&fixed, &mut rows, width) },
+            _ => 0,
+        };
+
+        scalar(&fixed, &mut rows, width, done);
+    }
+
+    match to.format {
+        PixelFormat::I420 => vec![
+            Plane { data: y_plane, stride: width },
+            Plane { data: cb_plane, stride: cw },
+            Plane { data: cr_plane, stride: cw },
+        ],
+        _ => {
+            let uv = cb_plane.iter().zip(&cr_plane).flat_map(|(&cb, &cr)| [cb, cr]).collect();
+            vec![Plane { data: y_plane, stride: width }, Plane { data: uv, stride: cw * 2 }]
+        }
+    }
+}
+
+/// Finishes the rows from pixel `from`, which is even. An odd width repeats the
+/// last pixel for the last chroma sample.
+fn scalar(c: &Fixed, rows: &mut Rows, width: usize, from: usize) {
+    let px = |row: &[u8], x: usize| {
+        let i = x.min(width - 1) * 4;
+        [row[i] as i32, row[i + 1] as i32, row[i + 2] as i32]
+    };
+    let dot = |k: [i16; 3], p: [i32; 3]| k[0] as i32 * p[0] + k[1] as i32 * p[1] + k[2] as i32 * p[2];
+
+    for (bgra, luma) in rows.bgra.iter().zip(rows.y.iter_mut()) {
+        for x in from..width {
+            luma[x] = ((dot(c.y, px(bgra, x)) + c.y_offset) >> Y_SHIFT).clamp(0, 255) as u8;
+        }
+    }
+
+    for cx in from / 2..width.div_ceil(2) {
+        let mut sum = [0; 3];
+
+        for bgra in rows.bgra {
+            for x in [cx * 2, cx * 2 + 1] {
+                let p = px(bgra, x);
+                sum = [sum[0] + p[0], sum[1] + p[1], sum[2] + p[2]];
+            }
+        }
+
+        rows.cb[cx] = ((dot(c.cb, sum) + c.c_offset) >> C_SHIFT).clamp(0, 255) as u8;
+        rows.cr[cx] = ((dot(c.cr, sum) + c.c_offset) >> C_SHIFT).clamp(0, 255) as u8;
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::{Fixed, Rows, C_SHIFT, Y_SHIFT};
+
+    // For _mm_madd_epi16 against pixels unpacked to 16 bits, enough for AVX2
+    fn weights([b, g, r]: [i16; 3]) -> [i16; 16] {
+        [b, g, r, 0, b, g, r, 0, b, g, r, 0, b, g, r, 0]
+    }
+
+    /// 8 pixels at a time. Returns how many pixels it did.
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn sse2(c: &Fixed, rows: &mut Rows, width: usize) -> usize {
+        let zero = _mm_setzero_si128();
+        let [ky, kcb, kcr] = [c.y, c.cb, c.cr].map(|k| _mm_loadu_si128(weights(k).as_ptr() as *const __m128i));
+        let y_offset = _mm_set1_epi32(c.y_offset);
+        let c_offset = _mm_set1_epi32(c.c_offset);
+
+        let mut x = 0;
+        while x + 8 <= width {
+            // Both rows as 16-bit BGRA, two pixels per register
+            let mut px = [[zero; 4]; 2];
+
+            for (r, bgra) in rows.bgra.iter().enumerate() {
+                let a = _mm_loadu_si128(bgra.as_ptr().add(x * 4) as *const __m128i);
+                let b = _mm_loadu_si128(bgra.as_ptr().add(x * 4 + 16) as *const __m128i);
+                px[r] = [
+                    _mm_unpacklo_epi8(a, zero),
+                    _mm_unpackhi_epi8(a, zero),
+                    _mm_unpacklo_epi8(b, zero),
+                    _mm_unpackhi_epi8(b, zero),
+                ];
+            }
+
+            for (p, luma) in px.iter().zip(rows.y.iter_mut()) {
+                let lo = _mm_srai_epi32::<Y_SHIFT>(_mm_add_epi32(dot4(p[0], p[1], ky), y_offset));
+                let hi = _mm_srai_epi32::<Y_SHIFT>(_mm_add_epi32(dot4(p[2], p[3], ky), y_offset));
+                let y = _mm_packs_epi32(lo, hi);
+                _mm_storel_epi64(luma.as_mut_ptr().add(x) as *mut __m128i, _mm_packus_epi16(y, y));
+            }
+
+            // Add the rows, then each pixel to its right neighbour, leaving one 2x2
+            // sum in the low half of each register
+            let mut sums = [zero; 4];
+            for (i, sum) in sums.iter_mut().enumerate() {
+                let v = _mm_add_epi16(px[0][i], px[1][i]);
+                *sum = _mm_add_epi16(v, _mm_srli_si128::<8>(v));
+            }
+            let a = _mm_unpacklo_epi64(sums[0], sums[1]);
+            let b = _mm_unpacklo_epi64(sums[2], sums[3]);
+
+            for (k, out) in [(kcb, &mut *rows.cb), (kcr, &mut *rows.cr)] {
+                let v = _mm_srai_epi32::<C_SHIFT>(_mm_add_epi32(dot4(a, b, k), c_offset));
+                let v = _mm_packus_epi16(_mm_packs_epi32(v, v), zero);
+                out[x / 2..x / 2 + 4].copy_from_slice(&_mm_cvtsi128_si32(v).to_le_bytes());
+            }
+
+            x += 8;
+        }
+
+        x
+    }
+
+    // Weighted sums of the four pixels in `a` and `b`, in order
+    #[target_feature(enable = "sse2")]
+    fn dot4(a: __m128i, b: __m128i, k: __m128i) -> __m128i {
+        let a = _mm_castsi128_ps(_mm_madd_epi16(a, k));
+        let b = _mm_castsi128_ps(_mm_madd_epi16(b, k));
+        let even = _mm_castps_si128(_mm_shuffle_ps::<0b10_00_10_00>(a, b));
+        let odd = _mm_castps_si128(_mm_shuffle_ps::<0b11_01_11_01>(a, b));
+        _mm_add_epi32(even, odd)
+    }
+
+    /// 16 pixels at a time. Same steps as `sse2`, with the two 128-bit lanes
+    /// put back in order before storing.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn avx2(c: &Fixed, rows: &mut Rows, width: usize) -> usize {
+        let zero = _mm256_setzero_si256();
+        let [ky, kcb, kcr] = [c.y, c.cb, c.cr].map(|k| _mm256_loadu_si256(weights(k).as_ptr() as *const __m256i));
+        let y_offset = _mm256_set1_epi32(c.y_offset);
+        let c_offset = _mm256_set1_epi32(c.c_offset);
+        let chroma_order = _mm256_setr_epi32(0, 1, 4, 5, 2, 3, 6, 7);
+
+        let mut x = 0;
+        while x + 16 <= width {
+            // Lanes hold pixels [0 1 | 4 5], [2 3 | 6 7], [8 9 | 12 13], [10 11 | 14 15]
+            let mut px = [[zero; 4]; 2];
+
+            for (r, bgra) in rows.bgra.iter().enumerate() {
+                let a = _mm256_loadu_si256(bgra.as_ptr().add(x * 4) as *const __m256i);
+                let b = _mm256_loadu_si256(bgra.as_ptr().add(x * 4 + 32) as *const __m256i);
+                px[r] = [
+                    _mm256_unpacklo_epi8(a, zero),
+                    _mm256_unpackhi_epi8(a, zero),
+                    _mm256_unpacklo_epi8(b, zero),
+                    _mm256_unpackhi_epi8(b, zero),
+                ];
+            }
+
+            for (p, luma) in px.iter().zip(rows.y.iter_mut()) {
+                // [0..4 | 4..8] and [8..12 | 12..16]
+                let lo = _mm256_srai_epi32::<Y_SHIFT>(_mm256_add_epi32(dot8(p[0], p[1], ky), y_offset));
+                let hi = _mm256_srai_epi32::<Y_SHIFT>(_mm256_add_epi32(dot8(p[2], p[3], ky), y_offset));
+                let y = _mm256_permute4x64_epi64::<0b11_01_10_00>(_mm256_packs_epi32(lo, hi));
+                let y = _mm_packus_epi16(_mm256_castsi256_si128(y), _mm256_extracti128_si256::<1>(y));
+                _mm_storeu_si128(luma.as_mut_ptr().add(x) as *mut __m128i, y);
+            }
+
+            let mut sums = [zero; 4];
+            for (i, sum) in sums.iter_mut().enumerate() {
+                let v = _mm256_add_epi16(px[0][i], px[1][i]);
+                *sum = _mm256_add_epi16(v, _mm256_srli_si256::<8>(v));
+            }
+            // Chroma samples [0 1 | 2 3] and [4 5 | 6 7]
+            let a = _mm256_unpacklo_epi64(sums[0], sums[1]);
+            let b = _mm256_unpacklo_epi64(sums[2], sums[3]);
+
+            for (k, out) in [(kcb, &mut *rows.cb), (kcr, &mut *rows.cr)] {
+                let v = _mm256_srai_epi32::<C_SHIFT>(_mm256_add_epi32(dot8(a, b, k), c_offset));
+                let v = _mm256_permutevar8x32_epi32(v, chroma_order);
+                let v = _mm_packs_epi32(_mm256_castsi256_si128(v), _mm256_extracti128_si256::<1>(v));
+                _mm_storel_epi64(out.as_mut_ptr().add(x / 2) as *mut __m128i, _mm_packus_epi16(v, v));
+            }
+
+            x += 16;
+        }
+
+        x
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn dot8(a: __m256i, b: __m256i, k: __m256i) -> __m256i {
+        let a = _mm256_castsi256_ps(_mm256_madd_epi16(a, k));
+        let b = _mm256_castsi256_ps(_mm256_madd_epi16(b, k));
+        let even = _mm256_castps_si256(_mm256_shuffle_ps::<0b10_00_10_00>(a, b));
+        let odd = _mm256_castps_si256(_mm256_shuffle_ps::<0b11_01_11_01>(a, b));
+        _mm256_add_epi32(even, odd)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use std::arch::aarch64::*;
+
+    use super::{Fixed, Rows, C_SHIFT, Y_SHIFT};
+
+    /// 16 pixels at a time, deinterleaved into B, G, R and A registers on load.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn neon(c: &Fixed, rows: &mut Rows, width: usize) -> usize {
+        let mut x = 0;
+        while x + 16 <= width {
+            // B, G and R of each 2x2 block, 8 blocks
+            let mut sums = [vdupq_n_u16(0); 3];
+
+            for (bgra, luma) in rows.bgra.iter().zip(rows.y.iter_mut()) {
+                let px = vld4q_u8(bgra.as_ptr().add(x * 4));
+                let channels = [px.0, px.1, px.2];
+
+                let lo = luma8(c, [vget_low_u8(px.0), vget_low_u8(px.1), vget_low_u8(px.2)]);
+                let hi = luma8(c, [vget_high_u8(px.0), vget_high_u8(px.1), vget_high_u8(px.2)]);
+                vst1q_u8(luma.as_mut_ptr().add(x), vcombine_u8(lo, hi));
+
+                for (sum, v) in sums.iter_mut().zip(channels) {
+                    *sum = vpadalq_u8(*sum, v);
+                }
+            }
+
+            let [b, g, r] = [vreinterpretq_s16_u16(sums[0]), vreinterpretq_s16_u16(sums[1]), vreinterpretq_s16_u16(sums[2])];
+
+            for (k, out) in [(c.cb, &mut *rows.cb), (c.cr, &mut *rows.cr)] {
+                let lo = vshrq_n_s32::<C_SHIFT>(dot4(k, [vget_low_s16(b), vget_low_s16(g), vget_low_s16(r)], c.c_offset));
+                let hi = vshrq_n_s32::<C_SHIFT>(dot4(k, [vget_high_s16(b), vget_high_s16(g), vget_high_s16(r)], c.c_offset));
+                let v = vqmovun_s16(vcombine_s16(vqmovn_s32(lo), vqmovn_s32(hi)));
+                vst1_u8(out.as_mut_ptr().add(x / 2), v);
+            }
+
+            x += 16;
+        }
+
+        x
+    }
+
+    #[target_feature(enable = "neon")]
+    unsafe fn luma8(c: &Fixed, [b, g, r]: [uint8x8_t; 3]) -> uint8x8_t {
+        let [b, g, r] = [vmovl_u8(b), vmovl_u8(g), vmovl_u8(r)].map(|v| vreinterpretq_s16_u16(v));
+        let lo = vshrq_n_s32::<Y_SHIFT>(dot4(c.y, [vget_low_s16(b), vget_low_s16(g), vget_low_s16(r)], c.y_offset));
+        let hi = vshrq_n_s32::<Y_SHIFT>(dot4(c.y, [vget_high_s16(b), vget_high_s16(g), vget_high_s16(r)], c.y_offset));
+        vqmovun_s16(vcombine_s16(vqmovn_s32(lo), vqmovn_s32(hi)))
+    }
+
+    #[target_feature(enable = "neon")]
+    unsafe fn dot4(k: [i16; 3], bgr: [int16x4_t; 3], offset: i32) -> int32x4_t {
+        let acc = vmlal_n_s16(vdupq_n_s32(offset), bgr[0], k[0]);
+        let acc = vmlal_n_s16(acc, bgr[1], k[1]);
+        vmlal_n_s16(acc, bgr[2], k[2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Gradients with noise on top, B, G, R, A. `pad` extra bytes end each row.
+    fn synthetic(width: usize, height: usize, pad: usize, rgba: bool) -> Frame {
+        let stride = width * 4 + pad;
+        let mut data = vec![0xee; stride * height];
+        let mut seed = 0x2545_f491_u32;
+
+        for (y, row) in data.chunks_exact_mut(stride).enumerate() {
+            for (x, px) in row[..width * 4].chunks_exact_mut(4).enumerate() {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let noise = (seed & 63) as usize;
+
+                px[0] = ((x * 255 / width + noise) % 256) as u8;
+                px[1] = ((y * 255 / height + noise) % 256) as u8;
+                px[2] = ((x + y) * 255 / (width + height)) as u8;
+                px[3] = 255;
+                if rgba {
+                    px.swap(0, 2);
+                }
+            }
+        }
+
+        let format = if rgba { PixelFormat::Rgba } else { PixelFormat::Bgra };
+        Frame::new(width, height, format, vec![Plane { data, stride }], Instant::now())
+    }
+
+    #[test]
+    fn kernels_match_scalar_exactly() {
+        use crate::convert::convert;
+        use crate::frame::Matrix;
+
+        let targets = [
+            Conversion { format: PixelFormat::Nv12, matrix: Matrix::Bt709, range: ColorRange::Limited },
+            Conversion { format: PixelFormat::I420, matrix: Matrix::Bt601, range: ColorRange::Full },
+        ];
+
+        // Odd sizes and padded rows exercise the scalar tails after the SIMD loops
+        for (width, height, pad) in [(64, 32, 0), (67, 33, 12), (1, 1, 0), (31, 7, 4), (130, 3, 8)] {
+            let bgra = synthetic(width, height, pad, false);
+
+            for to in targets {
+                let reference = bgra_to_420(&bgra, to, Kernel::Scalar);
+
+                for kernel in Kernel::available() {
+                    let planes = bgra_to_420(&bgra, to, kernel);
+                    for (a, b) in planes.iter().zip(&reference) {
+                        assert!(a.data == b.data, "{:?} differs from scalar at {}x{}", kernel, width, height);
+                    }
+                }
+
+                // RGBA doesn't have a fast path, so this is the floating point one
+                let float = convert(synthetic(width, height, pad, true), to).unwrap();
+                for (a, b) in float.planes.iter().zip(&reference) {
+                    let worst = a.data.iter().zip(&b.data).map(|(a, b)| a.abs_diff(*b)).max();
+                    assert!(worst <= Some(1), "scalar is off by {:?} at {}x{}", worst, width, height);
+                }
+            }
+        }
+    }
+}
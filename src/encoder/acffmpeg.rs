@@ -1,172 +1,549 @@
 use std::fs::File;
 use std::time::Instant;
-use super::Encoder;
-use anyhow::Error;
+use super::{Encoder, EncoderError};
 
 use ac_ffmpeg::format::muxer::{Muxer, OutputFormat};
 use ac_ffmpeg::codec::Encoder as ACEncoder;
-use ac_ffmpeg::codec::video::frame::get_pixel_format;
+use ac_ffmpeg::codec::audio::{AudioEncoder, AudioFrameMut, ChannelLayout as AvChannelLayout};
+use ac_ffmpeg::codec::audio::frame::get_sample_format;
+use ac_ffmpeg::codec::video::frame::{get_pixel_format, PixelFormat as AvPixelFormat};
 use ac_ffmpeg::codec::video::{VideoEncoder, VideoFrame, VideoFrameMut, VideoFrameScaler};
 use ac_ffmpeg::time::{TimeBase, Timestamp};
 use ac_ffmpeg::format::io::IO;
 
-use crabgrab::feature::bitmap::{FrameBitmapBgraUnorm8x4, FrameBitmapYCbCr};
-use crabgrab::prelude::VideoFrameBitmap;
-use crabgrab::prelude::FrameBitmap::{BgraUnorm8x4, YCbCr};
+use super::settings::{AudioCodec, AudioSettings, Codec, EncoderSettings, FrameTiming, Profile, RateControl};
+use super::timing::FrameGrid;
+use crate::audio::{AudioBuffer, Timeline};
+use crate::convert::{self, Conversion};
+use crate::frame::{ColorRange, ColorSpace, Frame, Matrix, PixelFormat};
 
 
+/// Codec name plus the pixel format and private options to open it with.
+/// Any encoder FFmpeg was built with works, the presets below are the ones we've tuned.
+#[derive(Clone, Debug)]
+pub struct FfmpegCodec {
+    pub name: String,
+    pub pixel_format: String,
+    pub options: Vec<(String, String)>,
+}
+
+impl FfmpegCodec {
+    pub fn new(name: &str, pixel_format: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            pixel_format: pixel_format.to_string(),
+            options: Vec::new(),
+        }
+    }
+
+    pub fn with_option(mut self, key: &str, value: &str) -> Self {
+        self.options.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    // Rate control and colour tags come from `EncoderSettings`, see `apply_settings`
+    pub fn libx264() -> Self {
+        Self::new("libx264", "yuv420p")
+            // Basic quality settings
+            .with_option("preset", "fast")  // Balance between speed and quality
+            .with_option("tune", "zerolatency")  // Better for screen recording
+    }
+
+    pub fn libx265() -> Self {
+        Self::new("libx265", "yuv420p")
+            .with_option("preset", "fast")
+            .with_option("tune", "zerolatency")
+    }
+
+    pub fn libsvtav1() -> Self {
+        Self::new("libsvtav1", "yuv420p")
+            .with_option("preset", "10")
+    }
+
+    pub fn libvpx_vp9() -> Self {
+        Self::new("libvpx-vp9", "yuv420p")
+            .with_option("deadline", "realtime")
+            .with_option("cpu-used", "8")
+            .with_option("row-mt", "1")
+    }
+
+    /// Lossless, takes BGRA as-is so there's no colour conversion at all
+    pub fn ffv1() -> Self {
+        Self::new("ffv1", "bgra")
+            .with_option("level", "3")
+            .with_option("slicecrc", "1")
+    }
+
+    pub fn for_codec(codec: Codec) -> Self {
+        match codec {
+            Codec::H264 => Self::libx264(),
+            Codec::Hevc => Self::libx265(),
+            Codec::Av1 => Self::libsvtav1(),
+            Codec::Vp9 => Self::libvpx_vp9(),
+            Codec::Ffv1 => Self::ffv1(),
+        }
+    }
+
+    /// Adds the private options for rate control, GOP, profile and colour tags.
+    /// Returns the settings that couldn't be expressed for this codec.
+    pub fn apply_settings(mut self, settings: &EncoderSettings) -> (Self, Vec<&'static str>) {
+        let mut ignored = Vec::new();
+        let codec = settings.codec;
+
+        match (settings.rate_control, codec) {
+            (_, Codec::Ffv1) => ignored.push("rate_control"),
+            // bit_rate itself is set on the builder
+            (RateControl::Vbr, _) => {}
+            (RateControl::Cbr, _) => {
+                self = self
+                    .with_option("minrate", &settings.bitrate.to_string())
+                    .with_option("maxrate", &settings.bitrate.to_string())
+                    .with_option("bufsize", &(settings.bitrate as u64 * 2).to_string());
+            }
+            (RateControl::Crf(crf), Codec::Vp9 | Codec::Av1) => self = self.with_option("crf", &crf.min(63).to_string()),
+            (RateControl::Crf(crf), _) => self = self.with_option("crf", &crf.to_string()),
+            // Lossless stays RGB like ffv1, going through YUV would lose some of what was captured
+            (RateControl::Lossless, Codec::H264) => {
+                self.name = "libx264rgb".to_string();
+                self.pixel_format = "bgr0".to_string();
+                self = self.with_option("qp", "0");
+            }
+            (RateControl::Lossless, Codec::Hevc) => {
+                self.pixel_format = "gbrp".to_string();
+                self = self.with_option("x265-params", "lossless=1");
+            }
+            (RateControl::Lossless, Codec::Vp9) => {
+                self.pixel_format = "gbrp".to_string();
+                self = self.with_option("lossless", "1");
+            }
+            (RateControl::Lossless, Codec::Av1) => ignored.push("rate_control"),
+        }
+
+        if let Some(interval) = settings.keyframe_interval {
+            self = self.with_option("g", &interval.to_string());
+        }
+
+        match (settings.profile, codec) {
+            (None, _) => {}
+            (Some(_), Codec::H264) if settings.rate_control == RateControl::Lossless => ignored.push("profile"),
+            (Some(Profile::Baseline), Codec::H264) => self = self.with_option("profile", "baseline"),
+            (Some(Profile::Main), Codec::H264 | Codec::Hevc) => self = self.with_option("profile", "main"),
+            (Some(Profile::High), Codec::H264) => self = self.with_option("profile", "high"),
+            _ => ignored.push("profile"),
+        }
+
+        // RGB output has no YUV matrix or range to tag
+        if matches!(self.pixel_format.as_str(), "bgra" | "bgr0" | "gbrp") {
+            ignored.push("color_space");
+            ignored.push("color_range");
+        } else {
+            let trc = match settings.color_space {
+                ColorSpace::Srgb => "iec61966-2-1",
+                ColorSpace::Bt709 => "bt709",
+            };
+            let range = match settings.color_range {
+                ColorRange::Limited => "tv",
+                ColorRange::Full => "pc",
+            };
+
+            self = self
+                .with_option("colorspace", "bt709")
+                .with_option("color_primaries", "bt709")
+                .with_option("color_trc", trc)
+                .with_option("color_range", range);
+        }
+
+        (self, ignored)
+    }
+}
+
 pub struct EncoderAcFfmpeg {
     muxer: Muxer<File>,
     first_ts: Option<Instant>,
+    // `None` with `FrameTiming::Vfr`, where timestamps are capture times in microseconds
+    grid: Option<FrameGrid>,
+    time_base: TimeBase,
     encoder: VideoEncoder,
+    // What frames are converted to before swscale sees them
+    input: Conversion,
+    // Built for the first frame swscale has to touch, and again only if the source changes
+    scaler: Option<((AvPixelFormat, usize, usize), VideoFrameScaler)>,
+    audio: Option<AudioTrack>,
+    ignored: Vec<&'static str>,
+}
+
+// Second stream in the muxer. Encoders take a fixed number of samples per frame,
+// so PCM is collected in `pending` until there's a whole one
+struct AudioTrack {
+    settings: AudioSettings,
+    encoder: AudioEncoder,
+    timeline: Timeline,
+    // Interleaved, starting `pts` samples into the track
+    pending: Vec<f32>,
+    pts: i64,
+    frame_size: usize,
+    planar: bool,
+}
+
+impl AudioTrack {
+    fn open(settings: AudioSettings, origin: Instant) -> Result<Self, EncoderError> {
+        // The native AAC encoder only takes planar float, libopus only interleaved
+        let (name, sample_format, planar) = match settings.codec {
+            AudioCodec::Aac => ("aac", "fltp", true),
+            AudioCodec::Opus => ("libopus", "flt", false),
+        };
+
+        // libopus only runs at a few rates and nothing here resamples, so say so
+        // rather than have the encoder fail to open with EINVAL
+        if settings.codec == AudioCodec::Opus && !matches!(settings.sample_rate, 8_000 | 12_000 | 16_000 | 24_000 | 48_000) {
+            return Err(EncoderError::UnsupportedSampleRate {
+                backend: "libopus",
+                sample_rate: settings.sample_rate,
+                supported: "8000, 12000, 16000, 24000 or 48000 Hz",
+            });
+        }
+
+        let layout = AvChannelLayout::from_channels(settings.layout.channels() as u32)
+            .ok_or(EncoderError::backend("libavcodec", "no channel layout for this many channels"))?;
+
+        let encoder = AudioEncoder::builder(name)?
+            .sample_rate(settings.sample_rate)
+            .channel_layout(layout)
+            .sample_format(get_sample_format(sample_format))
+            .bit_rate(settings.bitrate as u64)
+            .time_base(TimeBase::new(1, settings.sample_rate as i32))
+            .build()?;
+
+        // Encoders that take any frame size say nothing, 20ms is as good as any
+        let frame_size = encoder.samples_per_frame().unwrap_or(settings.sample_rate as usize / 50);
+
+        Ok(Self {
+            settings,
+            encoder,
+            timeline: Timeline::new(origin, settings.sample_rate, settings.layout),
+            pending: Vec::new(),
+            pts: 0,
+            frame_size,
+            planar,
+        })
+    }
+
+    // Encodes whole frames out of `pending`, and with `flush` what's left too, padded to a whole frame
+    fn encode(&mut self, muxer: &mut Muxer<File>, flush: bool) -> Result<(), EncoderError> {
+        let channels = self.settings.layout.channels();
+        let frame_len = self.frame_size * channels;
+
+        while self.pending.len() >= frame_len || (flush && !self.pending.is_empty()) {
+            let take = frame_len.min(self.pending.len());
+            let samples: Vec<f32> = self.pending.drain(..take).collect();
+
+            let codec = self.encoder.codec_parameters();
+            let mut frame = AudioFrameMut::silence(
+                codec.channel_layout(),
+                codec.sample_format(),
+                self.settings.sample_rate,
+                self.frame_size,
+            );
+
+            let mut planes = frame.planes_mut();
+            if self.planar {
+                for (channel, plane) in planes.iter_mut().enumerate().take(channels) {
+                    let data = plane.data_mut();
+                    for (i, sample) in samples.iter().skip(channel).step_by(channels).enumerate() {
+                        data[i * 4..i * 4 + 4].copy_from_slice(&sample.to_le_bytes());
+                    }
+                }
+            } else {
+                let data = planes[0].data_mut();
+                for (i, sample) in samples.iter().enumerate() {
+                    data[i * 4..i * 4 + 4].copy_from_slice(&sample.to_le_bytes());
+                }
+            }
+
+            let time_base = TimeBase::new(1, self.settings.sample_rate as i32);
+            self.encoder.push(frame.freeze().with_pts(Timestamp::new(self.pts, time_base)))?;
+            self.pts += self.frame_size as i64;
+
+            while let Some(p) = self.encoder.take()? {
+                muxer.push(p.with_stream_index(1))?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl EncoderAcFfmpeg {
-    pub fn init(height: f64, width: f64, path: &std::path::Path) -> Result<Self, Error> {
-        let pf = get_pixel_format("yuv420p");
-        let codec = "libx264";
-        let time_base = TimeBase::MICROSECONDS;
-
-        let encoder_builder = VideoEncoder::builder(codec)?
-            // Ensure proper bitrate (in bits per second)
-            .bit_rate(2_000_000)  // 2Mbps
+    pub fn init(height: f64, width: f64, path: &std::path::Path, settings: &EncoderSettings) -> Result<Self, EncoderError> {
+        let codec = match &settings.ffmpeg_encoder {
+            Some(encoder) => FfmpegCodec::new(&encoder.name, &encoder.pixel_format),
+            None => FfmpegCodec::for_codec(settings.codec),
+        };
+
+        Self::init_with_codec(height, width, path, settings, codec)
+    }
+
+    /// Like `init`, but with any encoder FFmpeg knows about. `settings.codec` only
+    /// decides how the rate control and profile options are spelled.
+    pub fn init_with_codec(
+        height: f64,
+        width: f64,
+        path: &std::path::Path,
+        settings: &EncoderSettings,
+        codec: FfmpegCodec,
+    ) -> Result<Self, EncoderError> {
+        let (codec, mut ignored) = codec.apply_settings(settings);
+
+        // With constant frame rate the time base is one frame, otherwise timestamps
+        // are microseconds straight from capture and there's no nominal rate to set,
+        // which is reported under `timing` like every other backend does
+        let time_base = match settings.timing {
+            FrameTiming::Cfr => TimeBase::new(1, settings.fps as i32),
+            FrameTiming::Vfr => {
+                ignored.push("timing");
+                TimeBase::MICROSECONDS
+            }
+        };
+
+        tracing::debug!(codec = %codec.name, pixel_format = %codec.pixel_format, options = ?codec.options, "opening libavcodec encoder");
+
+        if codec.pixel_format == "yuv420p" && (!(width as usize).is_multiple_of(2) || !(height as usize).is_multiple_of(2)) {
+            return Err(EncoderError::InvalidDimensions {
+                width: width as usize,
+                height: height as usize,
+                reason: "4:2:0 output needs even dimensions",
+            });
+        }
+
+        // Convert straight to the codec's format when we can, so swscale only has
+        // to deal with the ones we can't
+        let input = match pixel_format_from_name(&codec.pixel_format) {
+            Some(format) if format.is_rgb() => Conversion { format, matrix: Matrix::Bt709, range: ColorRange::Full },
+            Some(format) => Conversion { format, matrix: Matrix::Bt709, range: settings.color_range },
+            None => Conversion { format: PixelFormat::Bgra, matrix: Matrix::Bt709, range: ColorRange::Full },
+        };
+
+        let pf = get_pixel_format(&codec.pixel_format);
+
+        // Only Cbr and Vbr aim for a bitrate. Next to crf it would turn libvpx's
+        // constant quality into constrained quality at that rate
+        let bit_rate = match settings.rate_control {
+            RateControl::Cbr | RateControl::Vbr => settings.bitrate as u64,
+            RateControl::Crf(_) | RateControl::Lossless => 0,
+        };
+
+        let mut encoder_builder = VideoEncoder::builder(&codec.name)?
+            .bit_rate(bit_rate)
             .pixel_format(pf)
             .time_base(time_base)
             .width(width as usize)
-            .height(height as usize)
-            .set_option("fps", "60")
-            // Basic quality settings
-            .set_option("preset", "fast")  // Balance between speed and quality
-            .set_option("tune", "zerolatency")  // Better for screen recording
-            .set_option("crf", "13")  // Default CRF value, lower means better quality
-            .set_option("color_range", "jpeg")
-            // .set_option("colormatrix", "bt709")
-            // .set_option("colorprim", "bt709")
-            // .set_option("transfer", "bt709")
-            ;
+            .height(height as usize);
+
+        for (key, value) in &codec.options {
+            encoder_builder = encoder_builder.set_option(key, value);
+        }
 
         let encoder = encoder_builder.build()?;
         let cp = encoder.codec_parameters();
 
-        let file = File::create_new(path)?;
+        let file = super::create_output(path, settings)?;
         let io = IO::from_seekable_write_stream(file);
 
-        let output_format = OutputFormat::guess_from_file_name(path.file_name().unwrap().to_str().unwrap())
-            .ok_or(Error::msg("Failed to guess output format"))?;
+        let output_format = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(OutputFormat::guess_from_file_name)
+            .ok_or(EncoderError::backend("libavformat", format!("can't guess a format for {}", path.display())))?;
+
+        // With an audio track both tracks start now rather than at their first
+        // sample, so they share a time zero
+        let origin = settings.start.or(settings.audio.map(|_| Instant::now()));
+        let audio = match (settings.audio, origin) {
+            (Some(audio), Some(origin)) => Some(AudioTrack::open(audio, origin)?),
+            _ => None,
+        };
 
         let mut muxer_builder = Muxer::builder();
         muxer_builder.add_stream(&cp.into())?;
+        if let Some(audio) = &audio {
+            muxer_builder.add_stream(&audio.encoder.codec_parameters().into())?;
+        }
+
+        // ac-ffmpeg doesn't expose AVChapter, so there's no way to hand them to the muxer
+        if settings.chapters {
+            ignored.push("chapters");
+        }
+
+        // Only the mov/mp4 muxer knows these, any other one would quietly leave them unused
+        let is_mp4 = matches!(path.extension().and_then(|ext| ext.to_str()), Some("mp4" | "mov" | "m4v"));
+        match settings.fragment_duration {
+            Some(duration) if is_mp4 => {
+                muxer_builder = muxer_builder
+                    .set_option("movflags", "+empty_moov+default_base_moof")
+                    .set_option("frag_duration", duration.as_micros());
+            }
+            Some(_) => ignored.push("fragment_duration"),
+            None => {}
+        }
 
         let muxer = muxer_builder.build(io, output_format)?;
 
 
-        Ok(EncoderAcFfmpeg { first_ts: None, encoder, muxer })
+        let grid = match settings.timing {
+            FrameTiming::Cfr => Some(FrameGrid::new(settings.fps, origin)),
+            FrameTiming::Vfr => None,
+        };
+
+        Ok(EncoderAcFfmpeg { first_ts: origin, grid, time_base, encoder, muxer, input, scaler: None, audio, ignored })
     }
 }
 
 impl Encoder for EncoderAcFfmpeg {
-    fn append_frame(&mut self, frame: crabgrab::prelude::VideoFrame) -> Result<(), Error> {
-        // Update first_ts if it no exist
-        let ts = frame.capture_time();
-        if self.first_ts.is_none() {
-            self.first_ts = Some(ts)
+    fn append_frame(&mut self, frame: Frame) -> Result<(), EncoderError> {
+        let (ts, time_base) = (frame.timestamp, self.time_base);
+
+        // On the grid the frame goes in once per slot, and the slot is its pts
+        let pts: Vec<Timestamp> = match &mut self.grid {
+            Some(grid) => grid.slots(ts).map(|slot| Timestamp::new(slot as i64, time_base)).collect(),
+            None => {
+                let first_ts = *self.first_ts.get_or_insert(ts);
+                vec![Timestamp::from_micros(ts.duration_since(first_ts).as_micros() as i64)]
+            }
+        };
+        if pts.is_empty() {
+            return Ok(());
         }
 
-        // Create ts
-        let pts_raw = ts.duration_since(self.first_ts.unwrap()).as_micros();
-        let pts = Timestamp::from_micros(pts_raw as i64);
-
-        let frame = create_acff_videoframe_from_crabgrab_frame(frame)?;
+        let frame = convert::convert(frame, self.input)?;
+        let frame = create_acff_videoframe(&frame)?;
 
         let cp = self.encoder.codec_parameters();
         let target_pf = cp.pixel_format();
         let target_height = cp.height();
         let target_width = cp.width();
 
-        // Resize/convert frame to compatible one
-        let scaled_frame = VideoFrameScaler::builder()
-            .source_height(frame.height())
-            .source_width(frame.width())
-            .source_pixel_format(frame.pixel_format())
-            .target_height(target_height)
-            .target_width(target_width)
-            .target_pixel_format(target_pf)
-            .build()?
-            .scale(&frame)?;
-
-        self.encoder.push(scaled_frame.with_pts(pts))?;
-
-        while let Ok(Some(p)) = self.encoder.take() {
-            self.muxer.push(p)?;
+        // Resize/convert frame to compatible one, if our conversion didn't already
+        let scaled_frame = if frame.pixel_format() == target_pf && frame.width() == target_width && frame.height() == target_height {
+            frame
+        } else {
+            let source = (frame.pixel_format(), frame.width(), frame.height());
+
+            let scaler = match &mut self.scaler {
+                Some((cached, scaler)) if *cached == source => scaler,
+                cache => {
+                    let scaler = VideoFrameScaler::builder()
+                        .source_height(frame.height())
+                        .source_width(frame.width())
+                        .source_pixel_format(frame.pixel_format())
+                        .target_height(target_height)
+                        .target_width(target_width)
+                        .target_pixel_format(target_pf)
+                        .build()?;
+
+                    &mut cache.insert((source, scaler)).1
+                }
+            };
+
+            scaler.scale(&frame)?
+        };
+
+        for pts in pts {
+            self.encoder.push(scaled_frame.clone().with_pts(pts))?;
+
+            while let Ok(Some(p)) = self.encoder.take() {
+                self.muxer.push(p)?;
+            }
         }
 
         Ok(())
     }
 
-    fn finish(&mut self) -> Result<(), Error> {
-        self.encoder.flush()?;
-        self.muxer.flush()?;
+    fn append_audio(&mut self, audio: AudioBuffer) -> Result<(), EncoderError> {
+        let track = self.audio.as_mut().ok_or(EncoderError::NoAudioTrack)?;
 
-        Ok(())
+        let expected = (track.settings.sample_rate, track.settings.layout.channels());
+        let got = (audio.sample_rate, audio.layout.channels());
+        if got != expected {
+            return Err(EncoderError::AudioFormatMismatch { expected, got });
+        }
+
+        // The track only counts samples, so gaps and overlaps are evened out here
+        let samples = track.timeline.place(&audio);
+        track.pending.extend_from_slice(&samples);
+        track.encode(&mut self.muxer, false)
     }
-}
 
+    fn ignored_settings(&self) -> &[&'static str] {
+        &self.ignored
+    }
 
-fn create_acff_videoframe_from_crabgrab_frame(source: crabgrab::prelude::VideoFrame) -> Result<VideoFrame, Error> {
-    let width = source.size().width as usize;
-    let height = source.size().height as usize;
+    fn dropped_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::dropped)
+    }
 
-    let bitmap = source.get_bitmap()?;
-    let pf = match bitmap {
-        BgraUnorm8x4(_) => get_pixel_format("bgra"),
-        YCbCr(_) => get_pixel_format("nv12"),
-        _ => todo!("what format you give bro?")
-    };
+    fn duplicated_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::duplicated)
+    }
 
-    let mut black_frame = VideoFrameMut::black(pf, width, height);
+    fn finish(&mut self) -> Result<(), EncoderError> {
+        self.encoder.flush()?;
 
-    match bitmap {
-        BgraUnorm8x4(FrameBitmapBgraUnorm8x4 { data, width, .. }) => {
-            let bytes = data.as_flattened();
-            let stride = black_frame.planes()[0].line_size();
+        // Flushing the encoder releases the frames it was still holding on to
+        while let Ok(Some(p)) = self.encoder.take() {
+            self.muxer.push(p)?;
+        }
 
-            for (out_line, in_line) in black_frame.planes_mut()[0]
-                .data_mut()
-                .chunks_mut(stride)
-                .zip(bytes.chunks(width * 4))
-            {
-                out_line[..width * 4].copy_from_slice(in_line);
+        if let Some(track) = &mut self.audio {
+            track.encode(&mut self.muxer, true)?;
+            track.encoder.flush()?;
+
+            while let Some(p) = track.encoder.take()? {
+                self.muxer.push(p.with_stream_index(1))?;
             }
         }
-        YCbCr(FrameBitmapYCbCr { luma_data, chroma_data, .. }) => {
-            let y_bytes = luma_data.as_ref();
-            let uv_bytes = chroma_data.as_flattened();
 
-            let y_stride = black_frame.planes()[0].line_size();
+        self.muxer.flush()?;
 
-            for (out_line, in_line) in black_frame.planes_mut()[0]
-                .data_mut()
-                .chunks_mut(y_stride)
-                .zip(y_bytes.chunks(width))
-            {
-                out_line[..width].copy_from_slice(in_line);
-            }
+        Ok(())
+    }
+}
 
-            let uv_stride = black_frame.planes()[1].line_size();
 
-            for (out_line, in_line) in black_frame.planes_mut()[1]
-                .data_mut()
-                .chunks_mut(uv_stride)
-                .zip(uv_bytes.chunks(width))
-            {
-                out_line[..width].copy_from_slice(in_line);
-            }
-        }
-        _ => unimplemented!("what format you give bro?")
+fn create_acff_videoframe(source: &Frame) -> Result<VideoFrame, EncoderError> {
+    let pf = match source.format {
+        PixelFormat::Bgra => get_pixel_format("bgra"),
+        PixelFormat::Rgba => get_pixel_format("rgba"),
+        PixelFormat::Nv12 => get_pixel_format("nv12"),
+        PixelFormat::I420 => get_pixel_format("yuv420p"),
+        PixelFormat::I444 => get_pixel_format("yuv444p"),
+        PixelFormat::P010 => get_pixel_format("p010le"),
+    };
+
+    let mut black_frame = VideoFrameMut::black(pf, source.width, source.height);
+
+    for i in 0..source.planes.len() {
+        let stride = black_frame.planes()[i].line_size();
+        convert::copy_plane(source, i, black_frame.planes_mut()[i].data_mut(), stride)?;
     }
 
+    Ok(black_frame.freeze())
+}
 
-    let frame = black_frame.freeze();
+/// Our name for an FFmpeg pixel format, if we can convert to it ourselves.
+fn pixel_format_from_name(name: &str) -> Option<PixelFormat> {
+    match name {
+        "bgra" => Some(PixelFormat::Bgra),
+        "rgba" => Some(PixelFormat::Rgba),
+        "nv12" => Some(PixelFormat::Nv12),
+        "yuv420p" => Some(PixelFormat::I420),
+        "yuv444p" => Some(PixelFormat::I444),
+        "p010le" => Some(PixelFormat::P010),
+        _ => None,
+    }
+}
 
-    Ok(frame)
-}
\ No newline at end of file
+impl From<ac_ffmpeg::Error> for EncoderError {
+    fn from(e: ac_ffmpeg::Error) -> Self {
+        EncoderError::backend("libavcodec", e)
+    }
+}
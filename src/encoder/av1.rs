@@ -0,0 +1,471 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rav1e::prelude::{
+    ColorDescription, ColorPrimaries, Config, Context, EncoderConfig, EncoderStatus, FrameType,
+    MatrixCoefficients, Packet, PixelRange, Rational, TransferCharacteristics,
+};
+
+use super::{Encoder, EncoderError};
+use super::settings::{Chapter, Codec, EncoderSettings, FrameTiming, RateControl};
+use super::timing::FrameGrid;
+use super::mp4::{self, Mp4Writer};
+use crate::convert::{self, Conversion};
+use crate::frame::{ColorRange, ColorSpace, Frame, Matrix, PixelFormat};
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+enum Output {
+    Ivf { file: BufWriter<File>, frames: u32 },
+    // Created once the first keyframe hands us the sequence header for av1C
+    Mp4 { file: Option<File>, writer: Option<Mp4Writer>, fragment_duration: Option<Duration> },
+}
+
+/// Software AV1 encoder on top of rav1e, no FFmpeg or OS media framework needed.
+/// Writes IVF for `.ivf` outputs and MP4 for `.mp4`.
+pub struct Rav1eEncoder {
+    ctx: Context<u8>,
+    output: Output,
+    width: usize,
+    height: usize,
+    fps: u32,
+    color: ColorDescription,
+    first_ts: Option<Instant>,
+    // `None` with `FrameTiming::Vfr`, frames keep their capture time
+    grid: Option<FrameGrid>,
+    // Capture times of frames sent to rav1e that haven't come back as packets yet
+    pending_ts: VecDeque<(u64, Instant)>,
+    frames_sent: u64,
+    // Handed to the MP4 writer on finish, it only exists once the first packet is out
+    chapters: Vec<Chapter>,
+    ignored: Vec<&'static str>,
+}
+
+impl Rav1eEncoder {
+    pub fn init(height: f64, width: f64, output: &Path, settings: &EncoderSettings) -> Result<Self, EncoderError> {
+        let options = settings.av1;
+        let width = width as usize;
+        let height = height as usize;
+        let mut ignored = Vec::new();
+
+        if settings.codec != Codec::Av1 {
+            ignored.push("codec");
+        }
+
+        let mut enc = EncoderConfig::with_speed_preset(options.speed);
+        enc.width = width;
+        enc.height = height;
+        enc.time_base = Rational::new(1, settings.fps as u64);
+        enc.tiles = options.tiles;
+        // No B-frame style reordering, so packets come back as soon as possible
+        enc.low_latency = true;
+
+        match settings.rate_control {
+            RateControl::Vbr => enc.bitrate = settings.bitrate as i32,
+            // rav1e only does average bitrate
+            RateControl::Cbr => {
+                enc.bitrate = settings.bitrate as i32;
+                ignored.push("rate_control");
+            }
+            // Stretch the 0-51 CRF scale onto rav1e's 0-255 quantizer
+            RateControl::Crf(crf) => enc.quantizer = (crf.min(51) as usize * 255).div_ceil(51),
+            RateControl::Lossless => enc.quantizer = 0,
+        }
+
+        // MP4 fragments can only start on a keyframe, so without an explicit interval
+        // place one at least every fragment to keep them near the requested length
+        let fragment_interval = settings
+            .fragment_duration
+            .map(|d| (d.as_secs_f64() * settings.fps as f64).ceil().max(1.0) as u32);
+
+        if let Some(interval) = settings.keyframe_interval.or(fragment_interval) {
+            let interval = interval as u64;
+            enc.set_key_frame_interval(enc.min_key_frame_interval.min(interval), interval);
+        }
+
+        if settings.profile.is_some() {
+            ignored.push("profile");
+        }
+
+        // Frames are always converted to limited range before they reach rav1e
+        if settings.color_range != ColorRange::Limited {
+            ignored.push("color_range");
+        }
+
+        let color = ColorDescription {
+            color_primaries: ColorPrimaries::BT709,
+            transfer_characteristics: match settings.color_space {
+                ColorSpace::Srgb => TransferCharacteristics::SRGB,
+                ColorSpace::Bt709 => TransferCharacteristics::BT709,
+            },
+            matrix_coefficients: MatrixCoefficients::BT709,
+        };
+        enc.pixel_range = PixelRange::Limited;
+        enc.color_description = Some(color);
+
+        tracing::debug!(speed = options.speed, tiles = options.tiles, bitrate = enc.bitrate, quantizer = enc.quantizer, "configuring rav1e");
+
+        let ctx: Context<u8> = Config::new()
+            .with_encoder_config(enc)
+            .with_threads(0)
+            .new_context()
+            .map_err(|e| EncoderError::backend("rav1e", format!("invalid config: {}", e)))?;
+
+        let extension = output.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        if !matches!(extension, "ivf" | "mp4") {
+            return Err(EncoderError::UnsupportedContainer { backend: "rav1e", extension: extension.to_string() });
+        }
+
+        let file = super::create_output(output, settings)?;
+
+        let output = match extension {
+            "ivf" => {
+                let mut file = BufWriter::new(file);
+                write_ivf_header(&mut file, width, height, settings.fps, 0)?;
+
+                if settings.fragment_duration.is_some() {
+                    ignored.push("fragment_duration");
+                }
+
+                Output::Ivf { file, frames: 0 }
+            }
+            _ => Output::Mp4 { file: Some(file), writer: None, fragment_duration: settings.fragment_duration },
+        };
+
+        // Chapters go in the moov, which a fragmented MP4 writes before the first frame
+        let has_chapters = matches!(output, Output::Mp4 { fragment_duration: None, .. });
+        if settings.chapters && !has_chapters {
+            ignored.push("chapters");
+        }
+
+        // IVF and our MP4 writer both hold a single track, and there's no audio encoder to fill another
+        if settings.audio.is_some() {
+            ignored.push("audio");
+        }
+
+        Ok(Self {
+            ctx,
+            output,
+            width,
+            height,
+            fps: settings.fps,
+            color,
+            first_ts: settings.start,
+            grid: match settings.timing {
+                FrameTiming::Cfr => Some(FrameGrid::new(settings.fps, settings.start)),
+                FrameTiming::Vfr => None,
+            },
+            pending_ts: VecDeque::new(),
+            frames_sent: 0,
+            chapters: Vec::new(),
+            ignored,
+        })
+    }
+
+    fn drain_packets(&mut self) -> Result<(), EncoderError> {
+        loop {
+            match self.ctx.receive_packet() {
+                Ok(packet) => self.write_packet(packet)?,
+                Err(EncoderStatus::Encoded) => continue,
+                Err(EncoderStatus::NeedMoreData) | Err(EncoderStatus::LimitReached) => break,
+                Err(e) => return Err(EncoderError::backend("rav1e", e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_packet(&mut self, packet: Packet<u8>) -> Result<(), EncoderError> {
+        // Packets come back in input order, drop timestamps of anything rav1e skipped
+        let mut ts = None;
+        while let Some(&(frameno, t)) = self.pending_ts.front() {
+            if frameno > packet.input_frameno {
+                break;
+            }
+            self.pending_ts.pop_front();
+            if frameno == packet.input_frameno {
+                ts = Some(t);
+            }
+        }
+
+        let first_ts = self.first_ts.ok_or(EncoderError::backend("rav1e", "packet before any frame"))?;
+        let elapsed = ts
+            .ok_or(EncoderError::backend("rav1e", "no timestamp for packet"))?
+            .duration_since(first_ts);
+        let is_key = packet.frame_type == FrameType::KEY;
+
+        match &mut self.output {
+            Output::Ivf { file, frames } => {
+                let pts = (elapsed.as_secs_f64() * self.fps as f64).round() as u64;
+
+                file.write_all(&(packet.data.len() as u32).to_le_bytes())?;
+                file.write_all(&pts.to_le_bytes())?;
+                file.write_all(&packet.data)?;
+                *frames += 1;
+            }
+            Output::Mp4 { file, writer, fragment_duration } => {
+                if writer.is_none() {
+                    let color = self.color;
+                    let config_header = self.ctx.container_sequence_header();
+                    let seq_header = find_obu(&packet.data, OBU_SEQUENCE_HEADER)
+                        .ok_or(EncoderError::backend("rav1e", "first packet has no sequence header"))?;
+
+                    let mut av1c = Vec::new();
+                    mp4::write_box(&mut av1c, b"av1C", |b| {
+                        b.extend_from_slice(&config_header);
+                        b.extend_from_slice(seq_header);
+                    });
+                    mp4::write_box(&mut av1c, b"colr", |b| {
+                        b.extend_from_slice(b"nclx");
+                        mp4::put_u16(b, color.color_primaries as u16);
+                        mp4::put_u16(b, color.transfer_characteristics as u16);
+                        mp4::put_u16(b, color.matrix_coefficients as u16);
+                        b.push(0); // limited range
+                    });
+
+                    let entry = mp4::visual_sample_entry(b"av01", self.width, self.height, &av1c);
+                    let file = file.take().ok_or(EncoderError::Finished)?;
+                    *writer = Some(match fragment_duration {
+                        Some(duration) => Mp4Writer::fragmented(file, self.width, self.height, entry, *duration)?,
+                        None => Mp4Writer::new(file, self.width, self.height, entry)?,
+                    });
+                }
+
+                let pts = (elapsed.as_secs_f64() * mp4::TIMESCALE as f64).round() as u64;
+                let sample = strip_temporal_delimiters(&packet.data);
+
+                writer
+                    .as_mut()
+                    .ok_or(EncoderError::Finished)?
+                    .write_sample(&sample, pts, is_key)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Encoder for Rav1eEncoder {
+    fn append_frame(&mut self, frame: Frame) -> Result<(), EncoderError> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(EncoderError::FrameSizeMismatch {
+                expected: (self.width, self.height),
+                got: (frame.width, frame.height),
+            });
+        }
+
+        let first_ts = *self.first_ts.get_or_insert(frame.timestamp);
+
+        // On the grid the frame goes in once per slot, stamped with the slot's time
+        let timestamps: Vec<Instant> = match &mut self.grid {
+            Some(grid) => {
+                let slots = grid.slots(frame.timestamp);
+                slots.map(|slot| first_ts + grid.time(slot)).collect()
+            }
+            None => vec![frame.timestamp],
+        };
+        if timestamps.is_empty() {
+            return Ok(());
+        }
+
+        // Has to match the colour description rav1e was set up with
+        let to = Conversion { format: PixelFormat::I420, matrix: Matrix::Bt709, range: ColorRange::Limited };
+        let frame = convert::convert(frame, to)?;
+
+        let mut av1_frame = self.ctx.new_frame();
+        for (dst, src) in av1_frame.planes.iter_mut().zip(&frame.planes) {
+            dst.copy_from_raw_u8(&src.data, src.stride, 1);
+            // rav1e only pads frames it has the only reference to, and a repeated one is shared
+            dst.pad(self.width, self.height);
+        }
+
+        let av1_frame = Arc::new(av1_frame);
+        for timestamp in timestamps {
+            self.ctx
+                .send_frame(av1_frame.clone())
+                .map_err(|e| EncoderError::backend("rav1e", format!("rejected frame: {}", e)))?;
+
+            self.pending_ts.push_back((self.frames_sent, timestamp));
+            self.frames_sent += 1;
+
+            self.drain_packets()?;
+        }
+
+        Ok(())
+    }
+
+    fn add_chapter(&mut self, chapter: Chapter) -> Result<(), EncoderError> {
+        if self.ignored.contains(&"chapters") {
+            return Err(EncoderError::NoChapters);
+        }
+
+        self.chapters.push(chapter);
+        Ok(())
+    }
+
+    fn ignored_settings(&self) -> &[&'static str] {
+        &self.ignored
+    }
+
+    fn dropped_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::dropped)
+    }
+
+    fn duplicated_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::duplicated)
+    }
+
+    fn finish(&mut self) -> Result<(), EncoderError> {
+        self.ctx.flush();
+        self.drain_packets()?;
+
+        match &mut self.output {
+            Output::Ivf { file, frames } => {
+                file.seek(SeekFrom::Start(0))?;
+                write_ivf_header(file, self.width, self.height, self.fps, *frames)?;
+                file.flush()?;
+            }
+            Output::Mp4 { writer, .. } => {
+                if let Some(writer) = writer {
+                    for chapter in self.chapters.drain(..) {
+                        let at = self.first_ts.map_or(Duration::ZERO, |first_ts| chapter.start.saturating_duration_since(first_ts));
+                        writer.add_chapter((at.as_secs_f64() * mp4::TIMESCALE as f64).round() as u64, &chapter.title)?;
+                    }
+
+                    writer.finish()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_ivf_header(file: &mut impl Write, width: usize, height: usize, fps: u32, frames: u32) -> Result<(), EncoderError> {
+    file.write_all(b"DKIF")?;
+    file.write_all(&0u16.to_le_bytes())?; // version
+    file.write_all(&32u16.to_le_bytes())?; // header size
+    file.write_all(b"AV01")?;
+    file.write_all(&(width as u16).to_le_bytes())?;
+    file.write_all(&(height as u16).to_le_bytes())?;
+    file.write_all(&fps.to_le_bytes())?; // timebase denominator
+    file.write_all(&1u32.to_le_bytes())?; // timebase numerator
+    file.write_all(&frames.to_le_bytes())?;
+    file.write_all(&0u32.to_le_bytes())?;
+
+    Ok(())
+}
+
+// Splits a low-overhead bitstream into (obu_type, whole obu) pairs
+fn obus(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let header = *data.first()?;
+        let obu_type = (header >> 3) & 0xf;
+        let has_extension = header & 0x4 != 0;
+        let has_size = header & 0x2 != 0;
+
+        let mut pos = 1 + has_extension as usize;
+        let payload_len = if has_size {
+            let mut value = 0usize;
+            for i in 0..8 {
+                let byte = *data.get(pos)?;
+                pos += 1;
+                value |= ((byte & 0x7f) as usize) << (i * 7);
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            value
+        } else {
+            // A truncated OBU whose header runs past the end, nothing more to read
+            data.len().checked_sub(pos)?
+        };
+
+        let end = pos.saturating_add(payload_len).min(data.len());
+        let (obu, rest) = data.split_at(end);
+        data = rest;
+
+        Some((obu_type, obu))
+    })
+}
+
+fn find_obu(data: &[u8], obu_type: u8) -> Option<&[u8]> {
+    obus(data).find(|(t, _)| *t == obu_type).map(|(_, obu)| obu)
+}
+
+// ISO-BMFF AV1 samples shouldn't carry temporal delimiters
+fn strip_temporal_delimiters(data: &[u8]) -> Vec<u8> {
+    obus(data)
+        .filter(|(t, _)| *t != OBU_TEMPORAL_DELIMITER)
+        .flat_map(|(_, obu)| obu.iter().copied())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_obus_and_stops_at_truncated_ones() {
+        // Temporal delimiter with a size, then a sequence header running to the end
+        let data = [0x12, 0x00, 0x08, 0xaa, 0xbb];
+        let split: Vec<_> = obus(&data).collect();
+        assert_eq!(split, vec![(OBU_TEMPORAL_DELIMITER, &data[..2]), (OBU_SEQUENCE_HEADER, &data[2..])]);
+
+        // Headers that promise an extension byte or a size that isn't there
+        for data in [&[0x0c][..], &[0x0e][..], &[0x0a][..], &[0x0a, 0x80][..], &[0x0a, 0x05, 0xaa][..]] {
+            let split: Vec<_> = obus(data).collect();
+            assert!(split.len() <= 1, "{:?}", data);
+            assert!(split.iter().all(|(_, obu)| obu.len() <= data.len()));
+        }
+    }
+
+    #[test]
+    fn plays_straight_through_a_pause() {
+        use crate::clock::SessionClock;
+        use crate::frame::Plane;
+
+        for timing in [FrameTiming::Vfr, FrameTiming::Cfr] {
+            let path = std::env::temp_dir().join(format!("av1-test-{}-{:?}.ivf", std::process::id(), timing));
+            let settings = EncoderSettings { fps: 100, timing, overwrite: true, ..EncoderSettings::default() };
+            let mut encoder = Rav1eEncoder::init(64.0, 64.0, &path, &settings).unwrap();
+
+            let clock = SessionClock::new();
+            let source = clock.register("source");
+            let mut append = |at: Instant| {
+                let plane = Plane { data: vec![128; 64 * 64 * 4], stride: 64 * 4 };
+                let timestamp = source.stamp(at, at).unwrap();
+                encoder.append_frame(Frame::new(64, 64, PixelFormat::Bgra, vec![plane], timestamp)).unwrap();
+            };
+
+            // Five frames 10ms apart up to the pause, and five more from where it ends
+            let paused_at = Instant::now();
+            (0..5).rev().for_each(|i| append(paused_at - Duration::from_millis(10) * i));
+            assert!(clock.pause());
+            std::thread::sleep(Duration::from_millis(50));
+            let length = clock.resume().unwrap();
+            (1..=5).for_each(|i| append(paused_at + length + Duration::from_millis(10) * i));
+
+            encoder.finish().unwrap();
+            assert_eq!(encoder.duplicated_frames(), 0, "{:?}", timing);
+
+            let data = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            // One frame per 1/100s tick, without the 5 or more ticks the pause lasted
+            let mut pts = Vec::new();
+            let mut pos = 32;
+            while pos < data.len() {
+                let size = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+                pts.push(u64::from_le_bytes(data[pos + 4..pos + 12].try_into().unwrap()));
+                pos += 12 + size;
+            }
+            assert_eq!(pts, (0..10).collect::<Vec<u64>>(), "{:?}", timing);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+use super::settings::Codec;
+use crate::convert::ConvertError;
+use crate::frame::PixelFormat;
+
+/// Everything that can go wrong in the encoder layer, so callers can tell a bad
+/// frame apart from a full disk or a backend giving up.
+// Not every variant is reachable from every platform's backends
+#[allow(dead_code)]
+#[derive(Debug, Error)]
+pub enum EncoderError {
+    #[error("{backend} can't encode {codec:?}")]
+    UnsupportedCodec { backend: &'static str, codec: Codec },
+
+    #[error("{backend} can't write .{extension} files")]
+    UnsupportedContainer { backend: &'static str, extension: String },
+
+    #[error("{backend} doesn't accept {format:?} frames")]
+    UnsupportedPixelFormat { backend: &'static str, format: PixelFormat },
+
+    /// The frame is in a format the backend takes, but something else about it isn't
+    #[error("{backend} can't take this frame: {reason}")]
+    UnsupportedFrame { backend: &'static str, reason: &'static str },
+
+    #[error("invalid dimensions {width}x{height}: {reason}")]
+    InvalidDimensions { width: usize, height: usize, reason: &'static str },
+
+    #[error("frame is {}x{} but the encoder was set up for {}x{}", .got.0, .got.1, .expected.0, .expected.1)]
+    FrameSizeMismatch { expected: (usize, usize), got: (usize, usize) },
+
+    #[error("audio is {}ch at {} Hz but the encoder was set up for {}ch at {} Hz", .got.1, .got.0, .expected.1, .expected.0)]
+    AudioFormatMismatch { expected: (u32, usize), got: (u32, usize) },
+
+    #[error("{backend} can't encode audio at {sample_rate} Hz, only at {supported}")]
+    UnsupportedSampleRate { backend: &'static str, sample_rate: u32, supported: &'static str },
+
+    #[error("encoder has no audio track")]
+    NoAudioTrack,
+
+    #[error("encoder can't write chapters")]
+    NoChapters,
+
+    #[error(transparent)]
+    Convert(#[from] ConvertError),
+
+    #[error("output file {} already exists", .0.display())]
+    OutputExists(PathBuf),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("{backend} failed: {message}")]
+    Backend { backend: &'static str, message: String },
+
+    /// The other end of a channel to a backend thread went away
+    #[error("channel to {0} closed")]
+    ChannelClosed(&'static str),
+
+    #[error("encoder already finished")]
+    Finished,
+}
+
+impl EncoderError {
+    pub fn backend(backend: &'static str, message: impl std::fmt::Display) -> Self {
+        EncoderError::Backend { backend, message: message.to_string() }
+    }
+}
@@ -0,0 +1,468 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::{Encoder, EncoderError};
+use super::settings::{AudioCodec, AudioSettings, Chapter, Codec, EncoderSettings, FrameTiming, Profile, RateControl};
+use super::timing::FrameGrid;
+use crate::audio::{AudioBuffer, Timeline};
+use crate::convert::{self, Conversion};
+use crate::frame::{ColorRange, ColorSpace, Frame, PixelFormat};
+
+// How late PCM can be before the audio track is padded with silence instead
+const AUDIO_SLACK: Duration = Duration::from_millis(200);
+
+// Tells apart the temporary files of encoders open at the same time
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Pipes raw BGRA frames into an `ffmpeg` child process, which does the
+/// colour conversion, encoding and muxing.
+pub struct FfmpegCliEncoder {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    output: PathBuf,
+    width: usize,
+    height: usize,
+    // The rawvideo pipe carries no timestamps, so frames are laid onto a fixed grid
+    grid: FrameGrid,
+    audio: Option<AudioTrack>,
+    // Added by a second pass once ffmpeg is done, see `write_chapters`
+    chapters: Vec<Chapter>,
+    ignored: Vec<&'static str>,
+}
+
+// PCM reaches ffmpeg through a FIFO as a second input, fed from its own thread
+struct AudioTrack {
+    settings: AudioSettings,
+    fifo: PathBuf,
+    // Nanoseconds of video written so far, silence is only ever padded up to it
+    video_end: Arc<AtomicU64>,
+    tx: Option<Sender<AudioBuffer>>,
+    writer: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl Drop for AudioTrack {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.fifo).ok();
+    }
+}
+
+impl FfmpegCliEncoder {
+    pub fn init(height: f64, width: f64, output: &Path, settings: &EncoderSettings) -> Result<Self, EncoderError> {
+        let width = width as usize;
+        let height = height as usize;
+        let (encoder, pix_fmt) = encoder_and_pixel_format(settings);
+
+        // 4:2:0 needs whole chroma samples, ffmpeg would only tell us once it's running
+        if pix_fmt == "yuv420p" && (!width.is_multiple_of(2) || !height.is_multiple_of(2)) {
+            return Err(EncoderError::InvalidDimensions {
+                width,
+                height,
+                reason: "4:2:0 output needs even dimensions",
+            });
+        }
+
+        let (args, mut ignored) = output_args(settings, output);
+
+        // Frames always go out at -framerate, there's nowhere to put a timestamp
+        if settings.timing == FrameTiming::Vfr {
+            ignored.push("timing");
+        }
+
+        // The second pass that adds chapters would write the fragments out as one moov
+        if settings.chapters && settings.fragment_duration.is_some() {
+            ignored.push("chapters");
+        }
+
+        tracing::debug!(encoder, ?args, "spawning ffmpeg");
+
+        // With an audio track both tracks start now rather than at their first
+        // sample, so the writer thread knows where its timeline begins
+        let origin = settings.start.or(settings.audio.map(|_| Instant::now()));
+        // ffmpeg is told to overwrite the file we create, so it's our rule that decides
+        super::create_output(output, settings)?;
+        let fifo = settings.audio.map(|_| create_fifo()).transpose()?;
+
+        let mut command = Command::new("ffmpeg");
+        command
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args(["-f", "rawvideo", "-pix_fmt", "bgra"])
+            .args(["-video_size", &format!("{}x{}", width, height)])
+            .args(["-framerate", &settings.fps.to_string()])
+            .args(["-i", "-"]);
+
+        if let (Some(audio), Some(fifo)) = (settings.audio, &fifo) {
+            command
+                .args(["-thread_queue_size", "1024", "-f", "f32le"])
+                .args(["-ar", &audio.sample_rate.to_string()])
+                .args(["-ac", &audio.layout.channels().to_string()])
+                .arg("-i")
+                .arg(fifo);
+        }
+
+        let spawned = command.args(args).arg(output).stdin(Stdio::piped()).spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some(fifo) = &fifo {
+                    std::fs::remove_file(fifo).ok();
+                }
+                return Err(EncoderError::backend("ffmpeg", format!("failed to spawn: {}", e)));
+            }
+        };
+
+        let stdin = child.stdin.take().ok_or(EncoderError::ChannelClosed("ffmpeg stdin"))?;
+
+        let audio = match (settings.audio, fifo, origin) {
+            (Some(audio), Some(fifo), Some(origin)) => {
+                let (tx, rx) = mpsc::channel();
+                let timeline = Timeline::new(origin, audio.sample_rate, audio.layout);
+                let video_end = Arc::new(AtomicU64::new(0));
+                let writer = std::thread::spawn({
+                    let (fifo, video_end) = (fifo.clone(), video_end.clone());
+                    move || write_audio(&fifo, timeline, origin, &video_end, rx)
+                });
+
+                Some(AudioTrack { settings: audio, fifo, video_end, tx: Some(tx), writer: Some(writer) })
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            child,
+            stdin: Some(stdin),
+            output: output.to_path_buf(),
+            width,
+            height,
+            grid: FrameGrid::new(settings.fps, origin),
+            audio,
+            chapters: Vec::new(),
+            ignored,
+        })
+    }
+}
+
+fn temp_path(extension: &str) -> PathBuf {
+    let number = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("recording-{}-{}.{}", std::process::id(), number, extension))
+}
+
+fn create_fifo() -> Result<PathBuf, EncoderError> {
+    let fifo = temp_path("pcm");
+
+    let status = Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .map_err(|e| EncoderError::backend("mkfifo", format!("failed to spawn: {}", e)))?;
+    if !status.success() {
+        return Err(EncoderError::backend("mkfifo", format!("exited with {}", status)));
+    }
+
+    Ok(fifo)
+}
+
+// ffmpeg opens and probes its inputs one after the other, and won't read any
+// more video while it waits for audio. Rather than let that hold up capture,
+// the track is padded with silence whenever PCM is more than AUDIO_SLACK behind
+// the video. Not behind the wall clock: while recording is paused neither comes in.
+fn write_audio(
+    fifo: &Path,
+    mut timeline: Timeline,
+    origin: Instant,
+    video_end: &AtomicU64,
+    rx: Receiver<AudioBuffer>,
+) -> std::io::Result<()> {
+    // Blocks until ffmpeg opens the other end
+    let mut file = OpenOptions::new().write(true).open(fifo)?;
+
+    loop {
+        let samples = match rx.recv_timeout(AUDIO_SLACK) {
+            Ok(buffer) => timeline.place(&buffer),
+            Err(RecvTimeoutError::Timeout) => {
+                let video = origin + Duration::from_nanos(video_end.load(Ordering::Relaxed));
+                timeline.pad_to(video.checked_sub(AUDIO_SLACK).unwrap_or(origin))
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        file.write_all(&bytes)?;
+    }
+}
+
+// Lossless output stays RGB, going through YUV (limited range especially) would lose
+// some of what was captured
+fn encoder_and_pixel_format(settings: &EncoderSettings) -> (&'static str, &'static str) {
+    let lossless = settings.rate_control == RateControl::Lossless;
+
+    match (settings.codec, lossless) {
+        (Codec::H264, false) => ("libx264", "yuv420p"),
+        (Codec::H264, true) => ("libx264rgb", "bgr0"),
+        (Codec::Hevc, false) => ("libx265", "yuv420p"),
+        (Codec::Hevc, true) => ("libx265", "gbrp"),
+        (Codec::Av1, _) => ("libsvtav1", "yuv420p"),
+        (Codec::Vp9, false) => ("libvpx-vp9", "yuv420p"),
+        (Codec::Vp9, true) => ("libvpx-vp9", "gbrp"),
+        (Codec::Ffv1, _) => ("ffv1", "bgra"),
+    }
+}
+
+// The muxer `-movflags` means anything to
+fn is_mp4(output: &Path) -> bool {
+    matches!(output.extension().and_then(|ext| ext.to_str()), Some("mp4" | "mov" | "m4v"))
+}
+
+fn output_args(settings: &EncoderSettings, output: &Path) -> (Vec<String>, Vec<&'static str>) {
+    let mut args: Vec<String> = Vec::new();
+    let mut ignored = Vec::new();
+    let mut push = |a: &[&str]| args.extend(a.iter().map(|s| s.to_string()));
+
+    let lossless = settings.rate_control == RateControl::Lossless;
+
+    let (encoder, pix_fmt) = encoder_and_pixel_format(settings);
+    push(&["-c:v", encoder, "-pix_fmt", pix_fmt]);
+
+    let bitrate = settings.bitrate.to_string();
+    let bufsize = (settings.bitrate as u64 * 2).to_string();
+
+    match (settings.rate_control, settings.codec) {
+        (_, Codec::Ffv1) => ignored.push("rate_control"),
+        (RateControl::Cbr, _) => push(&["-b:v", &bitrate, "-minrate", &bitrate, "-maxrate", &bitrate, "-bufsize", &bufsize]),
+        (RateControl::Vbr, _) => push(&["-b:v", &bitrate]),
+        (RateControl::Crf(crf), Codec::Av1) => push(&["-crf", &crf.min(63).to_string()]),
+        (RateControl::Crf(crf), Codec::Vp9) => push(&["-crf", &crf.min(63).to_string(), "-b:v", "0"]),
+        (RateControl::Crf(crf), _) => push(&["-crf", &crf.to_string()]),
+        (RateControl::Lossless, Codec::H264) => push(&["-qp", "0"]),
+        (RateControl::Lossless, Codec::Hevc) => push(&["-x265-params", "lossless=1"]),
+        (RateControl::Lossless, Codec::Vp9) => push(&["-lossless", "1"]),
+        (RateControl::Lossless, Codec::Av1) => ignored.push("rate_control"),
+    }
+
+    if let Some(interval) = settings.keyframe_interval {
+        push(&["-g", &interval.to_string()]);
+    }
+
+    match (settings.profile, settings.codec) {
+        (None, _) => {}
+        // Lossless RGB needs the High 4:4:4 profile, which isn't one we expose
+        (Some(_), Codec::H264) if lossless => ignored.push("profile"),
+        (Some(Profile::Baseline), Codec::H264) => push(&["-profile:v", "baseline"]),
+        (Some(Profile::Main), Codec::H264 | Codec::Hevc) => push(&["-profile:v", "main"]),
+        (Some(Profile::High), Codec::H264) => push(&["-profile:v", "high"]),
+        _ => ignored.push("profile"),
+    }
+
+    // RGB output keeps BGRA as it is, there's no YUV matrix or range to tag
+    if matches!(pix_fmt, "bgra" | "bgr0" | "gbrp") {
+        ignored.push("color_space");
+        ignored.push("color_range");
+    } else {
+        let range = match settings.color_range {
+            ColorRange::Limited => "tv",
+            ColorRange::Full => "pc",
+        };
+        let trc = match settings.color_space {
+            ColorSpace::Srgb => "iec61966-2-1",
+            ColorSpace::Bt709 => "bt709",
+        };
+
+        // Convert with the matrix/range we're about to tag, instead of swscale's BT.601 default
+        push(&["-vf", &format!("scale=out_color_matrix=bt709:out_range={}", range)]);
+        push(&["-color_primaries", "bt709", "-color_trc", trc, "-colorspace", "bt709", "-color_range", range]);
+    }
+
+    match (settings.fragment_duration, is_mp4(output)) {
+        (Some(duration), true) => push(&[
+            "-movflags", "+empty_moov+default_base_moof",
+            "-frag_duration", &duration.as_micros().to_string(),
+        ]),
+        (None, true) => push(&["-movflags", "+faststart"]),
+        (Some(_), false) => ignored.push("fragment_duration"),
+        (None, false) => {}
+    }
+
+    if let Some(audio) = settings.audio {
+        let encoder = match audio.codec {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Opus => "libopus",
+        };
+        push(&["-map", "0:v", "-map", "1:a", "-c:a", encoder, "-b:a", &audio.bitrate.to_string()]);
+    }
+
+    (args, ignored)
+}
+
+impl Encoder for FfmpegCliEncoder {
+    fn append_frame(&mut self, frame: Frame) -> Result<(), EncoderError> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(EncoderError::FrameSizeMismatch {
+                expected: (self.width, self.height),
+                got: (frame.width, frame.height),
+            });
+        }
+
+        // Repeat the frame until the output catches up with capture time, or skip
+        // it entirely if we're already ahead
+        let slots = self.grid.slots(frame.timestamp);
+        if slots.is_empty() {
+            tracing::trace!(slot = slots.start, "output is ahead of capture, skipping frame");
+            return Ok(());
+        }
+
+        // ffmpeg treats rawvideo bgra as full range, anything else would come out miscoloured
+        let to = Conversion::to(PixelFormat::Bgra, &frame);
+        let frame = convert::convert(frame, to)?;
+
+        let stdin = self.stdin.as_mut().ok_or(EncoderError::Finished)?;
+        let plane = &frame.planes[0];
+        let row_len = self.width * 4;
+
+        for _ in slots {
+            for row in plane.data.chunks(plane.stride).take(self.height) {
+                stdin.write_all(&row[..row_len])?;
+            }
+        }
+
+        if let Some(track) = &self.audio {
+            track.video_end.store(self.grid.end().as_nanos() as u64, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn append_audio(&mut self, audio: AudioBuffer) -> Result<(), EncoderError> {
+        let track = self.audio.as_ref().ok_or(EncoderError::NoAudioTrack)?;
+
+        let expected = (track.settings.sample_rate, track.settings.layout.channels());
+        let got = (audio.sample_rate, audio.layout.channels());
+        if got != expected {
+            return Err(EncoderError::AudioFormatMismatch { expected, got });
+        }
+
+        let tx = track.tx.as_ref().ok_or(EncoderError::Finished)?;
+        tx.send(audio).map_err(|_| EncoderError::ChannelClosed("ffmpeg audio writer"))
+    }
+
+    fn add_chapter(&mut self, chapter: Chapter) -> Result<(), EncoderError> {
+        if self.ignored.contains(&"chapters") {
+            return Err(EncoderError::NoChapters);
+        }
+
+        self.chapters.push(chapter);
+        Ok(())
+    }
+
+    fn ignored_settings(&self) -> &[&'static str] {
+        &self.ignored
+    }
+
+    fn dropped_frames(&self) -> u64 {
+        self.grid.dropped()
+    }
+
+    fn duplicated_frames(&self) -> u64 {
+        self.grid.duplicated()
+    }
+
+    fn finish(&mut self) -> Result<(), EncoderError> {
+        // Closing stdin signals EOF so ffmpeg can write the moov atom, the
+        // audio FIFO closes once the writer is through what it was sent
+        drop(self.stdin.take());
+        let writer = self.audio.as_mut().and_then(|track| {
+            drop(track.tx.take());
+            track.writer.take()
+        });
+
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(EncoderError::backend("ffmpeg", format!("exited with {}", status)));
+        }
+
+        // A writer that's still going is stuck opening a FIFO ffmpeg never got to
+        if let Some(writer) = writer.filter(|writer| writer.is_finished()) {
+            writer.join().map_err(|_| EncoderError::backend("ffmpeg", "audio writer panicked"))??;
+        }
+
+        // The recording is already complete, a failed second pass only loses the chapters
+        if let (Some(origin), false) = (self.grid.origin(), self.chapters.is_empty()) {
+            if let Err(e) = write_chapters(&self.output, origin, &self.chapters, self.grid.end()) {
+                tracing::warn!("keeping the recording without chapters: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// ffmpeg only reads a metadata input when it starts, long before the chapters are
+// known, so they go in with a second pass that copies the streams as they are.
+// `end` is how long the video is, where the last chapter ends.
+fn write_chapters(output: &Path, origin: Instant, chapters: &[Chapter], end: Duration) -> Result<(), EncoderError> {
+    let mut starts: Vec<(Duration, &str)> = chapters
+        .iter()
+        .map(|chapter| (chapter.start.saturating_duration_since(origin), chapter.title.as_str()))
+        .collect();
+    starts.sort();
+
+    let mut metadata = String::from(";FFMETADATA1\n");
+    for (i, (start, title)) in starts.iter().enumerate() {
+        let next = starts.get(i + 1).map_or(end, |(next, _)| *next);
+        metadata.push_str(&format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            start.as_millis(),
+            next.max(*start).as_millis(),
+            escape_metadata(title)
+        ));
+    }
+
+    let metadata_path = temp_path("ffmeta");
+    std::fs::write(&metadata_path, metadata)?;
+
+    // Same directory and extension, so the rename is atomic and ffmpeg picks the same muxer
+    let name = output.file_name().and_then(|name| name.to_str()).unwrap_or("output");
+    let remuxed = output.with_file_name(format!(".chapters-{}", name));
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .arg("-i")
+        .arg(output)
+        .args(["-f", "ffmetadata", "-i"])
+        .arg(&metadata_path)
+        .args(["-map", "0", "-map_chapters", "1", "-c", "copy"]);
+    // movflags belongs to the MP4 muxer, nothing else takes it
+    if is_mp4(output) {
+        command.args(["-movflags", "+faststart"]);
+    }
+    let status = command.arg(&remuxed).status();
+    std::fs::remove_file(&metadata_path).ok();
+
+    let status = status.map_err(|e| EncoderError::backend("ffmpeg", format!("failed to spawn: {}", e)))?;
+    if !status.success() {
+        std::fs::remove_file(&remuxed).ok();
+        return Err(EncoderError::backend("ffmpeg", format!("adding chapters exited with {}", status)));
+    }
+
+    std::fs::rename(&remuxed, output).inspect_err(|_| {
+        std::fs::remove_file(&remuxed).ok();
+    })?;
+    Ok(())
+}
+
+// `=`, `;`, `#`, `\` and newlines mean something in an ffmetadata file
+fn escape_metadata(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -1,22 +1,129 @@
+use std::ffi::c_void;
 use std::path::Path;
+use std::ptr;
+use std::time::Instant;
 use cidre::arc::Retained;
 use cidre::{ns, av, cf, cm};
-use anyhow::Error;
 
-use super::Encoder;
+use super::{Encoder, EncoderError};
+use super::timing::FrameGrid;
+use super::settings::{AudioCodec, AudioSettings, Codec, EncoderSettings, FrameTiming, Profile, RateControl};
+use crate::audio::{AudioBuffer, Timeline};
+use crate::frame::{ColorRange, ColorSpace, Frame};
 
 #[link(name = "AVFoundation", kind = "framework")]
 extern "C" {
+    static AVVideoCodecKey: &'static ns::String;
+    static AVVideoCodecTypeH264: &'static ns::String;
+    static AVVideoCodecTypeHEVC: &'static ns::String;
+
     static AVVideoAverageBitRateKey: &'static ns::String;
+    static AVVideoQualityKey: &'static ns::String;
+    static AVVideoMaxKeyFrameIntervalKey: &'static ns::String;
+    static AVVideoExpectedSourceFrameRateKey: &'static ns::String;
     static AVVideoProfileLevelKey: &'static ns::String;
+    static AVVideoProfileLevelH264BaselineAutoLevel: &'static ns::String;
+    static AVVideoProfileLevelH264MainAutoLevel: &'static ns::String;
     static AVVideoProfileLevelH264HighAutoLevel: &'static ns::String;
 
     static AVVideoTransferFunctionKey: &'static ns::String;
     static AVVideoTransferFunction_ITU_R_709_2: &'static ns::String;
+    static AVVideoTransferFunction_IEC_sRGB: &'static ns::String;
     static AVVideoColorPrimariesKey: &'static ns::String;
     static AVVideoColorPrimaries_ITU_R_709_2: &'static ns::String;
     static AVVideoYCbCrMatrixKey: &'static ns::String;
     static AVVideoYCbCrMatrix_ITU_R_709_2: &'static ns::String;
+
+    static AVFormatIDKey: &'static ns::String;
+    static AVSampleRateKey: &'static ns::String;
+    static AVNumberOfChannelsKey: &'static ns::String;
+    static AVEncoderBitRateKey: &'static ns::String;
+}
+
+// Just enough of CoreMedia to wrap PCM in sample buffers and read the host clock
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CMTime {
+    value: i64,
+    timescale: i32,
+    flags: u32,
+    epoch: i64,
+}
+
+#[repr(C)]
+struct CMSampleTimingInfo {
+    duration: cm::Time,
+    pts: cm::Time,
+    dts: cm::Time,
+}
+
+#[repr(C)]
+struct AudioStreamBasicDescription {
+    sample_rate: f64,
+    format_id: u32,
+    format_flags: u32,
+    bytes_per_packet: u32,
+    frames_per_packet: u32,
+    bytes_per_frame: u32,
+    channels_per_frame: u32,
+    bits_per_channel: u32,
+    reserved: u32,
+}
+
+const TIME_VALID: u32 = 1;
+const FORMAT_LINEAR_PCM: u32 = u32::from_be_bytes(*b"lpcm");
+const FORMAT_MPEG4_AAC: u32 = u32::from_be_bytes(*b"aac ");
+const FORMAT_FLAG_IS_FLOAT: u32 = 1 << 0;
+const FORMAT_FLAG_IS_PACKED: u32 = 1 << 3;
+const BLOCK_BUFFER_ASSURE_MEMORY_NOW: u32 = 1 << 0;
+
+#[link(name = "CoreMedia", kind = "framework")]
+extern "C" {
+    fn CMClockGetHostTimeClock() -> *const c_void;
+    fn CMClockGetTime(clock: *const c_void) -> CMTime;
+    fn CMAudioFormatDescriptionCreate(
+        allocator: *const c_void,
+        asbd: *const AudioStreamBasicDescription,
+        layout_size: usize,
+        layout: *const c_void,
+        magic_cookie_size: usize,
+        magic_cookie: *const c_void,
+        extensions: *const c_void,
+        format_out: *mut *const c_void,
+    ) -> i32;
+    fn CMBlockBufferCreateWithMemoryBlock(
+        allocator: *const c_void,
+        memory_block: *mut c_void,
+        block_length: usize,
+        block_allocator: *const c_void,
+        custom_block_source: *const c_void,
+        offset_to_data: usize,
+        data_length: usize,
+        flags: u32,
+        block_buffer_out: *mut *const c_void,
+    ) -> i32;
+    fn CMSampleBufferCreateCopyWithNewTiming(
+        allocator: *const c_void,
+        original: *const c_void,
+        timing_count: isize,
+        timing: *const CMSampleTimingInfo,
+        sample_buffer_out: *mut *const c_void,
+    ) -> i32;
+    fn CMBlockBufferReplaceDataBytes(source: *const c_void, destination: *const c_void, offset: usize, length: usize) -> i32;
+    fn CMAudioSampleBufferCreateReadyWithPacketDescriptions(
+        allocator: *const c_void,
+        data_buffer: *const c_void,
+        format: *const c_void,
+        samples: isize,
+        pts: CMTime,
+        packet_descriptions: *const c_void,
+        sample_buffer_out: *mut *const c_void,
+    ) -> i32;
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFRelease(cf: *const c_void);
 }
 
 pub struct AVAssetWriterEncoder {
@@ -24,24 +131,63 @@ pub struct AVAssetWriterEncoder {
     input: Retained<av::AssetWriterInput>,
     first_ts: Option<cm::Time>,
     last_ts: Option<cm::Time>,
+    // `None` with `FrameTiming::Vfr`, frames keep their own timestamp.
+    // Otherwise they're copied onto the grid, whose slot 0 is `grid_start` seconds
+    // into the host clock once there is one.
+    grid: Option<FrameGrid>,
+    grid_start: Option<f64>,
+    audio: Option<AudioTrack>,
+    ignored: Vec<&'static str>,
+    dropped: u64,
+}
+
+// Second input of the writer, AVAssetWriter encodes the PCM to AAC
+struct AudioTrack {
+    settings: AudioSettings,
+    input: Retained<av::AssetWriterInput>,
+    timeline: Timeline,
+    // Where the track starts on the host clock, and how far into it the next
+    // sample goes, both in samples
+    start: i64,
+    written: u64,
 }
 
 impl AVAssetWriterEncoder {
-    pub fn init(height: f64, width: f64, output: &Path) -> Result<Self, Error> {
-        let mut writer = av::AssetWriter::with_url_and_file_type(
-            cf::Url::with_path(output, false)
-                .unwrap()
-                .as_ns(),
-            av::FileType::mp4(),
-        )?;
+    pub fn init(height: f64, width: f64, output: &Path, settings: &EncoderSettings) -> Result<Self, EncoderError> {
+        let mut ignored = Vec::new();
+
+        let codec = match settings.codec {
+            Codec::H264 => unsafe { AVVideoCodecTypeH264 },
+            Codec::Hevc => unsafe { AVVideoCodecTypeHEVC },
+            codec => return Err(EncoderError::UnsupportedCodec { backend: "AVAssetWriter", codec }),
+        };
+
+        // AVAssetWriter won't replace an existing file, and only says so once writing
+        // fails. Claiming the path first and handing it over empty keeps it ours.
+        super::create_output(output, settings)?;
+        std::fs::remove_file(output)?;
+
+        let url = cf::Url::with_path(output, false)
+            .ok_or(EncoderError::backend("AVAssetWriter", format!("invalid output path {}", output.display())))?;
+
+        let mut writer = av::AssetWriter::with_url_and_file_type(url.as_ns(), av::FileType::mp4())
+            .map_err(|e| EncoderError::backend("AVAssetWriter", format!("{:?}", e)))?;
+
+        // AVAssetWriter writes fragments plus a header it keeps rewriting, so the
+        // movie stays readable up to the last fragment if we never get to finish
+        if let Some(duration) = settings.fragment_duration {
+            writer.set_movie_fragment_interval(cm::Time::with_secs(duration.as_secs_f64(), 600));
+        }
 
         let assistant =
             av::OutputSettingsAssistant::with_preset(av::OutputSettingsPreset::h264_3840x2160())
-                .ok_or(Error::msg("Failed to create output settings assistant"))?;
+                .ok_or(EncoderError::backend("AVAssetWriter", "failed to create output settings assistant"))?;
 
 
         let mut dict = assistant.video_settings()
-            .ok_or(Error::msg("No assistant video settings"))?.copy_mut();
+            .ok_or(EncoderError::backend("AVAssetWriter", "no assistant video settings"))?.copy_mut();
+
+        dict.insert(unsafe { AVVideoCodecKey }, codec.as_id_ref());
 
         dict.insert(
             av::video_settings_keys::width(),
@@ -55,8 +201,35 @@ impl AVAssetWriterEncoder {
 
         let mut compression_flags = ns::DictionaryMut::new();
 
-        compression_flags.insert(unsafe { AVVideoProfileLevelKey }, unsafe { AVVideoProfileLevelH264HighAutoLevel }.as_id_ref());
-        compression_flags.insert( unsafe { AVVideoAverageBitRateKey }, ns::Number::with_u32(10_000_000).as_id_ref());
+        match (settings.codec, settings.profile) {
+            (Codec::H264, Some(Profile::Baseline)) => compression_flags.insert(unsafe { AVVideoProfileLevelKey }, unsafe { AVVideoProfileLevelH264BaselineAutoLevel }.as_id_ref()),
+            (Codec::H264, Some(Profile::Main)) => compression_flags.insert(unsafe { AVVideoProfileLevelKey }, unsafe { AVVideoProfileLevelH264MainAutoLevel }.as_id_ref()),
+            (Codec::H264, _) => compression_flags.insert(unsafe { AVVideoProfileLevelKey }, unsafe { AVVideoProfileLevelH264HighAutoLevel }.as_id_ref()),
+            // HEVC picks Main on its own
+            (_, Some(_)) => ignored.push("profile"),
+            _ => {}
+        }
+
+        match settings.rate_control {
+            RateControl::Vbr => compression_flags.insert( unsafe { AVVideoAverageBitRateKey }, ns::Number::with_u32(settings.bitrate).as_id_ref()),
+            // AVAssetWriter has no strict CBR, average bitrate is the closest thing
+            RateControl::Cbr => {
+                compression_flags.insert( unsafe { AVVideoAverageBitRateKey }, ns::Number::with_u32(settings.bitrate).as_id_ref());
+                ignored.push("rate_control");
+            }
+            // Map 0 (best) - 51 onto AVVideoQualityKey's 1.0 (best) - 0.0
+            RateControl::Crf(crf) => compression_flags.insert(unsafe { AVVideoQualityKey }, ns::Number::with_f32(1.0 - crf.min(51) as f32 / 51.0).as_id_ref()),
+            RateControl::Lossless => {
+                compression_flags.insert(unsafe { AVVideoQualityKey }, ns::Number::with_f32(1.0).as_id_ref());
+                ignored.push("rate_control");
+            }
+        }
+
+        if let Some(interval) = settings.keyframe_interval {
+            compression_flags.insert(unsafe { AVVideoMaxKeyFrameIntervalKey }, ns::Number::with_u32(interval).as_id_ref());
+        }
+
+        compression_flags.insert(unsafe { AVVideoExpectedSourceFrameRateKey }, ns::Number::with_u32(settings.fps).as_id_ref());
 
         dict.insert(
             av::video_settings_keys::compression_props(),
@@ -65,68 +238,358 @@ impl AVAssetWriterEncoder {
 
         let mut color_flags = ns::DictionaryMut::new();
 
-        color_flags.insert(unsafe { AVVideoTransferFunctionKey } , unsafe { AVVideoTransferFunction_ITU_R_709_2});
+        let transfer = match settings.color_space {
+            ColorSpace::Srgb => unsafe { AVVideoTransferFunction_IEC_sRGB },
+            ColorSpace::Bt709 => unsafe { AVVideoTransferFunction_ITU_R_709_2 },
+        };
+
+        color_flags.insert(unsafe { AVVideoTransferFunctionKey } , transfer);
         color_flags.insert(unsafe { AVVideoColorPrimariesKey} , unsafe { AVVideoColorPrimaries_ITU_R_709_2 });
         color_flags.insert(unsafe { AVVideoYCbCrMatrixKey} , unsafe { AVVideoYCbCrMatrix_ITU_R_709_2 });
 
         dict.insert(av::video_settings_keys::color_props(), color_flags.as_id_ref());
 
+        // VideoToolbox decides the range from the pixel format it's fed
+        if settings.color_range != ColorRange::Limited {
+            ignored.push("color_range");
+        }
+
+        // AVAssetWriter writes AAC into MP4, not Opus
+        let audio = match settings.audio {
+            Some(audio) if audio.codec != AudioCodec::Aac => {
+                ignored.push("audio");
+                None
+            }
+            audio => audio,
+        };
+
+        // Chapters would need a timed metadata track of their own
+        if settings.chapters {
+            ignored.push("chapters");
+        }
+
         let mut input = av::AssetWriterInput::with_media_type_and_output_settings(
             av::MediaType::video(),
             Some(dict.as_ref()),
         )
-        .map_err(|_| Error::msg("Failed to create AVAssetWriterInput"))?;
+        .map_err(|_| EncoderError::backend("AVAssetWriter", "failed to create AVAssetWriterInput"))?;
         input.set_expects_media_data_in_real_time(true);
 
         writer
             .add_input(&input)
-            .map_err(|_| Error::msg("Failed to add asset writer input"))?;
+            .map_err(|_| EncoderError::backend("AVAssetWriter", "failed to add asset writer input"))?;
+
+        let audio_input = match audio {
+            Some(audio) => {
+                let mut audio_settings = ns::DictionaryMut::new();
+                audio_settings.insert(unsafe { AVFormatIDKey }, ns::Number::with_u32(FORMAT_MPEG4_AAC).as_id_ref());
+                audio_settings.insert(unsafe { AVSampleRateKey }, ns::Number::with_u32(audio.sample_rate).as_id_ref());
+                audio_settings.insert(unsafe { AVNumberOfChannelsKey }, ns::Number::with_u32(audio.layout.channels() as u32).as_id_ref());
+                audio_settings.insert(unsafe { AVEncoderBitRateKey }, ns::Number::with_u32(audio.bitrate).as_id_ref());
+
+                let mut audio_input = av::AssetWriterInput::with_media_type_and_output_settings(
+                    av::MediaType::audio(),
+                    Some(audio_settings.as_ref()),
+                )
+                .map_err(|_| EncoderError::backend("AVAssetWriter", "failed to create audio AVAssetWriterInput"))?;
+                audio_input.set_expects_media_data_in_real_time(true);
+
+                writer
+                    .add_input(&audio_input)
+                    .map_err(|_| EncoderError::backend("AVAssetWriter", "failed to add audio asset writer input"))?;
+
+                Some((audio, audio_input))
+            }
+            None => None,
+        };
 
         writer.start_writing();
 
+        // Without a start or audio the session starts at the first frame. With one,
+        // it starts there for every track, so they line up with each other.
+        let origin = settings.start.or(audio.map(|_| Instant::now()));
+        let host_origin = origin.map(host_secs);
+        let first_ts = host_origin.map(|secs| {
+            let time = cm::Time::with_secs(secs, 1_000_000_000);
+            writer.start_session_at_src_time(time);
+            time
+        });
+
+        let audio = match (audio_input, origin, host_origin) {
+            (Some((audio, audio_input)), Some(origin), Some(host_origin)) => Some(AudioTrack {
+                settings: audio,
+                input: audio_input,
+                timeline: Timeline::new(origin, audio.sample_rate, audio.layout),
+                start: (host_origin * audio.sample_rate as f64).round() as i64,
+                written: 0,
+            }),
+            _ => None,
+        };
+
         Ok(Self {
             input,
             writer,
-            first_ts: None,
+            first_ts,
             last_ts: None,
+            grid: match settings.timing {
+                FrameTiming::Cfr => Some(FrameGrid::new(settings.fps, origin)),
+                FrameTiming::Vfr => None,
+            },
+            grid_start: host_origin,
+            audio,
+            ignored,
+            dropped: 0,
         })
     }
 }
 
 impl Encoder for AVAssetWriterEncoder {
-    fn append_frame(&mut self, frame: crabgrab::prelude::VideoFrame) -> Result<(), Error> {
+    fn append_frame(&mut self, frame: Frame) -> Result<(), EncoderError> {
+        // Real-time inputs aren't meant to wait, the frame is lost but counted
         if !self.input.is_ready_for_more_media_data() {
-            println!("not ready for more data");
+            tracing::debug!("input not ready for more data, dropping frame");
+            self.dropped += 1;
             return Ok(())
         }
 
+        // We hand AVAssetWriter the capturer's own CMSampleBuffer, so frames that
+        // didn't come from crabgrab can't be written yet
+        let native = frame
+            .native
+            .as_ref()
+            .ok_or(EncoderError::UnsupportedFrame {
+                backend: "AVAssetWriter",
+                reason: "needs frames backed by a CMSampleBuffer",
+            })?;
+
         // Get CMSampleBuffer from capturer and do some type gymnastics to cast it
-        let sample_buf = frame.get_cm_sample_buffer();
+        let sample_buf = native.get_cm_sample_buffer();
         let sample_buf = unsafe {
             let ptr = &*sample_buf as *const _ as *const cm::SampleBuf;
             &*ptr
         };
             
-        let time = sample_buf.pts();
+        let Some(grid) = &mut self.grid else {
+            // The capturer's own pts knows nothing of pauses or drift correction, the
+            // frame's timestamp comes from the session clock that handles both
+            let time = cm::Time::with_secs(host_secs(frame.timestamp), 1_000_000_000);
+
+            if self.first_ts.is_none() {
+                self.writer.start_session_at_src_time(time);
+                self.first_ts = Some(time);
+            }
+
+            self.last_ts = Some(time);
+
+            if !append_retimed(&mut self.input, sample_buf, time, cm::Time::invalid())? {
+                self.dropped += 1;
+            }
+
+            return Ok(());
+        };
+
+        // On the grid the frame goes in once per slot, as a copy stamped with the slot's time
+        let slots = grid.slots(frame.timestamp);
+        let start = match (self.grid_start, grid.origin()) {
+            (Some(start), _) => start,
+            (None, Some(origin)) => *self.grid_start.insert(host_secs(origin)),
+            (None, None) => return Ok(()),
+        };
 
         if self.first_ts.is_none() {
+            let time = cm::Time::with_secs(start, 1_000_000_000);
             self.writer.start_session_at_src_time(time);
             self.first_ts = Some(time);
         }
 
-        self.last_ts = Some(time);
+        let slot_length = cm::Time::with_secs(grid.time(1).as_secs_f64(), 1_000_000_000);
+        for slot in slots {
+            // Repeats go in back to back, the input can fill up between them
+            if !self.input.is_ready_for_more_media_data() {
+                self.dropped += 1;
+                continue;
+            }
 
-        self.input.append_sample_buf(sample_buf).ok();
+            let pts = cm::Time::with_secs(start + grid.time(slot).as_secs_f64(), 1_000_000_000);
+            if !append_retimed(&mut self.input, sample_buf, pts, slot_length)? {
+                self.dropped += 1;
+            }
+        }
+
+        // The session ends where the last slot does, not where it starts
+        self.last_ts = Some(cm::Time::with_secs(start + grid.end().as_secs_f64(), 1_000_000_000));
 
         Ok(())
     }
 
-    fn finish(&mut self) -> Result<(), Error> {
+    fn append_audio(&mut self, audio: AudioBuffer) -> Result<(), EncoderError> {
+        let track = self.audio.as_mut().ok_or(EncoderError::NoAudioTrack)?;
+
+        let expected = (track.settings.sample_rate, track.settings.layout.channels());
+        let got = (audio.sample_rate, audio.layout.channels());
+        if got != expected {
+            return Err(EncoderError::AudioFormatMismatch { expected, got });
+        }
+
+        let samples = track.timeline.place(&audio);
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        let pts = track.start + track.written as i64;
+        track.written += (samples.len() / expected.1) as u64;
+
+        // Same as frames, real-time inputs don't wait. The gap is left silent.
+        if !track.input.is_ready_for_more_media_data() {
+            tracing::debug!("audio input not ready for more data, dropping {} samples", samples.len() / expected.1);
+            return Ok(());
+        }
+
+        let sample_buf = pcm_sample_buf(&samples, &track.settings, pts)?;
+        let appended = track.input.append_sample_buf(unsafe { &*(sample_buf as *const cm::SampleBuf) });
+        unsafe { CFRelease(sample_buf) };
+
+        if let Err(e) = appended {
+            tracing::warn!("AVAssetWriter rejected audio: {:?}", e);
+        }
+
+        Ok(())
+    }
+
+    fn ignored_settings(&self) -> &[&'static str] {
+        &self.ignored
+    }
+
+    fn dropped_frames(&self) -> u64 {
+        self.dropped + self.grid.as_ref().map_or(0, FrameGrid::dropped)
+    }
+
+    fn duplicated_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::duplicated)
+    }
+
+    fn finish(&mut self) -> Result<(), EncoderError> {
         self.writer
             .end_session_at_src_time(self.last_ts.take().unwrap_or(cm::Time::zero()));
         self.input.mark_as_finished();
+        if let Some(track) = &mut self.audio {
+            track.input.mark_as_finished();
+        }
         self.writer.finish_writing();
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// Where `at` is on the host clock, which sample buffers are stamped with. `Instant`
+// counts the same ticks from somewhere else, reading both at once lines them up.
+fn host_secs(at: Instant) -> f64 {
+    let host = unsafe { CMClockGetTime(CMClockGetHostTimeClock()) };
+    let now = Instant::now();
+    let host = host.value as f64 / host.timescale as f64;
+
+    match now.checked_duration_since(at) {
+        Some(ago) => host - ago.as_secs_f64(),
+        None => host + (at - now).as_secs_f64(),
+    }
+}
+
+// Appends a copy of `sample_buf` stamped with `pts`, returning whether the input took it
+fn append_retimed(
+    input: &mut Retained<av::AssetWriterInput>,
+    sample_buf: &cm::SampleBuf,
+    pts: cm::Time,
+    duration: cm::Time,
+) -> Result<bool, EncoderError> {
+    // Uncompressed frames decode in the order they're shown
+    let timing = CMSampleTimingInfo { duration, pts, dts: pts };
+
+    let mut copy = ptr::null();
+    let status = unsafe {
+        CMSampleBufferCreateCopyWithNewTiming(ptr::null(), sample_buf as *const cm::SampleBuf as *const c_void, 1, &timing, &mut copy)
+    };
+    if status != 0 {
+        return Err(EncoderError::backend("AVAssetWriter", format!("retiming a frame failed with {}", status)));
+    }
+
+    let appended = input.append_sample_buf(unsafe { &*(copy as *const cm::SampleBuf) });
+    unsafe { CFRelease(copy) };
+
+    match appended {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            tracing::warn!("AVAssetWriter rejected sample: {:?}", e);
+            Ok(false)
+        }
+    }
+}
+
+// Wraps interleaved float PCM in a sample buffer that starts `pts` samples into
+// the host clock. The caller owns it and has to `CFRelease` it.
+fn pcm_sample_buf(samples: &[f32], settings: &AudioSettings, pts: i64) -> Result<*const c_void, EncoderError> {
+    let channels = settings.layout.channels() as u32;
+    let asbd = AudioStreamBasicDescription {
+        sample_rate: settings.sample_rate as f64,
+        format_id: FORMAT_LINEAR_PCM,
+        format_flags: FORMAT_FLAG_IS_FLOAT | FORMAT_FLAG_IS_PACKED,
+        bytes_per_packet: 4 * channels,
+        frames_per_packet: 1,
+        bytes_per_frame: 4 * channels,
+        channels_per_frame: channels,
+        bits_per_channel: 32,
+        reserved: 0,
+    };
+    let pts = CMTime { value: pts, timescale: settings.sample_rate as i32, flags: TIME_VALID, epoch: 0 };
+    let bytes = std::mem::size_of_val(samples);
+    let failed = |call: &str, status: i32| EncoderError::backend("AVAssetWriter", format!("{} failed with {}", call, status));
+
+    unsafe {
+        let mut format = ptr::null();
+        let status = CMAudioFormatDescriptionCreate(ptr::null(), &asbd, 0, ptr::null(), 0, ptr::null(), ptr::null(), &mut format);
+        if status != 0 {
+            return Err(failed("CMAudioFormatDescriptionCreate", status));
+        }
+
+        // The block buffer allocates and owns its memory, the samples are copied in
+        let mut block = ptr::null();
+        let mut status = CMBlockBufferCreateWithMemoryBlock(
+            ptr::null(),
+            ptr::null_mut(),
+            bytes,
+            ptr::null(),
+            ptr::null(),
+            0,
+            bytes,
+            BLOCK_BUFFER_ASSURE_MEMORY_NOW,
+            &mut block,
+        );
+        if status == 0 {
+            status = CMBlockBufferReplaceDataBytes(samples.as_ptr().cast(), block, 0, bytes);
+        }
+
+        let mut sample_buf = ptr::null();
+        if status == 0 {
+            let frames = (samples.len() / channels as usize) as isize;
+            status = CMAudioSampleBufferCreateReadyWithPacketDescriptions(
+                ptr::null(),
+                block,
+                format,
+                frames,
+                pts,
+                ptr::null(),
+                &mut sample_buf,
+            );
+        }
+
+        // The sample buffer keeps its own references to both
+        if !block.is_null() {
+            CFRelease(block);
+        }
+        CFRelease(format);
+
+        match status {
+            0 => Ok(sample_buf),
+            status => Err(failed("creating an audio sample buffer", status)),
+        }
+    }
+}
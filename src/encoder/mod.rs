@@ -1,8 +1,33 @@
 use anyhow::Error;
-use crabgrab::frame::VideoFrame;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::str::FromStr;
+use crate::audio::AudioBuffer;
+use crate::frame::Frame;
 
-// mod acffmpeg;
-// pub use acffmpeg::EncoderAcFfmpeg as VideoEncoder;
+mod error;
+pub use error::EncoderError;
+
+mod settings;
+pub use settings::{AudioCodec, AudioSettings, Av1Options, Chapter, Codec, EncoderSettings, FrameTiming, Profile, RateControl};
+#[cfg(feature = "ffmpeg")]
+pub use settings::FfmpegEncoder;
+
+mod mp4;
+
+mod timing;
+
+mod y4m;
+pub use y4m::Y4mEncoder;
+
+mod av1;
+pub use av1::Rav1eEncoder;
+
+#[cfg(feature = "ffmpeg")]
+mod acffmpeg;
+
+#[cfg(feature = "ffmpeg")]
+pub use acffmpeg::{EncoderAcFfmpeg, FfmpegCodec};
 
 #[cfg(target_os = "macos")]
 mod mac;
@@ -16,8 +41,125 @@ mod win;
 #[cfg(target_os = "windows")]
 pub use win::WmfEncoder as VideoEncoder;
 
+#[cfg(target_os = "linux")]
+mod linux;
+
+#[cfg(target_os = "linux")]
+pub use linux::FfmpegCliEncoder as VideoEncoder;
+
 pub trait Encoder {
-    fn append_frame(&mut self, video_frame: VideoFrame) -> Result<(), Error>;
+    fn append_frame(&mut self, frame: Frame) -> Result<(), EncoderError>;
+
+    /// Adds PCM to the audio track. Timestamps come from the same clock as the
+    /// frames', so audio and video line up however they were interleaved. Only
+    /// encoders opened with `EncoderSettings::audio`, that don't list `audio` in
+    /// `ignored_settings`, have a track to add it to.
+    fn append_audio(&mut self, _audio: AudioBuffer) -> Result<(), EncoderError> {
+        Err(EncoderError::NoAudioTrack)
+    }
+
+    /// Marks where a chapter starts. Chapters can come in any order, but only
+    /// before `finish`, and only encoders opened with `EncoderSettings::chapters`
+    /// that don't list `chapters` in `ignored_settings` can write them.
+    fn add_chapter(&mut self, _chapter: Chapter) -> Result<(), EncoderError> {
+        Err(EncoderError::NoChapters)
+    }
+
+    /// Names of the `EncoderSettings` fields this encoder couldn't honour.
+    fn ignored_settings(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Frames that were handed to `append_frame` but never made it into the file,
+    /// e.g. because the encoder wasn't ready for more.
+    fn dropped_frames(&self) -> u64 {
+        0
+    }
+
+    /// Extra copies of frames written to keep a constant frame rate, see
+    /// `FrameTiming::Cfr`.
+    fn duplicated_frames(&self) -> u64 {
+        0
+    }
+
+    fn finish(&mut self) -> Result<(), EncoderError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// AVAssetWriter on macOS, Media Foundation on Windows, ffmpeg on Linux
+    Native,
+    /// Uncompressed Y4M / raw dump
+    Lossless,
+    /// rav1e software AV1
+    Av1,
+    /// Any codec/muxer libavcodec offers, through ac-ffmpeg
+    #[cfg(feature = "ffmpeg")]
+    Ffmpeg,
+}
+
+impl Backend {
+    /// Guesses a backend from the output extension: `.y4m`, `.yuv` and `.bgra` get
+    /// the lossless writer, `.ivf` gets AV1, containers only FFmpeg can write go to
+    /// FFmpeg (when enabled) and everything else to the native encoder.
+    pub fn from_path(output: &Path) -> Self {
+        match output.extension().and_then(|ext| ext.to_str()) {
+            Some("y4m" | "yuv" | "bgra") => Backend::Lossless,
+            Some("ivf") => Backend::Av1,
+            #[cfg(feature = "ffmpeg")]
+            Some("mkv" | "webm" | "nut") => Backend::Ffmpeg,
+            _ => Backend::Native,
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "native" => Ok(Backend::Native),
+            "lossless" | "y4m" => Ok(Backend::Lossless),
+            "av1" | "rav1e" => Ok(Backend::Av1),
+            #[cfg(feature = "ffmpeg")]
+            "ffmpeg" => Ok(Backend::Ffmpeg),
+            _ => Err(Error::msg(format!("Unknown encoder backend: {}", s))),
+        }
+    }
+}
+
+// Every backend opens its output through here, so they agree on when a file that's
+// already there gets replaced
+fn create_output(path: &Path, settings: &EncoderSettings) -> Result<File, EncoderError> {
+    let mut options = OpenOptions::new();
+    match settings.overwrite {
+        true => options.write(true).create(true).truncate(true),
+        false => options.write(true).create_new(true),
+    };
+
+    options.open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => EncoderError::OutputExists(path.to_path_buf()),
+        _ => EncoderError::Io(e),
+    })
+}
+
+pub fn init_encoder(backend: Backend, height: f64, width: f64, output: &Path, settings: &EncoderSettings) -> Result<Box<dyn Encoder + Send>, EncoderError> {
+    // MP4/IVF store 16-bit sizes
+    if width < 1.0 || height < 1.0 || width > u16::MAX as f64 || height > u16::MAX as f64 {
+        return Err(EncoderError::InvalidDimensions {
+            width: width as usize,
+            height: height as usize,
+            reason: "must be between 1 and 65535",
+        });
+    }
+
+    let encoder: Box<dyn Encoder + Send> = match backend {
+        Backend::Native => Box::new(VideoEncoder::init(height, width, output, settings)?),
+        Backend::Lossless => Box::new(Y4mEncoder::init(height, width, output, settings)?),
+        Backend::Av1 => Box::new(Rav1eEncoder::init(height, width, output, settings)?),
+        #[cfg(feature = "ffmpeg")]
+        Backend::Ffmpeg => Box::new(EncoderAcFfmpeg::init(height, width, output, settings)?),
+    };
 
-    fn finish(&mut self) -> Result<(), Error>;
-}
\ No newline at end of file
+    Ok(encoder)
+}
@@ -0,0 +1,702 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use super::EncoderError;
+
+// 90kHz is the usual video clock, 32-bit durations at this rate last ~13h so
+// longer files get version 1 headers with 64-bit ones
+pub const TIMESCALE: u32 = 90_000;
+
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+// trun sample flags: keyframes depend on nothing, everything else is a non-sync sample
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+struct Sample {
+    offset: u64,
+    size: u32,
+    pts: u64,
+    is_sync: bool,
+}
+
+enum Layout {
+    /// One `mdat` for the whole file, `moov` is written behind it on finish.
+    /// `position` is the end of the file, where the next sample goes.
+    Progressive { mdat_start: u64, position: u64 },
+    /// Empty `moov` up front, then a `moof`+`mdat` pair per fragment. `samples`
+    /// only holds the fragment being built, its data is buffered in `pending`.
+    Fragmented {
+        fragment_duration: u64,
+        sequence: u32,
+        pending: Vec<u8>,
+    },
+}
+
+/// Minimal single-track ISO-BMFF writer. Samples are streamed straight into `mdat`
+/// and the sample tables are written out as `moov` when the writer is finished.
+/// The codec-specific sample entry (e.g. `av01`) is built by the caller.
+///
+/// With `fragmented` the file is written as fMP4 instead, so everything up to the
+/// last complete fragment is playable even if `finish` never runs.
+pub struct Mp4Writer {
+    file: BufWriter<File>,
+    width: u16,
+    height: u16,
+    sample_entry: Vec<u8>,
+    layout: Layout,
+    samples: Vec<Sample>,
+    last_pts: Option<u64>,
+    last_duration: Option<u32>,
+    // Start in `TIMESCALE` units and title, written as a Nero `chpl` box
+    chapters: Vec<(u64, String)>,
+}
+
+impl Mp4Writer {
+    pub fn new(file: File, width: usize, height: usize, sample_entry: Vec<u8>) -> Result<Self, EncoderError> {
+        let mut file = BufWriter::new(file);
+
+        let ftyp = build_ftyp();
+        file.write_all(&ftyp)?;
+
+        // Large-size mdat header, the real size is patched in on finish
+        let mdat_start = ftyp.len() as u64;
+        file.write_all(&1u32.to_be_bytes())?;
+        file.write_all(b"mdat")?;
+        file.write_all(&0u64.to_be_bytes())?;
+
+        Ok(Self {
+            file,
+            width: width as u16,
+            height: height as u16,
+            sample_entry,
+            layout: Layout::Progressive { mdat_start, position: mdat_start + 16 },
+            samples: Vec::new(),
+            last_pts: None,
+            last_duration: None,
+            chapters: Vec::new(),
+        })
+    }
+
+    /// Fragmented MP4. A new fragment is started at the first keyframe after
+    /// `fragment_duration`, so fragments are at least that long.
+    pub fn fragmented(
+        file: File,
+        width: usize,
+        height: usize,
+        sample_entry: Vec<u8>,
+        fragment_duration: Duration,
+    ) -> Result<Self, EncoderError> {
+        let mut writer = Self {
+            file: BufWriter::new(file),
+            width: width as u16,
+            height: height as u16,
+            sample_entry,
+            layout: Layout::Fragmented {
+                fragment_duration: (fragment_duration.as_secs_f64() * TIMESCALE as f64) as u64,
+                sequence: 0,
+                pending: Vec::new(),
+            },
+            samples: Vec::new(),
+            last_pts: None,
+            last_duration: None,
+            chapters: Vec::new(),
+        };
+
+        let mut header = build_ftyp();
+        header.extend(writer.build_moov());
+        writer.file.write_all(&header)?;
+        writer.file.flush()?;
+
+        Ok(writer)
+    }
+
+    /// `pts` is in `TIMESCALE` units. Only progressive files have chapters, a
+    /// fragmented file's `moov` is already written.
+    pub fn add_chapter(&mut self, pts: u64, title: &str) -> Result<(), EncoderError> {
+        if let Layout::Fragmented { .. } = self.layout {
+            return Err(EncoderError::NoChapters);
+        }
+
+        self.chapters.push((pts, title.to_string()));
+        Ok(())
+    }
+
+    /// `pts` is in `TIMESCALE` units and must not go backwards.
+    pub fn write_sample(&mut self, data: &[u8], pts: u64, is_sync: bool) -> Result<(), EncoderError> {
+        if self.last_pts.is_some_and(|last| pts < last) {
+            return Err(EncoderError::backend("MP4 writer", "sample timestamps went backwards"));
+        }
+        self.last_pts = Some(pts);
+
+        // Fragments start on keyframes, once the current one is long enough
+        if let Layout::Fragmented { fragment_duration, .. } = self.layout {
+            let fragment_full = self
+                .samples
+                .first()
+                .is_some_and(|first| pts - first.pts >= fragment_duration);
+
+            if is_sync && fragment_full {
+                self.write_fragment(Some(pts))?;
+            }
+        }
+
+        // Offsets are into the file for progressive, into the pending fragment otherwise
+        let offset = match &mut self.layout {
+            Layout::Progressive { position, .. } => {
+                self.file.write_all(data)?;
+                *position += data.len() as u64;
+                *position - data.len() as u64
+            }
+            Layout::Fragmented { pending, .. } => {
+                pending.extend_from_slice(data);
+                (pending.len() - data.len()) as u64
+            }
+        };
+
+        self.samples.push(Sample {
+            offset,
+            size: data.len() as u32,
+            pts,
+            is_sync,
+        });
+
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), EncoderError> {
+        match self.layout {
+            Layout::Progressive { mdat_start, position } => {
+                let moov = self.build_moov();
+                self.file.write_all(&moov)?;
+
+                self.file.seek(SeekFrom::Start(mdat_start + 8))?;
+                self.file.write_all(&(position - mdat_start).to_be_bytes())?;
+                self.file.seek(SeekFrom::End(0))?;
+            }
+            Layout::Fragmented { .. } => self.write_fragment(None)?,
+        }
+
+        self.file.flush()?;
+
+        Ok(())
+    }
+
+    /// Durations of the buffered samples. `next_pts` is the sample after them, when
+    /// there is one; otherwise the last sample lasts as long as the one before.
+    fn durations(&self, next_pts: Option<u64>) -> Vec<u32> {
+        let mut durations: Vec<u32> = self
+            .samples
+            .windows(2)
+            .map(|w| (w[1].pts - w[0].pts) as u32)
+            .collect();
+
+        if let Some(last) = self.samples.last() {
+            let duration = match next_pts {
+                Some(next) => (next - last.pts) as u32,
+                None => durations.last().copied().or(self.last_duration).unwrap_or(TIMESCALE / 60),
+            };
+            durations.push(duration);
+        }
+
+        durations
+    }
+
+    // Writes out the buffered samples as a moof+mdat pair and flushes them to disk
+    fn write_fragment(&mut self, next_pts: Option<u64>) -> Result<(), EncoderError> {
+        if self.samples.is_empty() {
+            return Ok(());
+        }
+
+        let durations = self.durations(next_pts);
+        self.last_duration = durations.last().copied();
+
+        let Layout::Fragmented { sequence, pending, .. } = &mut self.layout else {
+            return Err(EncoderError::backend("MP4 writer", "not a fragmented MP4"));
+        };
+        *sequence += 1;
+
+        // trun's data offset counts the moof itself, whose size doesn't depend on the offset
+        let moof_len = build_moof(*sequence, &self.samples, &durations, 0).len();
+        let moof = build_moof(*sequence, &self.samples, &durations, moof_len as u32 + 8);
+
+        self.file.write_all(&moof)?;
+        self.file.write_all(&(pending.len() as u32 + 8).to_be_bytes())?;
+        self.file.write_all(b"mdat")?;
+        self.file.write_all(pending)?;
+        self.file.flush()?;
+
+        pending.clear();
+        self.samples.clear();
+
+        Ok(())
+    }
+
+    fn build_moov(&self) -> Vec<u8> {
+        // Fragmented files carry their samples (and so their duration) in the fragments
+        let durations = match self.layout {
+            Layout::Progressive { .. } => self.durations(None),
+            Layout::Fragmented { .. } => Vec::new(),
+        };
+        let duration: u64 = durations.iter().map(|&d| d as u64).sum();
+
+        // The sample tables start at 0, so a first sample that isn't is pushed back to
+        // where it belongs by an empty edit in front of the track
+        let start = match self.layout {
+            Layout::Progressive { .. } => self.samples.first().map_or(0, |s| s.pts),
+            Layout::Fragmented { .. } => 0,
+        };
+        let version = if start + duration > u32::MAX as u64 { 1 } else { 0 };
+
+        let mut moov = Vec::new();
+        write_box(&mut moov, b"moov", |b| {
+            write_full_box(b, b"mvhd", version, 0, |b| {
+                put_time(b, version, 0); // creation time
+                put_time(b, version, 0); // modification time
+                put_u32(b, TIMESCALE);
+                put_time(b, version, start + duration);
+                put_u32(b, 0x0001_0000); // rate 1.0
+                put_u16(b, 0x0100); // volume 1.0
+                b.extend_from_slice(&[0; 10]);
+                MATRIX.iter().for_each(|v| put_u32(b, *v));
+                b.extend_from_slice(&[0; 24]);
+                put_u32(b, 2); // next track id
+            });
+
+            write_box(b, b"trak", |b| {
+                write_full_box(b, b"tkhd", version, 0x3, |b| {
+                    put_time(b, version, 0);
+                    put_time(b, version, 0);
+                    put_u32(b, 1); // track id
+                    put_u32(b, 0);
+                    put_time(b, version, start + duration);
+                    b.extend_from_slice(&[0; 8]);
+                    put_u16(b, 0); // layer
+                    put_u16(b, 0); // alternate group
+                    put_u16(b, 0); // volume
+                    put_u16(b, 0);
+                    MATRIX.iter().for_each(|v| put_u32(b, *v));
+                    put_u32(b, (self.width as u32) << 16);
+                    put_u32(b, (self.height as u32) << 16);
+                });
+
+                if start > 0 {
+                    write_box(b, b"edts", |b| {
+                        write_full_box(b, b"elst", version, 0, |b| {
+                            put_u32(b, 2);
+                            // Nothing for `start`, media time -1 is an empty edit
+                            put_time(b, version, start);
+                            put_time(b, version, u64::MAX);
+                            put_u32(b, 0x0001_0000); // rate 1.0
+                            // Then the samples from the beginning
+                            put_time(b, version, duration);
+                            put_time(b, version, 0);
+                            put_u32(b, 0x0001_0000);
+                        });
+                    });
+                }
+
+                write_box(b, b"mdia", |b| {
+                    write_full_box(b, b"mdhd", version, 0, |b| {
+                        put_time(b, version, 0);
+                        put_time(b, version, 0);
+                        put_u32(b, TIMESCALE);
+                        put_time(b, version, duration);
+                        put_u16(b, 0x55c4); // "und"
+                        put_u16(b, 0);
+                    });
+
+                    write_full_box(b, b"hdlr", 0, 0, |b| {
+                        put_u32(b, 0);
+                        b.extend_from_slice(b"vide");
+                        b.extend_from_slice(&[0; 12]);
+                        b.extend_from_slice(b"VideoHandler\0");
+                    });
+
+                    write_box(b, b"minf", |b| {
+                        write_full_box(b, b"vmhd", 0, 0x1, |b| {
+                            b.extend_from_slice(&[0; 8]);
+                        });
+
+                        write_box(b, b"dinf", |b| {
+                            write_full_box(b, b"dref", 0, 0, |b| {
+                                put_u32(b, 1);
+                                write_full_box(b, b"url ", 0, 0x1, |_| {});
+                            });
+                        });
+
+                        write_box(b, b"stbl", |b| self.write_stbl(b, &durations));
+                    });
+                });
+            });
+
+            if !self.chapters.is_empty() {
+                let mut chapters = self.chapters.clone();
+                chapters.sort();
+                chapters.truncate(u8::MAX as usize);
+
+                write_box(b, b"udta", |b| {
+                    write_full_box(b, b"chpl", 1, 0, |b| {
+                        put_u32(b, 0);
+                        b.push(chapters.len() as u8);
+
+                        for (pts, title) in &chapters {
+                            // Chapter starts are in 100ns units whatever the timescale
+                            put_u64(b, pts * 10_000_000 / TIMESCALE as u64);
+
+                            // Titles are a byte-length string, cut on a char boundary if too long
+                            let mut len = title.len().min(u8::MAX as usize);
+                            while !title.is_char_boundary(len) {
+                                len -= 1;
+                            }
+                            b.push(len as u8);
+                            b.extend_from_slice(&title.as_bytes()[..len]);
+                        }
+                    });
+                });
+            }
+
+            if let Layout::Fragmented { .. } = self.layout {
+                write_box(b, b"mvex", |b| {
+                    write_full_box(b, b"trex", 0, 0, |b| {
+                        put_u32(b, 1); // track id
+                        put_u32(b, 1); // sample description index
+                        put_u32(b, 0); // default duration, size and flags are all in trun
+                        put_u32(b, 0);
+                        put_u32(b, 0);
+                    });
+                });
+            }
+        });
+
+        moov
+    }
+
+    fn write_stbl(&self, b: &mut Vec<u8>, durations: &[u32]) {
+        write_full_box(b, b"stsd", 0, 0, |b| {
+            put_u32(b, 1);
+            b.extend_from_slice(&self.sample_entry);
+        });
+
+        // Run-length encoded durations
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for &d in durations {
+            match runs.last_mut() {
+                Some((count, delta)) if *delta == d => *count += 1,
+                _ => runs.push((1, d)),
+            }
+        }
+
+        write_full_box(b, b"stts", 0, 0, |b| {
+            put_u32(b, runs.len() as u32);
+            for (count, delta) in &runs {
+                put_u32(b, *count);
+                put_u32(b, *delta);
+            }
+        });
+
+        // An empty stss would mean no keyframes at all, leave it out of fragmented headers
+        if self.samples.is_empty() {
+            write_full_box(b, b"stsc", 0, 0, |b| put_u32(b, 0));
+            write_full_box(b, b"stsz", 0, 0, |b| {
+                put_u32(b, 0);
+                put_u32(b, 0);
+            });
+            write_full_box(b, b"co64", 0, 0, |b| put_u32(b, 0));
+            return;
+        }
+
+        write_full_box(b, b"stss", 0, 0, |b| {
+            let sync: Vec<u32> = self
+                .samples
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.is_sync)
+                .map(|(i, _)| i as u32 + 1)
+                .collect();
+
+            put_u32(b, sync.len() as u32);
+            sync.iter().for_each(|i| put_u32(b, *i));
+        });
+
+        // One sample per chunk keeps the chunk tables trivial
+        write_full_box(b, b"stsc", 0, 0, |b| {
+            put_u32(b, 1);
+            put_u32(b, 1);
+            put_u32(b, 1);
+            put_u32(b, 1);
+        });
+
+        write_full_box(b, b"stsz", 0, 0, |b| {
+            put_u32(b, 0);
+            put_u32(b, self.samples.len() as u32);
+            self.samples.iter().for_each(|s| put_u32(b, s.size));
+        });
+
+        write_full_box(b, b"co64", 0, 0, |b| {
+            put_u32(b, self.samples.len() as u32);
+            self.samples.iter().for_each(|s| put_u64(b, s.offset));
+        });
+    }
+}
+
+fn build_ftyp() -> Vec<u8> {
+    let mut ftyp = Vec::new();
+    write_box(&mut ftyp, b"ftyp", |b| {
+        b.extend_from_slice(b"isom");
+        put_u32(b, 0x200);
+        for brand in [b"isom", b"iso6", b"mp41", b"av01"] {
+            b.extend_from_slice(brand);
+        }
+    });
+
+    ftyp
+}
+
+// `data_offset` is from the start of the moof to the first sample in the mdat after it
+fn build_moof(sequence: u32, samples: &[Sample], durations: &[u32], data_offset: u32) -> Vec<u8> {
+    let mut moof = Vec::new();
+
+    write_box(&mut moof, b"moof", |b| {
+        write_full_box(b, b"mfhd", 0, 0, |b| put_u32(b, sequence));
+
+        write_box(b, b"traf", |b| {
+            // default-base-is-moof, so offsets don't depend on where the fragment lands
+            write_full_box(b, b"tfhd", 0, 0x02_0000, |b| put_u32(b, 1));
+
+            write_full_box(b, b"tfdt", 1, 0, |b| put_u64(b, samples[0].pts));
+
+            // data offset, then duration, size and flags per sample
+            write_full_box(b, b"trun", 0, 0x0701, |b| {
+                put_u32(b, samples.len() as u32);
+                put_u32(b, data_offset);
+
+                for (sample, duration) in samples.iter().zip(durations) {
+                    put_u32(b, *duration);
+                    put_u32(b, sample.size);
+                    put_u32(b, if sample.is_sync { SYNC_SAMPLE_FLAGS } else { NON_SYNC_SAMPLE_FLAGS });
+                }
+            });
+        });
+    });
+
+    moof
+}
+
+/// `VisualSampleEntry` wrapper around the codec configuration boxes.
+pub fn visual_sample_entry(kind: &[u8; 4], width: usize, height: usize, config_boxes: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::new();
+
+    write_box(&mut entry, kind, |b| {
+        b.extend_from_slice(&[0; 6]);
+        put_u16(b, 1); // data reference index
+        b.extend_from_slice(&[0; 16]);
+        put_u16(b, width as u16);
+        put_u16(b, height as u16);
+        put_u32(b, 0x0048_0000); // 72 dpi
+        put_u32(b, 0x0048_0000);
+        put_u32(b, 0);
+        put_u16(b, 1); // frame count
+        b.extend_from_slice(&[0; 32]); // compressor name
+        put_u16(b, 0x0018); // depth
+        put_u16(b, 0xffff);
+        b.extend_from_slice(config_boxes);
+    });
+
+    entry
+}
+
+pub fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+pub fn write_full_box(out: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    write_box(out, kind, |b| {
+        put_u32(b, (version as u32) << 24 | (flags & 0x00ff_ffff));
+        body(b);
+    });
+}
+
+pub fn put_u16(b: &mut Vec<u8>, v: u16) {
+    b.extend_from_slice(&v.to_be_bytes());
+}
+
+pub fn put_u32(b: &mut Vec<u8>, v: u32) {
+    b.extend_from_slice(&v.to_be_bytes());
+}
+
+pub fn put_u64(b: &mut Vec<u8>, v: u64) {
+    b.extend_from_slice(&v.to_be_bytes());
+}
+
+// Times and durations in mvhd, tkhd, mdhd and elst are 64-bit in version 1 boxes.
+// Version 0 truncates, which turns the u64::MAX of an empty edit into u32::MAX
+fn put_time(b: &mut Vec<u8>, version: u8, v: u64) {
+    if version == 1 {
+        put_u64(b, v);
+    } else {
+        put_u32(b, v as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Child boxes as (type, offset of the box, body), checking their sizes add up to exactly `data`
+    fn boxes(data: &[u8]) -> Vec<([u8; 4], usize, &[u8])> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let (mut size, mut header) = (read_u32(data, pos) as usize, 8);
+            if size == 1 {
+                (size, header) = (read_u64(data, pos + 8) as usize, 16);
+            }
+            assert!(size >= header && pos + size <= data.len(), "box at {} runs past its parent", pos);
+
+            out.push((data[pos + 4..pos + 8].try_into().unwrap(), pos, &data[pos + header..pos + size]));
+            pos += size;
+        }
+        out
+    }
+
+    // Body of the box at `path`, each step being a child of the previous one
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        path.iter().fold(data, |data, kind| {
+            let found = boxes(data).into_iter().find(|(k, _, _)| k == *kind);
+            found.unwrap_or_else(|| panic!("no {:?} box", std::str::from_utf8(*kind))).2
+        })
+    }
+
+    fn read_u32(data: &[u8], pos: usize) -> u32 {
+        u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn read_u64(data: &[u8], pos: usize) -> u64 {
+        u64::from_be_bytes(data[pos..pos + 8].try_into().unwrap())
+    }
+
+    // Runs `write` against a writer on a scratch file and returns what ended up in it
+    fn written(name: &str, fragment: Option<Duration>, write: impl FnOnce(&mut Mp4Writer)) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("mp4-test-{}-{}.mp4", std::process::id(), name));
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let entry = visual_sample_entry(b"av01", 64, 48, &[]);
+
+        let mut writer = match fragment {
+            Some(duration) => Mp4Writer::fragmented(file, 64, 48, entry, duration).unwrap(),
+            None => Mp4Writer::new(file, 64, 48, entry).unwrap(),
+        };
+        write(&mut writer);
+        writer.finish().unwrap();
+        drop(writer);
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn progressive_file_parses_back() {
+        let samples: [(&[u8], u64, bool); 3] = [(&[1; 10], 3000, true), (&[2; 20], 6000, false), (&[3; 30], 9000, true)];
+        let data = written("progressive", None, |writer| {
+            for (sample, pts, is_sync) in samples {
+                writer.write_sample(sample, pts, is_sync).unwrap();
+            }
+        });
+
+        let top: Vec<_> = boxes(&data).iter().map(|(kind, _, _)| *kind).collect();
+        assert_eq!(top, [*b"ftyp", *b"mdat", *b"moov"]);
+
+        // The first sample is 3000 in, after which three last 3000 each
+        let mvhd = find(&data, &[b"moov", b"mvhd"]);
+        assert_eq!((read_u32(mvhd, 0), read_u32(mvhd, 12), read_u32(mvhd, 16)), (0, TIMESCALE, 12000));
+        let mdhd = find(&data, &[b"moov", b"trak", b"mdia", b"mdhd"]);
+        assert_eq!(read_u32(mdhd, 16), 9000);
+
+        // An empty edit for the 3000 before the first sample, then all of them
+        let elst = find(&data, &[b"moov", b"trak", b"edts", b"elst"]);
+        let entries: Vec<u32> = (0..7).map(|i| read_u32(elst, 4 + i * 4)).collect();
+        assert_eq!(entries, [2, 3000, u32::MAX, 0x0001_0000, 9000, 0, 0x0001_0000]);
+
+        let stbl = find(&data, &[b"moov", b"trak", b"mdia", b"minf", b"stbl"]);
+        let stts = find(stbl, &[b"stts"]);
+        assert_eq!((read_u32(stts, 4), read_u32(stts, 8), read_u32(stts, 12)), (1, 3, 3000));
+        let stss = find(stbl, &[b"stss"]);
+        assert_eq!((read_u32(stss, 4), read_u32(stss, 8), read_u32(stss, 12)), (2, 1, 3));
+
+        // Every chunk offset points at its sample's bytes
+        let (stsz, co64) = (find(stbl, &[b"stsz"]), find(stbl, &[b"co64"]));
+        assert_eq!(read_u32(co64, 4), 3);
+        for (i, (sample, _, _)) in samples.iter().enumerate() {
+            let (offset, size) = (read_u64(co64, 8 + i * 8) as usize, read_u32(stsz, 12 + i * 4) as usize);
+            assert_eq!(&data[offset..offset + size], *sample);
+        }
+    }
+
+    #[test]
+    fn fragmented_file_parses_back() {
+        // Fragments of 3000, so the keyframe at 3000 starts the second one
+        let samples: [(&[u8], u64, bool); 4] =
+            [(&[1; 10], 0, true), (&[2; 20], 1500, false), (&[3; 30], 3000, true), (&[4; 40], 4500, false)];
+        let data = written("fragmented", Some(Duration::from_secs_f64(3000.0 / TIMESCALE as f64)), |writer| {
+            for (sample, pts, is_sync) in samples {
+                writer.write_sample(sample, pts, is_sync).unwrap();
+            }
+        });
+
+        let top = boxes(&data);
+        let kinds: Vec<_> = top.iter().map(|(kind, _, _)| *kind).collect();
+        assert_eq!(kinds, [*b"ftyp", *b"moov", *b"moof", *b"mdat", *b"moof", *b"mdat"]);
+        find(&data, &[b"moov", b"mvex", b"trex"]);
+
+        for (fragment, (moof, expected)) in [(top[2], &samples[..2]), (top[4], &samples[2..])].into_iter().enumerate() {
+            let (_, moof_start, body) = moof;
+            assert_eq!(read_u32(find(body, &[b"mfhd"]), 4), fragment as u32 + 1);
+            assert_eq!(read_u64(find(body, &[b"traf", b"tfdt"]), 4), expected[0].1);
+
+            let trun = find(body, &[b"traf", b"trun"]);
+            assert_eq!(read_u32(trun, 4), 2);
+
+            // The data offset counts from the moof and lands on the first sample in the mdat after it
+            let mut offset = moof_start + read_u32(trun, 8) as usize;
+            for (i, (sample, _, is_sync)) in expected.iter().enumerate() {
+                let entry = 12 + i * 12;
+                assert_eq!(read_u32(trun, entry), 1500);
+                let flags = if *is_sync { SYNC_SAMPLE_FLAGS } else { NON_SYNC_SAMPLE_FLAGS };
+                assert_eq!(read_u32(trun, entry + 8), flags);
+
+                let size = read_u32(trun, entry + 4) as usize;
+                assert_eq!(&data[offset..offset + size], *sample);
+                offset += size;
+            }
+        }
+    }
+
+    #[test]
+    fn long_files_get_64_bit_durations() {
+        // Starts past what 32 bits hold and runs for another 6e9, ~18h at 90kHz
+        let data = written("long", None, |writer| {
+            writer.write_sample(&[1; 4], 5_000_000_000, true).unwrap();
+            writer.write_sample(&[2; 4], 8_000_000_000, false).unwrap();
+        });
+
+        let mvhd = find(&data, &[b"moov", b"mvhd"]);
+        assert_eq!((mvhd[0], read_u32(mvhd, 20), read_u64(mvhd, 24)), (1, TIMESCALE, 11_000_000_000));
+        let tkhd = find(&data, &[b"moov", b"trak", b"tkhd"]);
+        assert_eq!((tkhd[0], read_u32(tkhd, 20), read_u64(tkhd, 28)), (1, 1, 11_000_000_000));
+        let mdhd = find(&data, &[b"moov", b"trak", b"mdia", b"mdhd"]);
+        assert_eq!((mdhd[0], read_u64(mdhd, 24)), (1, 6_000_000_000));
+
+        let elst = find(&data, &[b"moov", b"trak", b"edts", b"elst"]);
+        assert_eq!((elst[0], read_u32(elst, 4)), (1, 2));
+        assert_eq!((read_u64(elst, 8), read_u64(elst, 16), read_u32(elst, 24)), (5_000_000_000, u64::MAX, 0x0001_0000));
+        assert_eq!((read_u64(elst, 28), read_u64(elst, 36)), (6_000_000_000, 0));
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::Error;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::audio::ChannelLayout;
+use crate::frame::{ColorRange, ColorSpace};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    Hevc,
+    Av1,
+    Vp9,
+    Ffv1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateControl {
+    /// Constant bitrate at `EncoderSettings::bitrate`
+    Cbr,
+    /// Average bitrate of `EncoderSettings::bitrate`, free to vary per frame
+    Vbr,
+    /// Constant quality, x264-style 0 (best) to 51
+    Crf(u8),
+    Lossless,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    Baseline,
+    Main,
+    High,
+}
+
+/// How frames are timed in the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameTiming {
+    /// Exactly `fps` frames a second: frames are moved onto a `1 / fps` grid,
+    /// repeated to fill the gaps when nothing changes and dropped when two land in
+    /// the same slot. What editors expect.
+    Cfr,
+    /// Every frame keeps the time it was captured at, so a static screen costs
+    /// nothing and motion is shown exactly when it happened
+    Vfr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioCodec {
+    Aac,
+    Opus,
+}
+
+/// The audio track, sized for the PCM that will be appended to it. Encoders
+/// don't resample, so it has to match what the source delivers, and one whose
+/// codec can't take that rate fails with `EncoderError::UnsupportedSampleRate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioSettings {
+    pub codec: AudioCodec,
+    pub sample_rate: u32,
+    pub layout: ChannelLayout,
+    /// Bits per second
+    pub bitrate: u32,
+}
+
+/// rav1e knobs that no other backend has, the rest leave them alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Av1Options {
+    /// rav1e speed preset, 0 (slowest) to 10 (fastest)
+    pub speed: u8,
+    /// Total tile count, 0 lets rav1e decide
+    pub tiles: usize,
+}
+
+impl Default for Av1Options {
+    fn default() -> Self {
+        Self { speed: 10, tiles: 0 }
+    }
+}
+
+/// libavcodec encoder for the ffmpeg backend to open instead of its own pick for
+/// `EncoderSettings::codec`, which still decides how rate control and profile
+/// are passed to it.
+#[cfg(feature = "ffmpeg")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FfmpegEncoder {
+    pub name: String,
+    /// What frames are handed to it as, an FFmpeg pixel format name
+    pub pixel_format: String,
+}
+
+/// A named point in the output, e.g. where a paused recording picks up again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chapter {
+    /// On the same clock as frame timestamps
+    pub start: Instant,
+    pub title: String,
+}
+
+/// Backend-agnostic encoder configuration. Backends map what they can and
+/// report the rest through `Encoder::ignored_settings`.
+#[derive(Clone, Debug)]
+pub struct EncoderSettings {
+    pub codec: Codec,
+    pub rate_control: RateControl,
+    /// Bits per second, used by `Cbr` and `Vbr`
+    pub bitrate: u32,
+    pub fps: u32,
+    pub timing: FrameTiming,
+    /// Maximum frames between keyframes, `None` leaves it to the encoder
+    pub keyframe_interval: Option<u32>,
+    /// `None` leaves it to the encoder
+    pub profile: Option<Profile>,
+    /// Colour tags written into the bitstream/container
+    pub color_space: ColorSpace,
+    pub color_range: ColorRange,
+    /// Write MP4 as fragments of about this length, so a crash only loses the
+    /// last one. `None` writes a regular MP4 that needs `finish` to be playable.
+    pub fragment_duration: Option<Duration>,
+    /// Time zero for timestamps. Outputs recorded together share it so they line
+    /// up, `None` starts each output at its first frame.
+    pub start: Option<Instant>,
+    /// Adds an audio track next to the video, `None` records video only
+    pub audio: Option<AudioSettings>,
+    /// Writes the chapters handed to `Encoder::add_chapter` into the output
+    pub chapters: bool,
+    /// Replaces an output file that's already there, instead of failing with
+    /// `EncoderError::OutputExists`
+    pub overwrite: bool,
+    pub av1: Av1Options,
+    #[cfg(feature = "ffmpeg")]
+    pub ffmpeg_encoder: Option<FfmpegEncoder>,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        Self {
+            codec: Codec::H264,
+            rate_control: RateControl::Vbr,
+            bitrate: 10_000_000,
+            fps: 60,
+            timing: FrameTiming::Cfr,
+            keyframe_interval: None,
+            profile: None,
+            color_space: ColorSpace::Bt709,
+            color_range: ColorRange::Limited,
+            fragment_duration: None,
+            start: None,
+            audio: None,
+            chapters: false,
+            overwrite: false,
+            av1: Av1Options::default(),
+            #[cfg(feature = "ffmpeg")]
+            ffmpeg_encoder: None,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "h264" | "avc" => Ok(Codec::H264),
+            "h265" | "hevc" => Ok(Codec::Hevc),
+            "av1" => Ok(Codec::Av1),
+            "vp9" => Ok(Codec::Vp9),
+            "ffv1" => Ok(Codec::Ffv1),
+            _ => Err(Error::msg(format!("Unknown codec: {}", s))),
+        }
+    }
+}
+
+/// `cbr`, `vbr`, `lossless` or `crf:<0-51>`
+impl FromStr for RateControl {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "cbr" => Ok(RateControl::Cbr),
+            "vbr" => Ok(RateControl::Vbr),
+            "lossless" => Ok(RateControl::Lossless),
+            other => match other.strip_prefix("crf:").map(str::parse::<u8>) {
+                Some(Ok(crf)) if crf <= 51 => Ok(RateControl::Crf(crf)),
+                _ => Err(Error::msg(format!("Unknown rate control: {}", s))),
+            },
+        }
+    }
+}
+
+/// `cfr` or `vfr`
+impl FromStr for FrameTiming {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "cfr" | "constant" => Ok(FrameTiming::Cfr),
+            "vfr" | "variable" => Ok(FrameTiming::Vfr),
+            _ => Err(Error::msg(format!("Unknown frame timing: {}", s))),
+        }
+    }
+}
+
+impl FromStr for AudioCodec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "aac" => Ok(AudioCodec::Aac),
+            "opus" => Ok(AudioCodec::Opus),
+            _ => Err(Error::msg(format!("Unknown audio codec: {}", s))),
+        }
+    }
+}
+
+/// `<name>` or `<name>:<pixel format>`, e.g. `libaom-av1` or `libx264:yuv444p`.
+/// The pixel format is yuv420p unless given.
+#[cfg(feature = "ffmpeg")]
+impl FromStr for FfmpegEncoder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (name, pixel_format) = s.split_once(':').unwrap_or((s, "yuv420p"));
+        if name.is_empty() || pixel_format.is_empty() {
+            return Err(Error::msg(format!("Invalid FFmpeg encoder {:?}, expected <name> or <name>:<pixel format>", s)));
+        }
+
+        Ok(FfmpegEncoder { name: name.to_string(), pixel_format: pixel_format.to_string() })
+    }
+}
+
+impl FromStr for Profile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "baseline" => Ok(Profile::Baseline),
+            "main" => Ok(Profile::Main),
+            "high" => Ok(Profile::High),
+            _ => Err(Error::msg(format!("Unknown profile: {}", s))),
+        }
+    }
+}
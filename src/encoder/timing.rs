@@ -0,0 +1,144 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Lays frames onto the exact `1 / fps` grid of a constant frame rate output.
+/// A frame fills every slot from the first empty one up to the one nearest its
+/// timestamp, so when the source goes quiet (a static screen) the last frame is
+/// repeated, and a frame whose slot is already taken is dropped.
+pub struct FrameGrid {
+    fps: u32,
+    origin: Option<Instant>,
+    // First slot nothing has been put in yet
+    next: u64,
+    dropped: u64,
+    duplicated: u64,
+}
+
+impl FrameGrid {
+    /// `origin` is where slot 0 is, `None` puts it at the first frame.
+    pub fn new(fps: u32, origin: Option<Instant>) -> Self {
+        Self { fps, origin, next: 0, dropped: 0, duplicated: 0 }
+    }
+
+    /// Slots a frame captured at `timestamp` goes into, empty if it should be dropped.
+    pub fn slots(&mut self, timestamp: Instant) -> Range<u64> {
+        let origin = *self.origin.get_or_insert(timestamp);
+        let position = timestamp.saturating_duration_since(origin).as_secs_f64() * self.fps as f64;
+
+        // Anything less than a slot from the next empty one goes into it, so a source
+        // running at the output rate doesn't drop and repeat frames on every bit of
+        // jitter. Only gaps and bursts longer than a slot do.
+        let last = match position - self.next as f64 {
+            ahead if ahead > -1.0 && ahead < 1.0 => self.next,
+            _ => position.round() as u64,
+        };
+
+        let slots = self.next..(last + 1).max(self.next);
+        match slots.end - slots.start {
+            0 => self.dropped += 1,
+            n => self.duplicated += n - 1,
+        }
+
+        self.next = slots.end;
+        slots
+    }
+
+    /// Where slot 0 is, once there is one.
+    pub fn origin(&self) -> Option<Instant> {
+        self.origin
+    }
+
+    /// How far `slot` is from the origin.
+    pub fn time(&self, slot: u64) -> Duration {
+        Duration::from_nanos((slot as u128 * 1_000_000_000 / self.fps as u128) as u64)
+    }
+
+    /// Where the last filled slot ends, measured from the origin.
+    pub fn end(&self) -> Duration {
+        self.time(self.next)
+    }
+
+    /// Frames that landed in a slot that was already filled.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Extra copies written to fill slots nothing arrived for.
+    pub fn duplicated(&self) -> u64 {
+        self.duplicated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Timestamp `slots` slots after (or before) `origin` at 60fps
+    fn at(origin: Instant, slots: f64) -> Instant {
+        match Duration::from_secs_f64(slots.abs() / 60.0) {
+            offset if slots < 0.0 => origin - offset,
+            offset => origin + offset,
+        }
+    }
+
+    #[test]
+    fn jitter_under_a_slot_is_absorbed() {
+        let origin = Instant::now();
+        let mut grid = FrameGrid::new(60, Some(origin));
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+
+        for i in 0..1000u64 {
+            // Up to 0.45 of a slot either way, from a seeded xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let jitter = (state % 900) as f64 / 1000.0 - 0.45;
+
+            assert_eq!(grid.slots(at(origin, i as f64 + jitter)), i..i + 1, "frame {} off by {:.2} slots", i, jitter);
+        }
+
+        assert_eq!((grid.dropped(), grid.duplicated()), (0, 0));
+    }
+
+    #[test]
+    fn static_screen_repeats_the_last_frame() {
+        let origin = Instant::now();
+        let mut grid = FrameGrid::new(60, Some(origin));
+
+        assert_eq!(grid.slots(at(origin, 0.0)), 0..1);
+        assert_eq!(grid.slots(at(origin, 1.0)), 1..2);
+        // Nothing for 8 slots, then a frame at slot 10 fills everything up to it
+        assert_eq!(grid.slots(at(origin, 10.0)), 2..11);
+
+        assert_eq!((grid.dropped(), grid.duplicated()), (0, 8));
+    }
+
+    #[test]
+    fn burst_is_dropped() {
+        let origin = Instant::now();
+        let mut grid = FrameGrid::new(60, None);
+
+        assert_eq!(grid.slots(origin), 0..1);
+        // Three more at the same time, all for the slot already filled
+        for _ in 0..3 {
+            assert!(grid.slots(origin).is_empty());
+        }
+        assert_eq!(grid.slots(at(origin, 1.0)), 1..2);
+
+        assert_eq!((grid.dropped(), grid.duplicated()), (3, 0));
+    }
+
+    #[test]
+    fn slot_times_and_end() {
+        let origin = Instant::now();
+        let mut grid = FrameGrid::new(30, Some(origin));
+        assert_eq!((grid.time(0), grid.time(3), grid.time(30)), (Duration::ZERO, Duration::from_millis(100), Duration::from_secs(1)));
+        assert_eq!(FrameGrid::new(60, None).time(1), Duration::from_nanos(16_666_666));
+        assert_eq!(grid.end(), Duration::ZERO);
+
+        // The first frame half a second after a given origin fills the slots before it
+        assert_eq!(grid.slots(origin + Duration::from_millis(500)), 0..16);
+        assert_eq!(grid.origin(), Some(origin));
+        assert_eq!(grid.end(), Duration::from_nanos(533_333_333));
+    }
+}
@@ -1,48 +1,124 @@
-use anyhow::Error;
-
 use std::path::Path;
+use std::sync::mpsc::Sender;
 use std::time::Instant;
 use std::thread::JoinHandle;
 
 use crate::Encoder;
-
-use crabgrab::frame::VideoFrame;
+use crate::audio::{AudioBuffer, Timeline};
+use crate::encoder::{AudioCodec, AudioSettings, Codec, EncoderError, EncoderSettings, FrameTiming, Profile, RateControl};
+use crate::encoder::timing::FrameGrid;
+use crate::convert::{self, Conversion};
+use crate::frame::{Frame, PixelFormat};
 
 use windows::core::HSTRING;
+use windows::Win32::Foundation::E_POINTER;
 use windows::Foundation::{EventRegistrationToken, TimeSpan, TypedEventHandler};
 use windows::Storage::{FileAccessMode, StorageFile};
 use windows::Media::Transcoding::MediaTranscoder;
 use windows::Media::Core::{
-    MediaStreamSample, MediaStreamSource,
+    AudioStreamDescriptor, MediaStreamSample, MediaStreamSource,
     MediaStreamSourceSampleRequestedEventArgs, MediaStreamSourceStartingEventArgs,
     VideoStreamDescriptor,
 };
 use windows::Media::MediaProperties::{
-    ContainerEncodingProperties, MediaEncodingProfile,
+    AudioEncodingProperties, ContainerEncodingProperties, H264ProfileIds, MediaEncodingProfile,
     MediaEncodingSubtypes, VideoEncodingProperties,
 };
+use windows::Security::Cryptography::CryptographicBuffer;
 
 
 pub struct WmfEncoder {
     first_ts: Option<Instant>,
-    sample_tx: std::sync::mpsc::Sender<Option<MediaStreamSample>>,
+    // `None` with `FrameTiming::Vfr`, samples keep their capture time
+    grid: Option<FrameGrid>,
+    sample_tx: Sender<Option<MediaStreamSample>>,
+    audio: Option<AudioTrack>,
     sample_requested: EventRegistrationToken,
     media_stream_source: MediaStreamSource,
     starting: EventRegistrationToken,
-    transcode_thread: Option<JoinHandle<Result<(), Error>>>,
+    transcode_thread: Option<JoinHandle<Result<(), EncoderError>>>,
+    // Upside down copy of the latest frame, reused so there's no allocation per frame
+    flipped: Vec<u8>,
+    ignored: Vec<&'static str>,
+}
+
+// Second stream of the source, 16-bit PCM the transcoder encodes to AAC
+struct AudioTrack {
+    settings: AudioSettings,
+    timeline: Timeline,
+    tx: Sender<Option<MediaStreamSample>>,
+    // Samples per channel handed over so far, where the next sample starts
+    written: u64,
 }
 
 impl WmfEncoder {
-    pub fn init(height: f64, width: f64, output: &Path) -> Result<Self, Error> {
+    pub fn init(height: f64, width: f64, output: &Path, settings: &EncoderSettings) -> Result<Self, EncoderError> {
+        let mut ignored = Vec::new();
+
+        let subtype = match settings.codec {
+            Codec::H264 => "H264",
+            Codec::Hevc => "HEVC",
+            codec => return Err(EncoderError::UnsupportedCodec { backend: "Media Foundation", codec }),
+        };
+
+        // The transcoder only takes a target bitrate, quality modes aren't exposed
+        if !matches!(settings.rate_control, RateControl::Vbr) {
+            ignored.push("rate_control");
+        }
+
+        // Neither is GOP length or colour signalling
+        if settings.keyframe_interval.is_some() {
+            ignored.push("keyframe_interval");
+        }
+        ignored.push("color_space");
+        ignored.push("color_range");
+
+        // MediaTranscoder only writes regular MP4
+        if settings.fragment_duration.is_some() {
+            ignored.push("fragment_duration");
+        }
+
+        // The transcoder's MP4 only carries AAC, and its AAC encoder only a few rates
+        let audio = match settings.audio {
+            Some(audio) if audio.codec != AudioCodec::Aac => {
+                ignored.push("audio");
+                None
+            }
+            Some(audio) if !matches!(audio.sample_rate, 44_100 | 48_000) => {
+                return Err(EncoderError::UnsupportedSampleRate {
+                    backend: "Media Foundation",
+                    sample_rate: audio.sample_rate,
+                    supported: "44100 or 48000 Hz",
+                });
+            }
+            audio => audio,
+        };
+
+        // With an audio track both tracks start now rather than at their first
+        // frame, or they'd each start at zero from a different point in time
+        let origin = settings.start.or(audio.map(|_| Instant::now()));
+
+        // The transcoder has nowhere to take chapters from
+        if settings.chapters {
+            ignored.push("chapters");
+        }
 
         // Setup video properties
         let video_props = VideoEncodingProperties::new()?;
-        video_props.SetSubtype(&HSTRING::from("H264"))?;
-        video_props.SetBitrate(15_000_000)?;
+        video_props.SetSubtype(&HSTRING::from(subtype))?;
+        video_props.SetBitrate(settings.bitrate)?;
         video_props.SetWidth(width as u32)?;
         video_props.SetHeight(height as u32)?;
-        video_props.FrameRate()?.SetNumerator(60)?;
+        video_props.FrameRate()?.SetNumerator(settings.fps)?;
         video_props.FrameRate()?.SetDenominator(1)?;
+
+        match (settings.codec, settings.profile) {
+            (_, None) => {}
+            (Codec::H264, Some(Profile::Baseline)) => video_props.SetProfileId(H264ProfileIds::Baseline()?)?,
+            (Codec::H264, Some(Profile::Main)) => video_props.SetProfileId(H264ProfileIds::Main()?)?,
+            (Codec::H264, Some(Profile::High)) => video_props.SetProfileId(H264ProfileIds::High()?)?,
+            _ => ignored.push("profile"),
+        }
         video_props.PixelAspectRatio()?.SetNumerator(1)?;
         video_props.PixelAspectRatio()?.SetDenominator(1)?;
 
@@ -55,6 +131,17 @@ impl WmfEncoder {
         media_profile.SetVideo(&video_props)?;
         media_profile.SetContainer(&container_props)?;
 
+        if let Some(audio) = audio {
+            // The AAC encoder only offers these, anything else fails the transcode
+            let bitrate = [96_000, 128_000, 160_000, 192_000]
+                .into_iter()
+                .min_by_key(|rate: &u32| rate.abs_diff(audio.bitrate))
+                .unwrap_or(128_000);
+            let channels = audio.layout.channels() as u32;
+
+            media_profile.SetAudio(&AudioEncodingProperties::CreateAac(audio.sample_rate, channels, bitrate)?)?;
+        }
+
         // Here we create the "source" video props. Note the "uncompressed" tag + Bgra8 subtype.
         // NOTE: also has MJPEG, YUV, NV12 etc. interesting.
         let video_props_source = VideoEncodingProperties::CreateUncompressed(
@@ -63,9 +150,21 @@ impl WmfEncoder {
             video_props.Height()?,
         )?;
         let video_stream_descriptor = VideoStreamDescriptor::Create(&video_props_source)?;
-
-        // Create a media stream source and set the buffer time
-        let media_stream_source = MediaStreamSource::CreateFromDescriptor(&video_stream_descriptor)?;
+        video_stream_descriptor.SetName(&HSTRING::from("video"))?;
+
+        // Create a media stream source and set the buffer time. Samples are asked
+        // for per stream, the names tell the handler which one it's for
+        let media_stream_source = match audio {
+            Some(audio) => {
+                let channels = audio.layout.channels() as u32;
+                let pcm = AudioEncodingProperties::CreatePcm(audio.sample_rate, channels, 16)?;
+                let audio_stream_descriptor = AudioStreamDescriptor::Create(&pcm)?;
+                audio_stream_descriptor.SetName(&HSTRING::from("audio"))?;
+
+                MediaStreamSource::CreateFromDescriptors(&video_stream_descriptor, &audio_stream_descriptor)?
+            }
+            None => MediaStreamSource::CreateFromDescriptor(&video_stream_descriptor)?,
+        };
         media_stream_source.SetBufferTime(TimeSpan::default())?;
 
         let starting = media_stream_source.Starting(&TypedEventHandler::<
@@ -74,7 +173,7 @@ impl WmfEncoder {
         >::new(move |_, stream_start| {
             let stream_start = stream_start
                 .as_ref()
-                .expect("how tf this none?");
+                .ok_or_else(|| windows::core::Error::from(E_POINTER))?;
 
             stream_start
                 .Request()?
@@ -84,42 +183,47 @@ impl WmfEncoder {
 
         let (sample_tx, sample_rx) =
             std::sync::mpsc::channel::<Option<MediaStreamSample>>();
+        let (audio_tx, audio_rx) =
+            std::sync::mpsc::channel::<Option<MediaStreamSample>>();
 
         let sample_requested = media_stream_source.SampleRequested(&TypedEventHandler::<
             MediaStreamSource,
             MediaStreamSourceSampleRequestedEventArgs,
         >::new({
             let sample_rx = sample_rx;
+            let audio_rx = audio_rx;
 
             move |_, sample_requested| {
-                let sample_requested = sample_requested.as_ref().expect("how tf this none?");
-
-                println!("Sample requested, waiting for sample...");
-
-                let result = sample_rx.recv().unwrap();
+                let sample_requested = sample_requested
+                    .as_ref()
+                    .ok_or_else(|| windows::core::Error::from(E_POINTER))?;
+                let request = sample_requested.Request()?;
+                let stream = request.StreamDescriptor()?.Name()?;
+
+                tracing::trace!("{} sample requested", stream);
+
+                // A closed channel means the encoder is gone, so end the stream
+                let result = match stream == "audio" {
+                    true => audio_rx.recv().ok().flatten(),
+                    false => sample_rx.recv().ok().flatten(),
+                };
 
                 match result {
-                    Some(sample) => {
-                        println!("Processing sample");
-                            sample_requested.Request()?.SetSample(&sample)?;
-                        }
-                        None => {
-                            println!("received end-of-stream signal");
-                            sample_requested.Request()?.SetSample(None)?;
-                        }
-                    }
-                
+                    Some(sample) => request.SetSample(&sample)?,
+                    None => request.SetSample(None)?,
+                }
 
                 Ok(())
             }
         }))?;
 
         // Set up file for writing into
-        std::fs::File::create(&output)?;
-        let path = std::fs::canonicalize(&output).unwrap().to_string_lossy()[4..].to_string();
-        let path = Path::new(&path);
+        super::create_output(&output, settings)?;
+        let path = std::fs::canonicalize(output)?;
 
-        let path = &HSTRING::from(path.as_os_str().to_os_string());
+        // StorageFile doesn't take the \\?\ prefix canonicalize puts on
+        let path = path.to_string_lossy();
+        let path = &HSTRING::from(path.strip_prefix(r"\\?\").unwrap_or(&*path));
 
         let file = StorageFile::GetFileFromPathAsync(path)?.get()?;
         let media_stream_output = file.OpenAsync(FileAccessMode::ReadWrite)?.get()?;
@@ -137,109 +241,160 @@ impl WmfEncoder {
             .get()?;
 
         let transcode_thread = std::thread::spawn({
-            move || -> Result<(), Error> {
-                println!("Starting transcoding...");    
-                let transcode_async = transcode.TranscodeAsync()?;
-                println!("TranscodeAsync called");
-                
-                match transcode_async.get() {
-                    Ok(_) => println!("Transcoding completed successfully"),
-                    Err(e) => {
-                        println!("Transcoding failed: {:?}", e);
-                        return Err(e.into());
-                    }
-                }
-                
+            move || -> Result<(), EncoderError> {
+                tracing::debug!("starting transcode");
+                transcode.TranscodeAsync()?.get()?;
+                tracing::debug!("transcode finished");
+
                 Ok(())
             }
         });
 
         Ok(Self {
-            first_ts: None,
+            first_ts: origin,
+            grid: match settings.timing {
+                FrameTiming::Cfr => Some(FrameGrid::new(settings.fps, origin)),
+                FrameTiming::Vfr => None,
+            },
             sample_tx,
+            audio: match (audio, origin) {
+                (Some(audio), Some(origin)) => Some(AudioTrack {
+                    settings: audio,
+                    timeline: Timeline::new(origin, audio.sample_rate, audio.layout),
+                    tx: audio_tx,
+                    written: 0,
+                }),
+                _ => None,
+            },
             sample_requested,
             media_stream_source,
             starting,
             transcode_thread: Some(transcode_thread),
+            flipped: Vec::new(),
+            ignored,
         })
     }
 }
 
 
 impl Encoder for WmfEncoder {
-    fn append_frame(&mut self, frame: VideoFrame) -> Result<(), anyhow::Error> {
-        // Process timestamp
-        let ts = frame.capture_time();
-        if self.first_ts.is_none() {
-            self.first_ts = Some(ts)
+    fn append_frame(&mut self, frame: Frame) -> Result<(), EncoderError> {
+        // Process timestamp, on the grid the frame goes in once per slot at the slot's time
+        let ts = frame.timestamp;
+        let ts_deltas: Vec<_> = match &mut self.grid {
+            Some(grid) => {
+                let slots = grid.slots(ts);
+                slots.map(|slot| grid.time(slot)).collect()
+            }
+            None => {
+                let first_ts = *self.first_ts.get_or_insert(ts);
+                vec![ts.duration_since(first_ts)]
+            }
+        };
+        if ts_deltas.is_empty() {
+            return Ok(());
         }
 
-        // TOCHECK: this might be wrong, need to double check
-        let ts_delta = ts.duration_since(self.first_ts.unwrap());
-        let ts_delta_nanos = ts_delta.as_nanos() as i64;
-
-        let timespan = TimeSpan { Duration: ts_delta_nanos / 100 };
-
         // Create a MediaStreamSample from D3DSurface
         // use crabgrab::feature::dx11::WindowsDx11VideoFrame;
 
-        // let (dx11_surface, _) = frame.get_dx11_surface()?;
+        // let (dx11_surface, _) = frame.native.unwrap().get_dx11_surface()?;
         // let media_sample = MediaStreamSample::CreateFromDirect3D11Surface(&dx11_surface, timespan)?;
 
         // Alt: create MediaStreamSample from Buffer
-        use crabgrab::feature::bitmap::{VideoFrameBitmap, FrameBitmap, FrameBitmapBgraUnorm8x4};
-        use windows::Security::Cryptography::CryptographicBuffer;
-
-        let media_sample = match frame.get_bitmap()? {
-            FrameBitmap::BgraUnorm8x4(bgra_frame) => {
-
-                let FrameBitmapBgraUnorm8x4 { height, width, data} = bgra_frame;
-
-                let flipped_buf = {
-                    let mut flipped = Vec::with_capacity(data.len());
-                    for row in (0..height).rev() { 
-                        let start = row * width;
-                        let end = start + width;
-                        flipped.extend_from_slice(&data[start..end]);
-                    }
-                    flipped
-                };
+        // Uncompressed Bgra8 is bottom-up, so flip rows on the way in
+        let to = Conversion::to(PixelFormat::Bgra, &frame);
+        let frame = convert::convert(frame, to)?;
+        convert::flip_vertical(&frame, &mut self.flipped)?;
 
-                let buf = flipped_buf.as_flattened();
+        for ts_delta in ts_deltas {
+            // TOCHECK: this might be wrong, need to double check
+            let timespan = TimeSpan { Duration: ts_delta.as_nanos() as i64 / 100 };
 
-                let buffer = CryptographicBuffer::CreateFromByteArray(&buf)?;
-                MediaStreamSample::CreateFromBuffer(&buffer, timespan)?
-            },
-            _ => unimplemented!("windows encoder no support this px format"),
-        };
+            let buffer = CryptographicBuffer::CreateFromByteArray(&self.flipped)?;
+            let media_sample = MediaStreamSample::CreateFromBuffer(&buffer, timespan)?;
 
-        self.sample_tx.send(Some(media_sample)).expect("couldn't send sample");
-        println!("sample sent to encoder w ts: {}", ts_delta_nanos);
+            self.sample_tx
+                .send(Some(media_sample))
+                .map_err(|_| EncoderError::ChannelClosed("Media Foundation transcoder"))?;
+        }
 
         Ok(())
     }
 
-    fn finish(&mut self) -> Result<(), anyhow::Error> {
-        println!("Finishing encoder...");
+    fn append_audio(&mut self, audio: AudioBuffer) -> Result<(), EncoderError> {
+        let track = self.audio.as_mut().ok_or(EncoderError::NoAudioTrack)?;
+
+        let expected = (track.settings.sample_rate, track.settings.layout.channels());
+        let got = (audio.sample_rate, audio.layout.channels());
+        if got != expected {
+            return Err(EncoderError::AudioFormatMismatch { expected, got });
+        }
+
+        let samples = track.timeline.place(&audio);
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        let pcm: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| ((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())
+            .collect();
+
+        // Times come from the sample count so rounding never adds up to a gap
+        let rate = track.settings.sample_rate as u64;
+        let at = |samples: u64| TimeSpan { Duration: (samples * 10_000_000 / rate) as i64 };
+        let start = track.written;
+        track.written += (samples.len() / expected.1) as u64;
 
-        // Send empty sample
-        self.sample_tx.send(None).expect("couldn't send no-op");
+        let buffer = CryptographicBuffer::CreateFromByteArray(&pcm)?;
+        let sample = MediaStreamSample::CreateFromBuffer(&buffer, at(start))?;
+        sample.SetDuration(TimeSpan { Duration: at(track.written).Duration - at(start).Duration })?;
+
+        track.tx
+            .send(Some(sample))
+            .map_err(|_| EncoderError::ChannelClosed("Media Foundation transcoder"))
+    }
+
+    fn ignored_settings(&self) -> &[&'static str] {
+        &self.ignored
+    }
+
+    fn dropped_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::dropped)
+    }
+
+    fn duplicated_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::duplicated)
+    }
+
+    fn finish(&mut self) -> Result<(), EncoderError> {
+        // Send empty samples. If the transcoder is already gone its thread has the reason
+        let sent = self.sample_tx.send(None);
+        if let Some(track) = &self.audio {
+            track.tx.send(None).ok();
+        }
 
         // Conclude transcode thread
-        println!("Waiting for transcode thread...");
         if let Some(transcode_thread) = self.transcode_thread.take() {
             transcode_thread
                 .join()
-                .expect("Failed to join transcode thread")?;
+                .map_err(|_| EncoderError::backend("Media Foundation", "transcode thread panicked"))??;
         }
 
+        sent.map_err(|_| EncoderError::ChannelClosed("Media Foundation transcoder"))?;
+
         // Close out stream source
         self.media_stream_source.RemoveStarting(self.starting)?;
         self.media_stream_source
             .RemoveSampleRequested(self.sample_requested)?;
 
-        println!("Encoder finished successfully");
-
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl From<windows::core::Error> for EncoderError {
+    fn from(e: windows::core::Error) -> Self {
+        EncoderError::backend("Media Foundation", e)
+    }
+}
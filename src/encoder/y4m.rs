@@ -0,0 +1,187 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::{Encoder, EncoderError};
+use super::settings::{EncoderSettings, FrameTiming};
+use super::timing::FrameGrid;
+use crate::convert::{self, Conversion};
+use crate::frame::{ColorRange, Frame, PixelFormat};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Container {
+    /// YUV4MPEG2 stream of I420 frames, or I444 if that's what came in
+    Y4m,
+    /// Headerless I420 frames
+    RawI420,
+    /// Headerless frames in whatever packed/planar layout they arrived in
+    RawPassthrough,
+}
+
+/// Lossless writer for bit-exact dumps of what the capturer produced. Picks the
+/// layout from the file extension: `.y4m`, `.yuv` (raw I420) or anything else
+/// (raw passthrough, e.g. `.bgra`).
+pub struct Y4mEncoder {
+    file: BufWriter<File>,
+    container: Container,
+    width: usize,
+    height: usize,
+    // Y4M has no per-frame timestamps, frames are written back to back at this rate
+    fps: u32,
+    // With `FrameTiming::Cfr` frames are repeated and dropped so they play back at
+    // the speed they were captured, `None` writes every frame once
+    grid: Option<FrameGrid>,
+    // Format and range of the first frame, every later frame has to match
+    input: Option<(PixelFormat, ColorRange)>,
+    ignored: Vec<&'static str>,
+}
+
+impl Y4mEncoder {
+    pub fn init(height: f64, width: f64, output: &Path, settings: &EncoderSettings) -> Result<Self, EncoderError> {
+        let container = match output.extension().and_then(|ext| ext.to_str()) {
+            Some("y4m") => Container::Y4m,
+            Some("yuv") => Container::RawI420,
+            _ => Container::RawPassthrough,
+        };
+
+        tracing::debug!(?container, "writing lossless output");
+        let file = super::create_output(output, settings)?;
+
+        // Nothing gets compressed, and the colour of the output follows the input
+        let mut ignored = vec!["codec", "rate_control", "bitrate", "keyframe_interval", "profile", "color_space", "color_range", "fragment_duration"];
+
+        // Frames go back to back, only the grid can line them up with anything
+        let grid = match settings.timing {
+            FrameTiming::Cfr => Some(FrameGrid::new(settings.fps, settings.start)),
+            FrameTiming::Vfr => {
+                ignored.push("timing");
+                if settings.start.is_some() {
+                    ignored.push("start");
+                }
+                None
+            }
+        };
+
+        // Y4M and raw dumps are video only
+        if settings.audio.is_some() {
+            ignored.push("audio");
+        }
+        if settings.chapters {
+            ignored.push("chapters");
+        }
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            container,
+            width: width as usize,
+            height: height as usize,
+            fps: settings.fps,
+            grid,
+            input: None,
+            ignored,
+        })
+    }
+
+    fn write_header(&mut self, format: PixelFormat, range: ColorRange) -> Result<(), EncoderError> {
+        // RGB gets averaged over each 2x2 block (centred chroma), 4:2:0 from
+        // capturers is left-sited like MPEG-2
+        let chroma = match format {
+            PixelFormat::Bgra | PixelFormat::Rgba => "420jpeg",
+            PixelFormat::Nv12 | PixelFormat::I420 | PixelFormat::P010 => "420mpeg2",
+            PixelFormat::I444 => "444",
+        };
+
+        let range = match range {
+            ColorRange::Limited => "LIMITED",
+            ColorRange::Full => "FULL",
+        };
+
+        writeln!(
+            self.file,
+            "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C{} XCOLORRANGE={}",
+            self.width, self.height, self.fps, chroma, range
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Encoder for Y4mEncoder {
+    fn append_frame(&mut self, frame: Frame) -> Result<(), EncoderError> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(EncoderError::FrameSizeMismatch {
+                expected: (self.width, self.height),
+                got: (frame.width, frame.height),
+            });
+        }
+
+        let copies = match &mut self.grid {
+            Some(grid) => {
+                let slots = grid.slots(frame.timestamp);
+                slots.end - slots.start
+            }
+            None => 1,
+        };
+        if copies == 0 {
+            return Ok(());
+        }
+
+        // P010 loses its extra bits, everything but I444 ends up as I420
+        let out_format = match (self.container, frame.format) {
+            (Container::RawPassthrough, format) => format,
+            (Container::Y4m, PixelFormat::I444) => PixelFormat::I444,
+            _ => PixelFormat::I420,
+        };
+        let to = Conversion::to(out_format, &frame);
+
+        match self.input {
+            None => {
+                if self.container == Container::Y4m {
+                    self.write_header(frame.format, to.range)?;
+                }
+                self.input = Some((frame.format, to.range));
+            }
+            Some(input) if input != (frame.format, to.range) => {
+                return Err(EncoderError::UnsupportedFrame {
+                    backend: "Y4M writer",
+                    reason: "format or range changed mid-stream",
+                });
+            }
+            _ => {}
+        }
+
+        let frame = convert::convert(frame, to)?;
+
+        for _ in 0..copies {
+            if self.container == Container::Y4m {
+                self.file.write_all(b"FRAME\n")?;
+            }
+
+            for (plane, (row_len, rows)) in frame.planes.iter().zip(frame.format.plane_sizes(frame.width, frame.height)) {
+                for row in plane.data.chunks(plane.stride).take(rows) {
+                    self.file.write_all(&row[..row_len])?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ignored_settings(&self) -> &[&'static str] {
+        &self.ignored
+    }
+
+    fn dropped_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::dropped)
+    }
+
+    fn duplicated_frames(&self) -> u64 {
+        self.grid.as_ref().map_or(0, FrameGrid::duplicated)
+    }
+
+    fn finish(&mut self) -> Result<(), EncoderError> {
+        self.file.flush()?;
+
+        Ok(())
+    }
+}
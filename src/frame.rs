@@ -0,0 +1,161 @@
+use anyhow::Error;
+use std::str::FromStr;
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Packed 8-bit B, G, R, A
+    Bgra,
+    /// Packed 8-bit R, G, B, A
+    Rgba,
+    /// 8-bit Y plane followed by an interleaved, 2x2 subsampled UV plane
+    Nv12,
+    /// 8-bit Y, U and V planes, U and V 2x2 subsampled
+    I420,
+    /// 8-bit Y, U and V planes at full resolution
+    I444,
+    /// Like NV12 with 16-bit little-endian samples, 10 bits used from the top
+    P010,
+}
+
+impl PixelFormat {
+    pub fn is_rgb(self) -> bool {
+        matches!(self, PixelFormat::Bgra | PixelFormat::Rgba)
+    }
+
+    /// Bytes per row and number of rows of each plane, without any padding.
+    /// Subsampled planes round odd sizes up.
+    pub fn plane_sizes(self, width: usize, height: usize) -> Vec<(usize, usize)> {
+        let (cw, ch) = (width.div_ceil(2), height.div_ceil(2));
+
+        match self {
+            PixelFormat::Bgra | PixelFormat::Rgba => vec![(width * 4, height)],
+            PixelFormat::Nv12 => vec![(width, height), (cw * 2, ch)],
+            PixelFormat::I420 => vec![(width, height), (cw, ch), (cw, ch)],
+            PixelFormat::I444 => vec![(width, height); 3],
+            PixelFormat::P010 => vec![(width * 2, height), (cw * 4, ch)],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    Srgb,
+    Bt709,
+}
+
+impl FromStr for PixelFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "bgra" => Ok(PixelFormat::Bgra),
+            "rgba" => Ok(PixelFormat::Rgba),
+            "nv12" => Ok(PixelFormat::Nv12),
+            "i420" | "yuv420p" => Ok(PixelFormat::I420),
+            "i444" | "yuv444p" => Ok(PixelFormat::I444),
+            "p010" => Ok(PixelFormat::P010),
+            _ => Err(Error::msg(format!("Unknown pixel format: {}", s))),
+        }
+    }
+}
+
+/// How YUV formats were derived from RGB.
+// Nothing we capture is BT.601 yet, but `convert` handles it for sources that are
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Matrix {
+    Bt601,
+    Bt709,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorRange {
+    Limited,
+    Full,
+}
+
+pub struct Plane {
+    pub data: Vec<u8>,
+    /// Bytes from the start of one row to the start of the next, may include padding
+    pub stride: usize,
+}
+
+/// A rectangle in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// A video frame owned by this crate, so encoders don't care whether it came
+/// from crabgrab, X11, a generator or a file.
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    pub color_space: ColorSpace,
+    pub color_range: ColorRange,
+    /// Only meaningful for YUV formats
+    pub matrix: Matrix,
+    pub planes: Vec<Plane>,
+    pub timestamp: Instant,
+
+    /// The frame crabgrab handed us, kept around so backends can reach the
+    /// platform surface (CMSampleBuffer, D3D11 texture) without a copy.
+    #[cfg(not(target_os = "linux"))]
+    pub native: Option<crabgrab::frame::VideoFrame>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize, format: PixelFormat, planes: Vec<Plane>, timestamp: Instant) -> Self {
+        let (color_space, color_range) = match format.is_rgb() {
+            true => (ColorSpace::Srgb, ColorRange::Full),
+            false => (ColorSpace::Bt709, ColorRange::Limited),
+        };
+
+        Self {
+            width,
+            height,
+            format,
+            color_space,
+            color_range,
+            matrix: Matrix::Bt709,
+            planes,
+            timestamp,
+            #[cfg(not(target_os = "linux"))]
+            native: None,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl TryFrom<crabgrab::frame::VideoFrame> for Frame {
+    type Error = anyhow::Error;
+
+    fn try_from(source: crabgrab::frame::VideoFrame) -> Result<Self, Self::Error> {
+        use crabgrab::feature::bitmap::{FrameBitmapBgraUnorm8x4, FrameBitmapYCbCr, VideoFrameBitmap};
+        use crabgrab::prelude::FrameBitmap::{BgraUnorm8x4, YCbCr};
+
+        let timestamp = source.capture_time();
+
+        let mut frame = match source.get_bitmap()? {
+            BgraUnorm8x4(FrameBitmapBgraUnorm8x4 { width, height, data }) => {
+                let plane = Plane { data: data.as_flattened().to_vec(), stride: width * 4 };
+                Frame::new(width, height, PixelFormat::Bgra, vec![plane], timestamp)
+            }
+            YCbCr(FrameBitmapYCbCr { luma_data, luma_width, luma_height, chroma_data, chroma_width, .. }) => {
+                let y = Plane { data: luma_data.to_vec(), stride: luma_width };
+                let uv = Plane { data: chroma_data.as_flattened().to_vec(), stride: chroma_width * 2 };
+                Frame::new(luma_width, luma_height, PixelFormat::Nv12, vec![y, uv], timestamp)
+            }
+            _ => return Err(anyhow::Error::msg("Unsupported crabgrab bitmap format")),
+        };
+
+        frame.native = Some(source);
+
+        Ok(frame)
+    }
+}
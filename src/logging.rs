@@ -0,0 +1,40 @@
+use anyhow::Error;
+
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::prelude::*;
+
+/// Logs to stderr at `level` (or whatever `RUST_LOG` says), and, when `json_file`
+/// is given, everything down to debug as JSON lines into that file.
+pub fn init(level: LevelFilter, json_file: Option<&Path>) -> Result<(), Error> {
+    let console_filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+
+    let console = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .with_filter(console_filter);
+
+    // The file is for bug reports, so it gets more detail than the console by default
+    let json = match json_file {
+        Some(path) => Some(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_writer(Mutex::new(File::create(path)?))
+                .with_filter(level.max(LevelFilter::DEBUG)),
+        ),
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(json)
+        .try_init()
+        .map_err(|e| Error::msg(format!("Failed to set up logging: {}", e)))
+}
@@ -1,77 +1,621 @@
+mod audio;
+mod capture;
+mod cli;
+mod clock;
+mod convert;
 mod encoder;
+mod frame;
+mod logging;
+mod queue;
+mod scale;
+mod segment;
+mod stats;
 
 use anyhow::Error;
+use clap::Parser;
+use std::path::PathBuf;
 use std::sync::mpsc;
-use std::path::Path;
-use encoder::{Encoder, VideoEncoder};
-use pollster::FutureExt;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, trace_span, warn};
 
-use crabgrab::capturable_content::{CapturableContent, CapturableContentFilter};
-use crabgrab::capture_stream::{CaptureConfig, CaptureStream, StreamEvent, CapturePixelFormat};
+use audio::AudioBuffer;
+use capture::{MultiDisplay, PreparedCapture, RunningCapture, Target, WindowSelector};
+use cli::{Cli, Command, RecordArgs};
+use clock::SessionClock;
+use encoder::{AudioSettings, Av1Options, Backend, Encoder, EncoderError, EncoderSettings};
+use queue::FrameQueue;
+use scale::{Filter, Scaler};
+use segment::ResizePolicy;
+use stats::{RecordingStats, Stats};
 
-// Variables to configure the stream
-// Encoder configs are in the ./encoder folder
-const STREAM_PX_FMT: CapturePixelFormat = CapturePixelFormat::Bgra8888;
-const SCALE_FACTOR: f64 = 1.0; // NOTE: on macbooks this can be 2.0
-const OUTPUT_FILE: &str = "./video.mp4";
+// A second Ctrl-C/SIGTERM this soon after the first skips finalizing and exits
+const FORCE_QUIT_WINDOW: Duration = Duration::from_secs(3);
 
 fn main() -> Result<(), Error> {
+    let cli = Cli::parse();
+    logging::init(cli.log_level, cli.log_json.as_deref())?;
+
+    match cli.command {
+        Command::List => list(),
+        Command::Record(args) => record(*args),
+    }
+}
+
+fn list() -> Result<(), Error> {
+    for source in capture::list()? {
+        let owner = match (&source.app, source.pid) {
+            (Some(app), Some(pid)) => format!("  [{}, pid {}]", app, pid),
+            (Some(app), None) => format!("  [{}]", app),
+            _ => String::new(),
+        };
+
+        println!(
+            "{:<8} {:>10}  {}x{} at {},{}  {}{}",
+            source.kind, source.id, source.width, source.height, source.x, source.y, source.name, owner
+        );
+    }
+
+    Ok(())
+}
+
+fn record(args: RecordArgs) -> Result<(), Error> {
 
     // MARK: Configure Stream
-    let content = CapturableContent::new(CapturableContentFilter::DISPLAYS).block_on()?;
-    let display = content.displays().next().ok_or(Error::msg("No displays found"))?;
+    let window = match (&args.window, &args.window_app, args.window_id) {
+        (Some(title), _, _) => Some(WindowSelector::Title(title.clone())),
+        (_, Some(app), _) => Some(WindowSelector::App(app.clone())),
+        (_, _, Some(id)) => Some(WindowSelector::Id(id)),
+        _ => None,
+    };
+    let is_window = window.is_some();
 
-    let size = display.rect().scaled(SCALE_FACTOR).size; // Hardcoded to 2 (as scale factor)
-    let height = size.height;
-    let width = size.width;
+    let displays: Vec<usize> = match args.all_displays {
+        true => capture::list()?
+            .into_iter()
+            .filter(|source| source.kind == "display")
+            .map(|source| source.id as usize)
+            .collect(),
+        false => args.display.clone(),
+    };
+    let separate = displays.len() > 1 && args.multi_display == MultiDisplay::Separate;
 
-    let stream_cfg = CaptureConfig::with_display(display, STREAM_PX_FMT, None)
-        .with_color_space_name("kCGColorSpaceSRGB".to_string())
-        .with_output_size(size);
+    if separate && args.region.is_some() {
+        return Err(Error::msg("--region picks part of a single display, it doesn't work with --multi-display separate"));
+    }
 
-    let stream_token =
-    CaptureStream::test_access(false).ok_or(Error::msg("Failed to get access token"))?;
+    // Every capture gets an output file of its own
+    let mut captures = match (window, args.test_pattern) {
+        (Some(selector), _) => vec![(args.output.clone(), capture::prepare(&Target::Window(selector), args.scale)?)],
+        (None, true) => vec![(args.output.clone(), capture::prepare(&Target::TestPattern(args.pattern_format), args.scale)?)],
+        (None, false) if separate => displays
+            .iter()
+            .map(|&index| Ok((segment::display_path(&args.output, index), capture::prepare(&Target::Display(Some(index)), args.scale)?)))
+            .collect::<Result<Vec<_>, Error>>()?,
+        (None, false) if displays.len() > 1 => vec![(args.output.clone(), capture::prepare_composite(&displays, args.scale)?)],
+        (None, false) => vec![(args.output.clone(), capture::prepare(&Target::Display(displays.first().copied()), args.scale)?)],
+    };
+
+    if let Some(region) = args.region {
+        captures = captures
+            .into_iter()
+            .map(|(output, capture)| Ok((output, capture.with_region(region)?)))
+            .collect::<Result<_, Error>>()?;
+    }
+
+    // MARK: Configure Scaling
+    let (resolution, fit, filter) = (args.resolution, args.fit, args.filter);
+    let scaler_for = move |input: (usize, usize)| {
+        resolution
+            .map(|resolution| Scaler::new(input, resolution, fit, filter))
+            .filter(|scaler| !scaler.is_identity())
+    };
 
     // MARK: Configure Encoder
-    let output = Path::new(OUTPUT_FILE);
-    let mut encoder = VideoEncoder::init(height, width, output)?;
+    #[cfg(feature = "ffmpeg")]
+    let backend = match (args.backend, &args.ffmpeg_encoder) {
+        (None, Some(_)) => Backend::Ffmpeg,
+        (Some(backend), Some(_)) if backend != Backend::Ffmpeg => {
+            return Err(Error::msg("--ffmpeg-encoder only works with the ffmpeg backend"));
+        }
+        (backend, _) => backend.unwrap_or(Backend::from_path(&args.output)),
+    };
+    #[cfg(not(feature = "ffmpeg"))]
+    let backend = args.backend.unwrap_or(Backend::from_path(&args.output));
+
+    // AVAssetWriter only takes the capturer's own sample buffers, --scale is the way to shrink those
+    if cfg!(target_os = "macos") && backend == Backend::Native {
+        let (_, capture) = &captures[0];
+        if scaler_for((capture.width, capture.height)).is_some() {
+            return Err(Error::msg("--resolution doesn't work with the native macOS encoder, use --scale or another backend"));
+        }
+        if args.region.is_some() {
+            return Err(Error::msg("--region doesn't work with the native macOS encoder yet, use another backend"));
+        }
+        if capture.layout().is_some() {
+            return Err(Error::msg("Composited displays don't work with the native macOS encoder, use another backend"));
+        }
+    }
+    let mut settings = EncoderSettings {
+        codec: args.codec,
+        rate_control: args.rate_control,
+        bitrate: args.bitrate,
+        fps: args.fps,
+        timing: args.timing,
+        keyframe_interval: args.keyframe_interval,
+        profile: args.profile,
+        fragment_duration: args.fragment.map(Duration::from_secs_f64),
+        // Pauses are marked as chapters, so only recordings that can be paused ask for them
+        chapters: args.pausable,
+        overwrite: args.overwrite,
+        av1: Av1Options { speed: args.av1_speed, tiles: args.av1_tiles },
+        #[cfg(feature = "ffmpeg")]
+        ffmpeg_encoder: args.ffmpeg_encoder.clone(),
+        ..EncoderSettings::default()
+    };
+
+    // MARK: Configure Audio
+    let audio_source = args.audio.as_ref().map(|input| input.open()).transpose()?;
+    settings.audio = audio_source.as_ref().map(|source| AudioSettings {
+        codec: args.audio_codec,
+        sample_rate: source.sample_rate(),
+        layout: source.layout(),
+        bitrate: args.audio_bitrate,
+    });
+
+    if args.dry_run {
+        for (output, capture) in &captures {
+            println!("source:   {}", capture.description);
+            println!("size:     {}x{}", capture.width, capture.height);
+            if let Some(layout) = capture.layout() {
+                println!("layout:   {}", layout);
+            }
+            if let Some(scaler) = scaler_for((capture.width, capture.height)) {
+                println!("scaling:  {}", scaler);
+            }
+            println!("output:   {}", output.display());
+        }
+        if is_window {
+            println!("resize:   {:?}", args.on_resize);
+        }
+        if let (Some(input), Some(audio)) = (&args.audio, &settings.audio) {
+            println!("audio:    {}, {} Hz {:?}", input, audio.sample_rate, audio.layout);
+        }
+        println!("backend:  {:?}", backend);
+        println!("duration: {}", args.duration.map_or("until Ctrl-C".to_string(), |d| format!("{}s", d)));
+        println!("{:#?}", settings);
+        return Ok(());
+    }
+
+    // Every source is stamped against one clock and every output starts at its
+    // origin, so the same moment has the same timestamp in all of them
+    let clock = SessionClock::new();
+    settings.start = Some(clock.origin());
+
+    // MARK: Handle Ctrl-C / SIGTERM
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let last_signal = Mutex::new(None::<Instant>);
+    let output = args.output.clone();
+    ctrlc::set_handler(move || {
+        let mut last_signal = last_signal.lock().unwrap();
 
-    let (tx, rx) = mpsc::channel();
+        if last_signal.is_some_and(|at| at.elapsed() < FORCE_QUIT_WINDOW) {
+            warn!(output = %output.display(), "quitting without finalizing the output");
+            std::process::exit(130);
+        }
+
+        *last_signal = Some(Instant::now());
+        info!("finishing recording, interrupt again to quit immediately");
+        stop_tx.send(()).ok();
+    })?;
 
-    let handle = std::thread::spawn(move || {
-        while let Ok(Some(frame)) = rx.recv() {
-            encoder.append_frame(frame).expect("couldn't encode frame");
+    // MARK: Start streams
+    // Audio goes to the first output only
+    let (audio_tx, audio_rx) = mpsc::channel();
+    let mut audio_rx = audio_source.as_ref().map(|_| audio_rx);
+
+    let mut pipelines = Vec::with_capacity(captures.len());
+    for (output, capture) in captures {
+        let scaler = scaler_for((capture.width, capture.height));
+
+        match Pipeline::start(&args, output, capture, scaler, scaler_for, backend, &settings, &clock, audio_rx.take()) {
+            Ok(pipeline) => pipelines.push(pipeline),
+            Err(e) => {
+                // Still finish the outputs that did start so they're playable
+                for mut pipeline in pipelines {
+                    pipeline.stop_capture();
+                    pipeline.finish().ok();
+                }
+                return Err(e);
+            }
         }
+    }
 
-        encoder.finish().expect("couldn't finish encoding");
+    let audio = audio_source.map(|source| {
+        let source_clock = clock.register("audio");
+        audio::start(source, move |mut buffer| {
+            if let Some(timestamp) = source_clock.stamp(buffer.timestamp, Instant::now()) {
+                buffer.timestamp = timestamp;
+                audio_tx.send(buffer).ok();
+            }
+        })
     });
 
-    // MARK: Start stream
-    let mut stream = CaptureStream::new(stream_token, stream_cfg, move |result| match result {
-        Ok(event) => match event {
-            StreamEvent::Video(frame) => {
-                println!("got new frame");
-                tx.send(Some(frame)).expect("couldn't send frame");
-            },
-            StreamEvent::End => match tx.send(None) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Error sending end-of-stream signal: {}", e);
+    // MARK: Pause and resume with Enter
+    if settings.chapters {
+        let clock = clock.clone();
+        std::thread::spawn(move || {
+            for _ in std::io::stdin().lines() {
+                if !clock.is_paused() {
+                    clock.pause();
+                    info!("paused, press Enter to resume");
+                } else if let Some(paused) = clock.resume() {
+                    info!(paused_for = ?paused, "resumed");
                 }
-            },
-            _ => {}
-        },
-        Err(e) => eprintln!("Error: {}", e),
-    })?;
+            }
+        });
+    }
 
+    // MARK: Record until the duration runs out or Ctrl-C, then stop
+    match args.duration {
+        Some(secs) => {
+            stop_rx.recv_timeout(Duration::from_secs_f64(secs)).ok();
+        }
+        None => {
+            match settings.chapters {
+                true => println!("recording, press Enter to pause or resume, Ctrl-C to stop"),
+                false => println!("recording, press Ctrl-C to stop"),
+            }
+            stop_rx.recv().ok();
+        }
+    }
 
-    // MARK: Record for 3 seconds, then stop
-    std::thread::sleep(std::time::Duration::from_secs(3));
-    stream.stop()?;
+    // Every stream stops before any encoder is finished, so the outputs end together
+    if let Some(Err(e)) = audio.map(|audio| audio.stop()) {
+        error!("Error stopping audio: {}", e);
+    }
+    for pipeline in &mut pipelines {
+        pipeline.stop_capture();
+    }
 
-    handle.join().expect("couldn't complete encoding thread");
+    let count = pipelines.len();
+    let mut result = Ok(());
+    let mut finished = Vec::with_capacity(count);
+    for pipeline in pipelines {
+        let output = pipeline.output.clone();
+        match pipeline.finish() {
+            Ok(stats) => finished.push((output, stats)),
+            Err(e) => {
+                error!(output = %output.display(), "Error finishing recording: {}", e);
+                result = Err(e);
+            }
+        }
+    }
+    result?;
 
     println!("finished!");
+    for (output, stats) in finished {
+        if count > 1 {
+            println!("{}:", output.display());
+        }
+        println!("{}", stats);
+    }
+
+    Ok(())
+}
+
+/// Capture, queue and encoder for one output and its segments.
+struct Pipeline {
+    output: PathBuf,
+    session: tracing::Span,
+    queue: FrameQueue,
+    stats: Stats,
+    stream: Option<RunningCapture>,
+    handle: JoinHandle<Result<(), EncoderError>>,
+}
+
+impl Pipeline {
+    #[allow(clippy::too_many_arguments)]
+    fn start<S>(
+        args: &RecordArgs,
+        output: PathBuf,
+        capture: PreparedCapture,
+        scaler: Option<Scaler>,
+        scaler_for: S,
+        backend: Backend,
+        settings: &EncoderSettings,
+        clock: &SessionClock,
+        audio: Option<mpsc::Receiver<AudioBuffer>>,
+    ) -> Result<Self, Error>
+    where
+        S: Fn((usize, usize)) -> Option<Scaler> + Send + 'static,
+    {
+        let (width, height) = scaler.as_ref().map_or((capture.width, capture.height), Scaler::output);
+        let session = info_span!(
+            "recording",
+            output = %output.display(),
+            source = %capture.description,
+            backend = ?backend,
+            width,
+            height,
+        );
+        let _session = session.clone().entered();
+        debug!(?settings, "starting");
+
+        let queue = FrameQueue::new(args.queue_size, args.drop_policy);
+        let stats = Stats::new(queue.clone(), clock.clone());
+
+        // Every segment gets its own encoder, sized for what the source is at that point
+        let open_segment = {
+            let (output, stats, settings) = (output.clone(), stats.clone(), settings.clone());
+            move |number: u32, input: (usize, usize), scaler: Option<Scaler>| -> Result<Segment, EncoderError> {
+                let size = scaler.as_ref().map_or(input, Scaler::output);
+                let path = segment::segment_path(&output, number);
+
+                // Later segments are files of their own, they start at their first frame
+                let settings = EncoderSettings { start: settings.start.filter(|_| number == 1), ..settings.clone() };
+                let encoder = encoder::init_encoder(backend, size.1 as f64, size.0 as f64, &path, &settings)?;
+                stats.segment_started(path);
+
+                Ok(Segment { number, since: None, encoder, scaler, input, size })
+            }
+        };
+
+        let first = open_segment(1, (capture.width, capture.height), scaler)?;
+        let next_segment = move |number, input| open_segment(number, input, scaler_for(input));
+        let handle = spawn_encoder_thread(
+            first,
+            next_segment,
+            args.on_resize,
+            args.filter,
+            queue.clone(),
+            audio,
+            stats.clone(),
+            clock.clone(),
+        );
+
+        let source_clock = clock.register(&capture.description);
+        let stream = capture.start(args.fps, {
+            let (queue, stats) = (queue.clone(), stats.clone());
+            move |frame| {
+                let frame = match frame {
+                    Some(mut frame) => match source_clock.stamp(frame.timestamp, Instant::now()) {
+                        Some(timestamp) => {
+                            frame.timestamp = timestamp;
+                            stats.frame_captured();
+                            Some(frame)
+                        }
+                        // Paused, nothing goes to the encoder
+                        None => return,
+                    },
+                    None => None,
+                };
+                queue.push(frame)
+            }
+        });
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Nothing is coming, let the encoder finish what it has (nothing) and close the file
+                queue.push(None);
+                handle.join().ok();
+                return Err(e);
+            }
+        };
+
+        if args.stats_interval > 0.0 {
+            let events = stats.events(Duration::from_secs_f64(args.stats_interval));
+            let session = session.clone();
+            std::thread::spawn(move || {
+                let _session = session.enter();
+
+                for snapshot in events {
+                    info!(
+                        frames_captured = snapshot.frames_captured,
+                        frames_encoded = snapshot.frames_encoded,
+                        frames_dropped = snapshot.frames_dropped(),
+                        frames_duplicated = snapshot.frames_duplicated,
+                        queue_depth = snapshot.queue_depth,
+                        bytes_written = snapshot.bytes_written,
+                        "{}",
+                        snapshot
+                    );
+                }
+            });
+        }
+
+        Ok(Self { output, session, queue, stats, stream: Some(stream), handle })
+    }
+
+    fn stop_capture(&mut self) {
+        let _session = self.session.enter();
+
+        // Whatever happens to the stream, the encoder still has to be finished or
+        // the file won't be playable (no moov atom, no trailer)
+        if let Some(stream) = self.stream.take() {
+            if let Err(e) = stream.stop() {
+                error!("Error stopping capture: {}", e);
+            }
+        }
+
+        // The stream sends its own `None` when it ends, this covers streams that didn't
+        self.queue.push(None);
+    }
+
+    /// Waits for the encoder to finish, call `stop_capture` first.
+    fn finish(self) -> Result<RecordingStats, Error> {
+        let result = self.handle.join().map_err(|_| Error::msg("Encoding thread panicked"))?;
+        self.stats.finish();
+        result?;
+
+        Ok(self.stats.snapshot())
+    }
+}
+
+/// One output file, and what frames go through on their way into it.
+struct Segment {
+    number: u32,
+    // First frame of the segment, `None` for one that starts with the session
+    since: Option<Instant>,
+    encoder: Box<dyn Encoder + Send>,
+    scaler: Option<Scaler>,
+    // Frame size the segment expects from the source
+    input: (usize, usize),
+    // Frame size the encoder was opened with
+    size: (usize, usize),
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_encoder_thread<F>(
+    mut segment: Segment,
+    mut next_segment: F,
+    on_resize: ResizePolicy,
+    filter: Filter,
+    queue: FrameQueue,
+    mut audio: Option<mpsc::Receiver<AudioBuffer>>,
+    stats: Stats,
+    clock: SessionClock,
+) -> JoinHandle<Result<(), EncoderError>>
+where
+    F: FnMut(u32, (usize, usize)) -> Result<Segment, EncoderError> + Send + 'static,
+{
+    if !segment.encoder.ignored_settings().is_empty() {
+        warn!("encoder ignores settings: {}", segment.encoder.ignored_settings().join(", "));
+    }
+
+    let session = tracing::Span::current();
+
+    std::thread::spawn(move || {
+        let _session = session.enter();
+        let mut result = Ok(());
+        let mut index = 0u64;
+        // Drops and duplicates from the encoders of earlier segments
+        let (mut dropped_before, mut duplicated_before) = (0, 0);
+
+        while let Some(frame) = queue.pop() {
+            let _frame = trace_span!("frame", index).entered();
+            let captured_at = frame.timestamp;
+            index += 1;
+
+            // Encoders are opened at a fixed size, so a resized source has to be dealt with here
+            let input = (frame.width, frame.height);
+            if input != segment.input {
+                info!(from = ?segment.input, to = ?input, policy = ?on_resize, "source changed size");
+
+                match on_resize {
+                    ResizePolicy::Scale => {
+                        segment.scaler = Some(Scaler::into_canvas(input, segment.size, filter)).filter(|scaler| !scaler.is_identity());
+                        segment.input = input;
+                    }
+                    ResizePolicy::Restart => {
+                        // The next file is opened first, so a failure leaves the current one to finish as usual
+                        let next = match next_segment(segment.number + 1, input) {
+                            Ok(next) => Segment { since: Some(captured_at), ..next },
+                            Err(e) => {
+                                error!("Error starting a new segment, stopping: {}", e);
+                                result = Err(e);
+                                break;
+                            }
+                        };
+
+                        let mut finished = std::mem::replace(&mut segment, next);
+                        dropped_before += finished.encoder.dropped_frames();
+                        duplicated_before += finished.encoder.duplicated_frames();
+                        debug!(segment = finished.number, "finishing encoder");
+
+                        if let Err(e) = add_chapters(&mut finished, &clock, Some(captured_at)) {
+                            warn!("Couldn't mark pauses in segment {}: {}", finished.number, e);
+                        }
+                        if let Err(e) = finished.encoder.finish() {
+                            error!("Error finishing segment {}, stopping: {}", finished.number, e);
+                            result = Err(e);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            let frame = match &segment.scaler {
+                Some(scaler) => match scaler.scale(frame) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        warn!("Dropping frame that couldn't be scaled: {}", e);
+                        continue;
+                    }
+                },
+                None => frame,
+            };
+
+            // Audio comes in between frames, whatever has arrived goes in ahead of this one
+            if let Err(e) = append_audio(&mut segment, &mut audio) {
+                error!("Error encoding audio, carrying on without it: {}", e);
+                audio = None;
+            }
+
+            if let Err(e) = segment.encoder.append_frame(frame) {
+                // Stop feeding frames but still finish, so what's been encoded so far is kept
+                error!("Error encoding frame, stopping: {}", e);
+                result = Err(e);
+                break;
+            }
+
+            stats.frame_encoded(
+                captured_at,
+                dropped_before + segment.encoder.dropped_frames(),
+                duplicated_before + segment.encoder.duplicated_frames(),
+            );
+        }
+
+        // Lets a blocked capture callback go if we stopped early
+        queue.close();
+
+        if let Err(e) = append_audio(&mut segment, &mut audio) {
+            error!("Error encoding the last of the audio: {}", e);
+        }
+
+        if let Err(e) = add_chapters(&mut segment, &clock, None) {
+            warn!("Couldn't mark pauses in segment {}: {}", segment.number, e);
+        }
+
+        debug!(frames = index, segment = segment.number, "finishing encoder");
+        segment.encoder.finish()?;
+
+        result
+    })
+}
+
+// Gives the segment a chapter for every part of the session that starts within it,
+// from its first frame up to `until`, unless its encoder can't write them
+fn add_chapters(segment: &mut Segment, clock: &SessionClock, until: Option<Instant>) -> Result<(), EncoderError> {
+    if segment.encoder.ignored_settings().contains(&"chapters") {
+        return Ok(());
+    }
+
+    for chapter in clock.chapters() {
+        let started = segment.since.is_none_or(|since| chapter.start >= since);
+        let ended = until.is_some_and(|until| chapter.start >= until);
+        if started && !ended {
+            segment.encoder.add_chapter(chapter)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Hands the segment whatever audio is waiting, unless its encoder has no track for it
+fn append_audio(segment: &mut Segment, audio: &mut Option<mpsc::Receiver<AudioBuffer>>) -> Result<(), EncoderError> {
+    let Some(rx) = audio else {
+        return Ok(());
+    };
+
+    let has_track = !segment.encoder.ignored_settings().contains(&"audio");
+    for buffer in rx.try_iter() {
+        if has_track {
+            segment.encoder.append_audio(buffer)?;
+        }
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,254 @@
+use anyhow::Error;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::frame::Frame;
+
+/// What to do with a new frame when the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Make room by throwing away the frame that's waited longest
+    DropOldest,
+    /// Throw away the new frame
+    DropNewest,
+    /// Hold up the capture callback until the encoder catches up
+    Block,
+}
+
+/// Bounded queue between the capture callback and the encoder thread.
+/// The end-of-stream `None` is never dropped and doesn't count towards the capacity.
+#[derive(Clone)]
+pub struct FrameQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    changed: Condvar,
+    capacity: usize,
+    policy: DropPolicy,
+}
+
+struct State {
+    frames: VecDeque<Frame>,
+    ended: bool,
+    // The consumer is gone, nothing will make room anymore
+    closed: bool,
+    dropped: u64,
+}
+
+impl FrameQueue {
+    /// A capacity of 0 would leave no room for even one frame, so it's taken as 1.
+    /// The CLI rejects `--queue-size 0` before it gets here.
+    pub fn new(capacity: usize, policy: DropPolicy) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    frames: VecDeque::with_capacity(capacity),
+                    ended: false,
+                    closed: false,
+                    dropped: 0,
+                }),
+                changed: Condvar::new(),
+                capacity: capacity.max(1),
+                policy,
+            }),
+        }
+    }
+
+    /// Queues a frame, or marks the end of the stream with `None`.
+    pub fn push(&self, frame: Option<Frame>) {
+        let inner = &self.inner;
+        let mut state = inner.state.lock().unwrap();
+
+        // Streams send their own `None` and we send one after stopping, only the first counts
+        if state.ended {
+            return;
+        }
+
+        let Some(frame) = frame else {
+            state.ended = true;
+            inner.changed.notify_all();
+            return;
+        };
+
+        if state.frames.len() >= inner.capacity {
+            match inner.policy {
+                DropPolicy::DropOldest => {
+                    state.frames.pop_front();
+                    state.dropped += 1;
+                }
+                DropPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return;
+                }
+                DropPolicy::Block => {
+                    state = inner
+                        .changed
+                        .wait_while(state, |s| s.frames.len() >= inner.capacity && !s.closed)
+                        .unwrap();
+                }
+            }
+        }
+
+        if state.closed {
+            state.dropped += 1;
+            return;
+        }
+
+        state.frames.push_back(frame);
+        inner.changed.notify_all();
+    }
+
+    /// Blocks until there's a frame. Returns `None` once the stream has ended and
+    /// everything before the end has been taken.
+    pub fn pop(&self) -> Option<Frame> {
+        let inner = &self.inner;
+        let mut state = inner
+            .changed
+            .wait_while(inner.state.lock().unwrap(), |s| s.frames.is_empty() && !s.ended)
+            .unwrap();
+
+        let frame = state.frames.pop_front();
+        inner.changed.notify_all();
+
+        frame
+    }
+
+    /// For the consumer to call when it stops taking frames, so a blocked
+    /// capture callback doesn't wait forever.
+    pub fn close(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.closed = true;
+        state.dropped += state.frames.len() as u64;
+        state.frames.clear();
+        self.inner.changed.notify_all();
+    }
+
+    /// Frames waiting for the encoder.
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().unwrap().frames.len()
+    }
+
+    /// Frames thrown away because the queue was full (or closed).
+    pub fn dropped(&self) -> u64 {
+        self.inner.state.lock().unwrap().dropped
+    }
+}
+
+/// `drop-oldest`, `drop-newest` or `block`
+impl FromStr for DropPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop-oldest" => Ok(DropPolicy::DropOldest),
+            "drop-newest" => Ok(DropPolicy::DropNewest),
+            "block" => Ok(DropPolicy::Block),
+            _ => Err(Error::msg(format!("Unknown drop policy: {}", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // Frames are told apart by their width
+    fn frame(id: usize) -> Option<Frame> {
+        Some(Frame::new(id, 1, PixelFormat::Bgra, Vec::new(), Instant::now()))
+    }
+
+    fn drain(queue: &FrameQueue) -> Vec<usize> {
+        std::iter::from_fn(|| queue.pop()).map(|frame| frame.width).collect()
+    }
+
+    #[test]
+    fn drop_oldest_evicts_the_head() {
+        let queue = FrameQueue::new(2, DropPolicy::DropOldest);
+        (1..=4).for_each(|id| queue.push(frame(id)));
+        queue.push(None);
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue), [3, 4]);
+    }
+
+    #[test]
+    fn drop_newest_rejects_the_push() {
+        let queue = FrameQueue::new(2, DropPolicy::DropNewest);
+        (1..=4).for_each(|id| queue.push(frame(id)));
+        queue.push(None);
+
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue), [1, 2]);
+    }
+
+    #[test]
+    fn end_of_stream_is_never_dropped() {
+        for policy in [DropPolicy::DropOldest, DropPolicy::DropNewest, DropPolicy::Block] {
+            let queue = FrameQueue::new(1, policy);
+            queue.push(frame(1));
+            // Past capacity, but doesn't block and isn't counted
+            queue.push(None);
+            // Only the first end counts, nothing after it is queued
+            queue.push(frame(2));
+            queue.push(None);
+
+            assert_eq!(queue.dropped(), 0, "{:?}", policy);
+            assert_eq!(drain(&queue), [1], "{:?}", policy);
+            assert!(queue.pop().is_none());
+        }
+    }
+
+    #[test]
+    fn block_waits_for_a_pop() {
+        let queue = FrameQueue::new(1, DropPolicy::Block);
+        queue.push(frame(1));
+
+        let pushed = Arc::new(AtomicBool::new(false));
+        let producer = thread::spawn({
+            let (queue, pushed) = (queue.clone(), pushed.clone());
+            move || {
+                queue.push(frame(2));
+                pushed.store(true, Ordering::SeqCst);
+            }
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!pushed.load(Ordering::SeqCst));
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(queue.pop().map(|frame| frame.width), Some(1));
+        producer.join().unwrap();
+        assert!(pushed.load(Ordering::SeqCst));
+
+        queue.push(None);
+        assert_eq!(drain(&queue), [2]);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn block_gives_up_on_close() {
+        let queue = FrameQueue::new(1, DropPolicy::Block);
+        queue.push(frame(1));
+
+        let producer = thread::spawn({
+            let queue = queue.clone();
+            move || queue.push(frame(2))
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!producer.is_finished());
+
+        // The queued frame and the one that was waiting both count as dropped
+        queue.close();
+        producer.join().unwrap();
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.len(), 0);
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::Error;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::convert::{self, Conversion, ConvertError};
+use crate::frame::{ColorRange, Frame, PixelFormat, Plane, Rect};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Bilinear,
+    /// Catmull-Rom
+    Bicubic,
+    /// Lanczos with three lobes
+    Lanczos,
+}
+
+impl Filter {
+    fn radius(self) -> f64 {
+        match self {
+            Filter::Bilinear => 1.0,
+            Filter::Bicubic => 2.0,
+            Filter::Lanczos => 3.0,
+        }
+    }
+
+    fn weight(self, x: f64) -> f64 {
+        let x = x.abs();
+
+        match self {
+            Filter::Bilinear => (1.0 - x).max(0.0),
+            Filter::Bicubic => match x {
+                x if x < 1.0 => 1.5 * x * x * x - 2.5 * x * x + 1.0,
+                x if x < 2.0 => -0.5 * x * x * x + 2.5 * x * x - 4.0 * x + 2.0,
+                _ => 0.0,
+            },
+            Filter::Lanczos => match x {
+                0.0 => 1.0,
+                x if x < 3.0 => {
+                    let px = std::f64::consts::PI * x;
+                    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+                }
+                _ => 0.0,
+            },
+        }
+    }
+}
+
+/// What to do when the target's aspect ratio isn't the source's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AspectMode {
+    /// Scale to fit inside the target, the output is only as big as the picture
+    Fit,
+    /// Scale to cover the target and crop whatever sticks out
+    Fill,
+    /// Scale to fit inside the target and pad the rest with black
+    Letterbox,
+}
+
+/// Output size asked for on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// `1920x1080`
+    Exact(usize, usize),
+    /// `1080p`, width follows the source's aspect ratio
+    Height(usize),
+    /// `max 1920 wide`, never scales up
+    MaxWidth(usize),
+    /// `max 1080 tall`, never scales up
+    MaxHeight(usize),
+}
+
+#[derive(Debug, Error)]
+pub enum ScaleError {
+    #[error("frame is {}x{} but the scaler was set up for {}x{}", .got.0, .got.1, .expected.0, .expected.1)]
+    SizeChanged { expected: (usize, usize), got: (usize, usize) },
+
+    #[error(transparent)]
+    Convert(#[from] ConvertError),
+}
+
+/// Scales every frame of a session from one size to another. The filter weights
+/// are worked out once up front, so each frame is only the arithmetic.
+pub struct Scaler {
+    input: (usize, usize),
+    output: (usize, usize),
+    fit: AspectMode,
+    filter: Filter,
+    // Where the picture goes in the output, anything outside it is black
+    dest: Rect,
+    // Weights for full resolution planes and for 2x2 subsampled chroma
+    full: (Axis, Axis),
+    half: (Axis, Axis),
+}
+
+impl Scaler {
+    /// Output sizes are rounded down to even numbers, so 4:2:0 encoders can take them.
+    pub fn new(input: (usize, usize), resolution: Resolution, fit: AspectMode, filter: Filter) -> Self {
+        let (width, height) = (input.0 as f64, input.1 as f64);
+        let even = |v: f64| ((v.round() as usize) / 2 * 2).max(2);
+
+        let target = match resolution {
+            Resolution::Exact(w, h) => (w as f64, h as f64),
+            Resolution::Height(h) => (h as f64 * width / height, h as f64),
+            Resolution::MaxWidth(w) => {
+                let w = (w as f64).min(width);
+                (w, w * height / width)
+            }
+            Resolution::MaxHeight(h) => {
+                let h = (h as f64).min(height);
+                (h * width / height, h)
+            }
+        };
+
+        // Picture size inside the target and the part of the input it's taken from
+        let fit_scale = (target.0 / width).min(target.1 / height);
+        let fill_scale = (target.0 / width).max(target.1 / height);
+
+        let (output, dest, crop) = match fit {
+            AspectMode::Fit => {
+                let size = (even(width * fit_scale), even(height * fit_scale));
+                (size, Rect { x: 0, y: 0, width: size.0, height: size.1 }, (0.0, 0.0, width, height))
+            }
+            AspectMode::Fill => {
+                let size = (even(target.0), even(target.1));
+                let (crop_w, crop_h) = (size.0 as f64 / fill_scale, size.1 as f64 / fill_scale);
+                let crop = ((width - crop_w) / 2.0, (height - crop_h) / 2.0, crop_w, crop_h);
+                (size, Rect { x: 0, y: 0, width: size.0, height: size.1 }, crop)
+            }
+            AspectMode::Letterbox => {
+                let size = (even(target.0), even(target.1));
+                let picture = (even(width * fit_scale).min(size.0), even(height * fit_scale).min(size.1));
+                // Even offsets keep the picture on the chroma grid
+                let dest = Rect {
+                    x: (size.0 - picture.0) / 4 * 2,
+                    y: (size.1 - picture.1) / 4 * 2,
+                    width: picture.0,
+                    height: picture.1,
+                };
+                (size, dest, (0.0, 0.0, width, height))
+            }
+        };
+
+        Self::with_layout(input, output, fit, filter, dest, crop)
+    }
+
+    /// Letterboxes frames of `input` size into a canvas of exactly `canvas`, even an
+    /// odd sized one, for when the source changes size but the encoder can't.
+    pub fn into_canvas(input: (usize, usize), canvas: (usize, usize), filter: Filter) -> Self {
+        let (width, height) = (input.0 as f64, input.1 as f64);
+        let fit_scale = (canvas.0 as f64 / width).min(canvas.1 as f64 / height);
+        let even = |v: usize| v / 2 * 2;
+
+        let picture = (
+            even((width * fit_scale).round() as usize).max(2).min(even(canvas.0)),
+            even((height * fit_scale).round() as usize).max(2).min(even(canvas.1)),
+        );
+        let dest = Rect {
+            x: (canvas.0 - picture.0) / 4 * 2,
+            y: (canvas.1 - picture.1) / 4 * 2,
+            width: picture.0,
+            height: picture.1,
+        };
+
+        Self::with_layout(input, canvas, AspectMode::Letterbox, filter, dest, (0.0, 0.0, width, height))
+    }
+
+    // `crop` is the part of the input that ends up in `dest`, as x, y, width, height
+    fn with_layout(
+        input: (usize, usize),
+        output: (usize, usize),
+        fit: AspectMode,
+        filter: Filter,
+        dest: Rect,
+        crop: (f64, f64, f64, f64),
+    ) -> Self {
+        let (cx, cy, cw, ch) = crop;
+        let full = (
+            Axis::new(filter, cx, cw, input.0, dest.width),
+            Axis::new(filter, cy, ch, input.1, dest.height),
+        );
+        let half = (
+            Axis::new(filter, cx / 2.0, cw / 2.0, input.0.div_ceil(2), dest.width / 2),
+            Axis::new(filter, cy / 2.0, ch / 2.0, input.1.div_ceil(2), dest.height / 2),
+        );
+
+        Self { input, output, fit, filter, dest, full, half }
+    }
+
+    pub fn output(&self) -> (usize, usize) {
+        self.output
+    }
+
+    /// Whether frames would come out exactly as they went in.
+    pub fn is_identity(&self) -> bool {
+        let whole = self.dest.width == self.input.0 && self.dest.height == self.input.1;
+        whole && self.output == self.input
+    }
+
+    /// Scales `frame`, which has to be the size the scaler was set up for. The
+    /// result keeps the frame's format, except P010 which comes out as NV12.
+    pub fn scale(&self, frame: Frame) -> Result<Frame, ScaleError> {
+        if (frame.width, frame.height) != self.input {
+            return Err(ScaleError::SizeChanged { expected: self.input, got: (frame.width, frame.height) });
+        }
+
+        convert::validate(&frame)?;
+
+        // Everything else is 8 bits per sample
+        let frame = match frame.format {
+            PixelFormat::P010 => {
+                let to = Conversion::to(PixelFormat::Nv12, &frame);
+                convert::convert(frame, to)?
+            }
+            _ => frame,
+        };
+
+        // (bytes per pixel, subsampled) for each plane
+        let layout: &[(usize, bool)] = match frame.format {
+            PixelFormat::Bgra | PixelFormat::Rgba => &[(4, false)],
+            PixelFormat::Nv12 | PixelFormat::P010 => &[(1, false), (2, true)],
+            PixelFormat::I420 => &[(1, false), (1, true), (1, true)],
+            PixelFormat::I444 => &[(1, false), (1, false), (1, false)],
+        };
+
+        let planes = frame
+            .planes
+            .iter()
+            .zip(layout)
+            .enumerate()
+            .map(|(i, (plane, &(channels, subsampled)))| {
+                let black = black(frame.format, frame.color_range, i);
+
+                match subsampled {
+                    true => self.scale_plane(plane, channels, &self.half, 2, &black[..channels]),
+                    false => self.scale_plane(plane, channels, &self.full, 1, &black[..channels]),
+                }
+            })
+            .collect();
+
+        Ok(Frame {
+            width: self.output.0,
+            height: self.output.1,
+            format: frame.format,
+            color_space: frame.color_space,
+            color_range: frame.color_range,
+            matrix: frame.matrix,
+            planes,
+            timestamp: frame.timestamp,
+            #[cfg(not(target_os = "linux"))]
+            native: None,
+        })
+    }
+
+    // `divisor` is 2 for subsampled planes
+    fn scale_plane(&self, src: &Plane, channels: usize, (h, v): &(Axis, Axis), divisor: usize, black: &[u8]) -> Plane {
+        let out_w = self.output.0.div_ceil(divisor);
+        let out_h = self.output.1.div_ceil(divisor);
+        let stride = out_w * channels;
+
+        // Only letterboxing leaves anything for the bars to show through
+        let covered = (self.dest.width, self.dest.height) == self.output;
+        let mut data = match covered {
+            true => vec![0; stride * out_h],
+            false => black.repeat(out_w).repeat(out_h),
+        };
+
+        // Vertical pass first, straight out of the source and only over the columns
+        // the horizontal pass reads, so the costlier horizontal pass sees output rows only
+        let (first, last) = h.source_range();
+        let columns = first * channels..(last + 1) * channels;
+        let (x0, y0) = (self.dest.x / divisor, self.dest.y / divisor);
+        let row_len = h.len * channels;
+
+        let mut line = vec![0.0; columns.end];
+
+        for y in 0..v.len {
+            v.apply_at(y, &mut line[columns.clone()], |row| &src.data[row * src.stride..][columns.clone()]);
+
+            let start = (y0 + y) * stride + x0 * channels;
+            let out = &mut data[start..start + row_len];
+
+            match channels {
+                1 => h.apply::<1>(&line, out),
+                2 => h.apply::<2>(&line, out),
+                _ => h.apply::<4>(&line, out),
+            }
+        }
+
+        Plane { data, stride }
+    }
+}
+
+impl fmt::Display for Scaler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} -> {}x{} ({:?}, {:?})",
+            self.input.0, self.input.1, self.output.0, self.output.1, self.fit, self.filter
+        )
+    }
+}
+
+fn black(format: PixelFormat, range: ColorRange, plane: usize) -> [u8; 4] {
+    match (format.is_rgb(), plane, range) {
+        (true, _, _) => [0, 0, 0, 255],
+        (false, 0, ColorRange::Limited) => [16; 4],
+        (false, 0, ColorRange::Full) => [0; 4],
+        (false, _, _) => [128; 4],
+    }
+}
+
+/// Filter weights for one direction: every output position reads `taps`
+/// consecutive source positions from its start.
+struct Axis {
+    len: usize,
+    taps: usize,
+    starts: Vec<usize>,
+    // f32 because SSE2 has no 32-bit integer multiply, and neither pass needs more
+    weights: Vec<f32>,
+}
+
+impl Axis {
+    /// Maps `crop_len` source pixels starting at `crop_start` onto `len` output pixels.
+    fn new(filter: Filter, crop_start: f64, crop_len: f64, source_len: usize, len: usize) -> Self {
+        let ratio = crop_len / len.max(1) as f64;
+        // Widen the filter when shrinking so every source pixel counts
+        let stretch = ratio.max(1.0);
+        let support = filter.radius() * stretch;
+        let wanted = (support * 2.0).ceil() as usize + 1;
+        let taps = wanted.min(source_len);
+
+        let mut starts = Vec::with_capacity(len);
+        let mut weights = vec![0.0; len * taps];
+
+        for (i, weights) in weights.chunks_exact_mut(taps).enumerate() {
+            let center = crop_start + (i as f64 + 0.5) * ratio;
+            let first = (center - support - 0.5).floor() as isize;
+
+            let raw: Vec<f64> = (0..wanted)
+                .map(|t| filter.weight((first + t as isize) as f64 + 0.5 - center) / stretch)
+                .collect();
+            // Normalized, so flat areas stay flat
+            let total: f64 = raw.iter().sum();
+
+            // Taps past the edge read the edge pixel, so their weight moves onto it and
+            // the window slides inside the source. That keeps every read contiguous.
+            let start = first.clamp(0, (source_len - taps) as isize);
+            for (t, w) in raw.iter().enumerate() {
+                let x = (first + t as isize).clamp(0, source_len as isize - 1);
+                weights[(x - start) as usize] += (w / total) as f32;
+            }
+
+            starts.push(start as usize);
+        }
+
+        Self { len, taps, starts, weights }
+    }
+
+    /// First and last source positions any output reads.
+    fn source_range(&self) -> (usize, usize) {
+        let first = self.starts.first().copied().unwrap_or(0);
+        let last = self.starts.last().map_or(0, |start| start + self.taps - 1);
+        (first, last)
+    }
+
+    /// Filters one line of `C`-interleaved samples into `out`.
+    fn apply<const C: usize>(&self, line: &[f32], out: &mut [u8]) {
+        let taps = self.starts.iter().zip(self.weights.chunks_exact(self.taps));
+
+        for ((&start, weights), out) in taps.zip(out.chunks_exact_mut(C)) {
+            let window = &line[start * C..(start + self.taps) * C];
+
+            // Two sums so consecutive taps don't wait on each other's additions
+            let mut even = [0.0; C];
+            let mut odd = [0.0; C];
+
+            let pairs = window.chunks_exact(2 * C).zip(weights.chunks_exact(2));
+            for (px, w) in pairs {
+                for c in 0..C {
+                    even[c] += px[c] * w[0];
+                    odd[c] += px[C + c] * w[1];
+                }
+            }
+
+            if self.taps % 2 == 1 {
+                let px = &window[(self.taps - 1) * C..];
+                for c in 0..C {
+                    even[c] += px[c] * weights[self.taps - 1];
+                }
+            }
+
+            for c in 0..C {
+                out[c] = (even[c] + odd[c] + 0.5).clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    /// Filters output line `i` out of the source lines `row` returns by position.
+    /// Stays in floating point, the horizontal pass rounds.
+    fn apply_at<'a>(&self, i: usize, out: &mut [f32], row: impl Fn(usize) -> &'a [u8]) {
+        let weights = &self.weights[i * self.taps..(i + 1) * self.taps];
+        out.fill(0.0);
+
+        // A line at a time, so the inner loop runs over contiguous memory
+        for (y, &w) in (self.starts[i]..).zip(weights) {
+            for (out, &v) in out.iter_mut().zip(row(y)) {
+                *out += v as f32 * w;
+            }
+        }
+    }
+}
+
+/// `bilinear`, `bicubic` or `lanczos`
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "bilinear" => Ok(Filter::Bilinear),
+            "bicubic" => Ok(Filter::Bicubic),
+            "lanczos" => Ok(Filter::Lanczos),
+            _ => Err(Error::msg(format!("Unknown scaling filter: {}", s))),
+        }
+    }
+}
+
+/// `fit`, `fill` or `letterbox`
+impl FromStr for AspectMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "fit" => Ok(AspectMode::Fit),
+            "fill" | "crop" => Ok(AspectMode::Fill),
+            "letterbox" | "pad" => Ok(AspectMode::Letterbox),
+            _ => Err(Error::msg(format!("Unknown aspect mode: {}", s))),
+        }
+    }
+}
+
+/// `1920x1080`, `1080p`, `4k`, `max 1920 wide` or `max 1080 tall`
+impl FromStr for Resolution {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::msg(format!("Invalid resolution: {}", s));
+        let lower = s.trim().to_ascii_lowercase();
+        let words: Vec<&str> = lower.split_whitespace().collect();
+        let number = |v: &str| v.parse::<usize>().ok().filter(|&v| v > 0).ok_or_else(invalid);
+
+        match words.as_slice() {
+            ["4k"] => Ok(Resolution::Height(2160)),
+            ["max", n, "wide"] => Ok(Resolution::MaxWidth(number(n)?)),
+            ["max", n, "tall" | "high"] => Ok(Resolution::MaxHeight(number(n)?)),
+            [one] => match (one.strip_suffix('p'), one.split_once('x')) {
+                (Some(h), _) => Ok(Resolution::Height(number(h)?)),
+                (None, Some((w, h))) => Ok(Resolution::Exact(number(w)?, number(h)?)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
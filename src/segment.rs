@@ -0,0 +1,52 @@
+use anyhow::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What to do when the source changes size mid-recording (a window being resized),
+/// since encoders are opened with a fixed width and height.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizePolicy {
+    /// Keep the output size and letterbox the new frames into it
+    Scale,
+    /// Finish the current file and start a new segment at the new size
+    Restart,
+}
+
+/// `scale` or `restart`
+impl FromStr for ResizePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "scale" | "letterbox" => Ok(ResizePolicy::Scale),
+            "restart" | "segment" => Ok(ResizePolicy::Restart),
+            _ => Err(Error::msg(format!("Unknown resize policy: {}", s))),
+        }
+    }
+}
+
+/// Where display `index` goes when displays are recorded to separate files:
+/// `video-display0.mp4`, `video-display1.mp4` and so on next to `output`.
+pub fn display_path(output: &Path, index: usize) -> PathBuf {
+    suffixed(output, &format!("display{}", index))
+}
+
+/// Where segment `number` goes: the output itself for the first one, then
+/// `video-2.mp4`, `video-3.mp4` and so on next to it.
+pub fn segment_path(output: &Path, number: u32) -> PathBuf {
+    if number <= 1 {
+        return output.to_path_buf();
+    }
+
+    suffixed(output, &number.to_string())
+}
+
+fn suffixed(output: &Path, suffix: &str) -> PathBuf {
+    let stem = output.file_stem().map_or("video".into(), |stem| stem.to_string_lossy());
+    let name = match output.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, suffix),
+    };
+
+    output.with_file_name(name)
+}
@@ -0,0 +1,209 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::{SessionClock, SyncOffset};
+use crate::queue::FrameQueue;
+
+/// Point-in-time view of a recording.
+#[derive(Clone, Debug, Default)]
+pub struct RecordingStats {
+    pub elapsed: Duration,
+    pub frames_captured: u64,
+    pub frames_encoded: u64,
+    /// Thrown away because the queue to the encoder was full
+    pub frames_dropped_queue: u64,
+    /// Accepted by the encoder but never written, see `Encoder::dropped_frames`
+    pub frames_dropped_encoder: u64,
+    /// Extra copies the encoder wrote to keep a constant frame rate
+    pub frames_duplicated: u64,
+    /// Encoded frames per second over the whole recording
+    pub average_fps: f64,
+    /// Encoded frames per second since the previous snapshot of the same `events`
+    /// channel, or over the whole recording for one from `snapshot`
+    pub current_fps: f64,
+    /// Capture to encoded, including the time spent waiting in the queue
+    pub encode_latency_avg: Duration,
+    pub encode_latency_max: Duration,
+    pub queue_depth: usize,
+    /// Size of the output files so far, every segment included
+    pub bytes_written: u64,
+    /// Bits per second achieved, from `bytes_written`
+    pub bitrate: f64,
+    /// Every source of the session against the session clock
+    pub sync: Vec<SyncOffset>,
+}
+
+impl RecordingStats {
+    pub fn frames_dropped(&self) -> u64 {
+        self.frames_dropped_queue + self.frames_dropped_encoder
+    }
+}
+
+impl fmt::Display for RecordingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>7.1}s  {} captured, {} encoded, {} dropped, {} duplicated  {:.1} fps (avg {:.1})  latency {:.1}ms (max {:.1}ms)  queue {}  {:.1} MB at {:.2} Mbit/s",
+            self.elapsed.as_secs_f64(),
+            self.frames_captured,
+            self.frames_encoded,
+            self.frames_dropped(),
+            self.frames_duplicated,
+            self.current_fps,
+            self.average_fps,
+            self.encode_latency_avg.as_secs_f64() * 1000.0,
+            self.encode_latency_max.as_secs_f64() * 1000.0,
+            self.queue_depth,
+            self.bytes_written as f64 / 1_000_000.0,
+            self.bitrate / 1_000_000.0,
+        )?;
+
+        if !self.sync.is_empty() {
+            let sync: Vec<_> = self.sync.iter().map(SyncOffset::to_string).collect();
+            write!(f, "  sync {}", sync.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects counters from the capture callback and encoder thread. Cheap to
+/// clone, every clone updates the same recording.
+#[derive(Clone)]
+pub struct Stats {
+    inner: Arc<Mutex<Counters>>,
+    queue: FrameQueue,
+    clock: SessionClock,
+}
+
+struct Counters {
+    started: Instant,
+    captured: u64,
+    encoded: u64,
+    dropped_encoder: u64,
+    duplicated: u64,
+    latency_total: Duration,
+    latency_max: Duration,
+    // Every file written so far, one per segment
+    outputs: Vec<PathBuf>,
+    finished: bool,
+}
+
+impl Stats {
+    pub fn new(queue: FrameQueue, clock: SessionClock) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Counters {
+                started: Instant::now(),
+                captured: 0,
+                encoded: 0,
+                dropped_encoder: 0,
+                duplicated: 0,
+                latency_total: Duration::ZERO,
+                latency_max: Duration::ZERO,
+                outputs: Vec::new(),
+                finished: false,
+            })),
+            queue,
+            clock,
+        }
+    }
+
+    /// Counts `output` towards `bytes_written`, once for each file the recording
+    /// goes into.
+    pub fn segment_started(&self, output: PathBuf) {
+        self.inner.lock().unwrap().outputs.push(output);
+    }
+
+    pub fn frame_captured(&self) {
+        self.inner.lock().unwrap().captured += 1;
+    }
+
+    /// `captured_at` is the frame's capture timestamp. `dropped_total` and
+    /// `duplicated_total` are the encoder's running `dropped_frames` and
+    /// `duplicated_frames` counts.
+    pub fn frame_encoded(&self, captured_at: Instant, dropped_total: u64, duplicated_total: u64) {
+        // Stamps after a pause are moved back by it, the frame wasn't waiting that long
+        let latency = captured_at.elapsed().saturating_sub(self.clock.paused());
+        let mut counters = self.inner.lock().unwrap();
+
+        counters.encoded += 1;
+        counters.dropped_encoder = dropped_total;
+        counters.duplicated = duplicated_total;
+        counters.latency_total += latency;
+        counters.latency_max = counters.latency_max.max(latency);
+    }
+
+    /// Marks the recording as done, which ends any `events` channels.
+    pub fn finish(&self) {
+        self.inner.lock().unwrap().finished = true;
+    }
+
+    pub fn snapshot(&self) -> RecordingStats {
+        let counters = self.inner.lock().unwrap();
+
+        // The encoder's own drops went through append_frame too
+        let encoded = counters.encoded - counters.dropped_encoder;
+        let elapsed = counters.started.elapsed();
+        let average_fps = encoded as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+
+        let bytes_written: u64 = counters
+            .outputs
+            .iter()
+            .map(|output| std::fs::metadata(output).map_or(0, |m| m.len()))
+            .sum();
+
+        RecordingStats {
+            elapsed,
+            frames_captured: counters.captured,
+            frames_encoded: encoded,
+            frames_dropped_queue: self.queue.dropped(),
+            frames_dropped_encoder: counters.dropped_encoder,
+            frames_duplicated: counters.duplicated,
+            average_fps,
+            current_fps: average_fps,
+            encode_latency_avg: counters.latency_total / counters.encoded.max(1) as u32,
+            encode_latency_max: counters.latency_max,
+            queue_depth: self.queue.len(),
+            bytes_written,
+            bitrate: bytes_written as f64 * 8.0 / elapsed.as_secs_f64().max(f64::EPSILON),
+            sync: self.clock.offsets(),
+        }
+    }
+
+    /// Sends a snapshot every `interval` until the recording finishes or the
+    /// receiver is dropped.
+    pub fn events(&self, interval: Duration) -> mpsc::Receiver<RecordingStats> {
+        let (tx, rx) = mpsc::channel();
+        let stats = self.clone();
+
+        std::thread::spawn(move || {
+            // Encoded count and elapsed time of the last snapshot sent, so every
+            // channel has its own current fps whoever else takes snapshots
+            let mut last = (0, Duration::ZERO);
+
+            loop {
+                std::thread::sleep(interval);
+                if stats.inner.lock().unwrap().finished {
+                    break;
+                }
+
+                let mut snapshot = stats.snapshot();
+                let since_last = (snapshot.elapsed - last.1).as_secs_f64();
+                snapshot.current_fps = match since_last > 0.0 {
+                    true => snapshot.frames_encoded.saturating_sub(last.0) as f64 / since_last,
+                    false => 0.0,
+                };
+                last = (snapshot.frames_encoded, snapshot.elapsed);
+
+                if tx.send(snapshot).is_err() {
+                    break;
+                }
+            }
+        });
+
+        rx
+    }
+}